@@ -0,0 +1,145 @@
+//! Compares the node storage of `OsmFile` with a `HashMap` that holds every
+//! node as a full element.
+//!
+//! Run with `cargo bench --bench nodes`. The memory usage of both is printed
+//! before the lookup benchmarks.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The crate has no library target, so the module is compiled into the
+// benchmark itself
+#[allow(dead_code, unused_imports)]
+#[path = "../src"]
+mod src {
+    pub mod osmpbf;
+}
+use src::osmpbf::{ElementRef, OsmElement, OsmFile, OsmFileElement, OsmNodeData, XmlReader};
+
+/// Allocator that keeps track of the allocated bytes.
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const NODE_COUNT: i64 = 1_000_000;
+
+/// Returns the value of `f` and the number of bytes it left allocated.
+fn measure<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let value = f();
+    (value, ALLOCATED.load(Ordering::Relaxed) - before)
+}
+
+/// Creates a file in which every tenth node is tagged, roughly like the
+/// nodes of a real extract.
+fn create_file() -> OsmFile {
+    let mut xml = Vec::new();
+    writeln!(xml, "<osm version=\"0.6\">").unwrap();
+    for i in 0..NODE_COUNT {
+        let lat = 47.0 + (i % 1000) as f64 * 1e-4;
+        let lon = 8.0 + (i / 1000) as f64 * 1e-4;
+        if i % 10 == 0 {
+            writeln!(
+                xml,
+                r#"<node id="{}" lat="{:.7}" lon="{:.7}"><tag k="amenity" v="bench"/></node>"#,
+                i * 3,
+                lat,
+                lon
+            )
+        } else {
+            writeln!(
+                xml,
+                r#"<node id="{}" lat="{:.7}" lon="{:.7}"/>"#,
+                i * 3,
+                lat,
+                lon
+            )
+        }
+        .unwrap();
+    }
+    writeln!(xml, "</osm>").unwrap();
+    XmlReader::new(xml.as_slice()).read().unwrap()
+}
+
+/// Pseudo-random ids of existing nodes.
+fn lookup_ids() -> Vec<i64> {
+    let mut state: u64 = 0x2545f4914f6cdd1d;
+    (0..10_000)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            ((state >> 33) as i64 % NODE_COUNT) * 3
+        })
+        .collect()
+}
+
+/// Location of a node stored in the `HashMap`.
+fn lat(osm_file: &OsmFile, node: &OsmElement<OsmNodeData>) -> f64 {
+    let el = ElementRef::Borrowed(node);
+    OsmFileElement { el, osm_file }.data().lat
+}
+
+fn node_lookup(c: &mut Criterion) {
+    let (osm_file, store_size) = measure(create_file);
+    let (map, map_size) = measure(|| {
+        osm_file
+            .nodes()
+            .map(|node| (node.id(), (*node.el).clone()))
+            .collect::<HashMap<i64, OsmElement<OsmNodeData>>>()
+    });
+    println!(
+        "Memory for {} nodes: OsmFile {:.1} MiB, HashMap {:.1} MiB",
+        NODE_COUNT,
+        store_size as f64 / (1024.0 * 1024.0),
+        map_size as f64 / (1024.0 * 1024.0)
+    );
+
+    let ids = lookup_ids();
+    let mut group = c.benchmark_group("get_node");
+    group.bench_function("OsmFile", |b| {
+        b.iter(|| {
+            ids.iter()
+                .map(|id| osm_file.get_node(*id).map_or(0.0, |n| n.data().lat))
+                .sum::<f64>()
+        })
+    });
+    group.bench_function("HashMap", |b| {
+        b.iter(|| {
+            ids.iter()
+                .map(|id| map.get(id).map_or(0.0, |n| lat(&osm_file, n)))
+                .sum::<f64>()
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("iterate");
+    group.sample_size(10);
+    group.bench_function("OsmFile", |b| {
+        b.iter(|| black_box(osm_file.nodes().map(|n| n.data().lat).sum::<f64>()))
+    });
+    group.bench_function("HashMap", |b| {
+        b.iter(|| black_box(map.values().map(|n| lat(&osm_file, n)).sum::<f64>()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, node_lookup);
+criterion_main!(benches);
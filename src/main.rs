@@ -1,36 +1,349 @@
 use cairo::{Context, Format, ImageSurface};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use flate2::read::GzDecoder;
+use std::collections::HashSet;
 use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
 mod osmpbf;
 mod render;
 mod style_file;
 
 #[derive(Parser)]
-struct Args {
-    #[arg(short, long)]
-    input: String,
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    input: Option<InputArgs>,
 
+    #[command(flatten)]
+    render: Option<RenderArgs>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write the elements selected by a style file or a bbox to a PBF file
+    Extract(ExtractArgs),
+    /// Print style files in canonical formatting
+    Fmt(FmtArgs),
+}
+
+#[derive(clap::Args)]
+struct RenderArgs {
     #[arg(short, long)]
     style_file: String,
 
     #[arg(short, long, default_value_t = String::from("output.png"))]
     output: String,
+
+    /// Scale denominator to render at, instead of the one of the style file
+    #[arg(long, value_parser = parse_scale)]
+    scale: Option<f64>,
+}
+
+#[derive(clap::Args)]
+struct ExtractArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Only write the elements drawn by the layers of the style file
+    #[arg(short, long, required_unless_present = "bbox")]
+    style_file: Option<String>,
+
+    /// Area to extract as min_lat,min_lon,max_lat,max_lon, instead of the
+    /// map area of the style file
+    #[arg(long, value_parser = parse_bbox)]
+    bbox: Option<osmpbf::BBox>,
+
+    /// Output PBF file
+    #[arg(short, long)]
+    output: String,
+}
+
+#[derive(clap::Args)]
+struct FmtArgs {
+    #[arg(required = true)]
+    style_files: Vec<String>,
+
+    /// Overwrite the files instead of printing them
+    #[arg(short, long)]
+    write: bool,
+}
+
+#[derive(clap::Args)]
+struct InputArgs {
+    #[arg(short, long)]
+    input: String,
+
+    /// Margin around the map area within which data is loaded, as a fraction
+    /// of the map size
+    #[arg(long, default_value_t = 0.1)]
+    bbox_margin: f64,
+
+    /// Load the whole input file instead of only the map area
+    #[arg(long)]
+    load_all: bool,
+
+    /// Only load elements having a tag key used by the style's filters
+    #[arg(long)]
+    filter_tags: bool,
+
+    /// OsmChange file (.osc or .osc.gz) to apply to the input before
+    /// rendering. Can be given multiple times, the files are applied in order.
+    #[arg(long)]
+    change: Vec<String>,
+
+    /// Snapshot file of the loaded data. It is created on the first run and
+    /// reused by later runs with the same input file and load options.
+    #[arg(long)]
+    cache: Option<String>,
+}
+
+fn parse_bbox(s: &str) -> Result<osmpbf::BBox, String> {
+    let coords = s
+        .split(',')
+        .map(|c| c.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|e| e.to_string())?;
+    let [min_lat, min_lon, max_lat, max_lon] = coords[..] else {
+        return Err("expected min_lat,min_lon,max_lat,max_lon".to_string());
+    };
+    Ok(osmpbf::BBox {
+        min_lat,
+        min_lon,
+        max_lat,
+        max_lon,
+    })
+}
+
+fn parse_scale(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(scale) if scale.is_finite() && scale > 0.0 => Ok(scale),
+        Ok(_) => Err("expected a positive scale denominator".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+enum InputFormat {
+    Pbf,
+    Xml,
+    XmlGz,
+}
+
+/// Detects the format of the input file from its first bytes, falling back
+/// to the file extension.
+fn detect_format(path: &str, file: &mut fs::File) -> io::Result<InputFormat> {
+    let mut magic = [0u8; 2];
+    let n = file.read(&mut magic)?;
+    file.rewind()?;
+    Ok(match &magic[..n] {
+        [0x1f, 0x8b] => InputFormat::XmlGz,
+        // XML declaration, root element or UTF-8 BOM
+        [b'<' | 0xef, ..] => InputFormat::Xml,
+        _ if path.ends_with(".osm.gz") => InputFormat::XmlGz,
+        _ if path.ends_with(".osm") || path.ends_with(".xml") => InputFormat::Xml,
+        _ => InputFormat::Pbf,
+    })
+}
+
+fn read_style(path: &str, scale: Option<f64>) -> style_file::ast::Style {
+    let config = style_file::read(Path::new(path), scale).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    println!("{:?}", config);
+    config
+}
+
+/// Returns the tag keys for `--filter-tags`, if the style allows to drop
+/// the elements without them.
+fn tag_keys(style: &style_file::ast::Style) -> Option<HashSet<String>> {
+    let keys = style.tag_keys();
+    if keys.is_none() {
+        eprintln!("Ignoring --filter-tags, the style draws elements that are not selected by tag");
+    }
+    keys
+}
+
+fn read_input(path: &str, filter: osmpbf::LoadFilter, with_metadata: bool) -> osmpbf::OsmFile {
+    let mut file = fs::File::open(path).expect("Unable to read file.");
+    let format = detect_format(path, &mut file).expect("Unable to read file.");
+    let osm_file = match format {
+        InputFormat::Pbf => osmpbf::PbfReader::new(BufReader::new(file))
+            .with_filter(filter)
+            .with_metadata(with_metadata)
+            .with_progress(|progress| {
+                print!(
+                    "\rReading OSM file (pass {})... {}%",
+                    progress.pass,
+                    progress.bytes_read * 100 / progress.total_bytes.max(1)
+                );
+                let _ = io::stdout().flush();
+            })
+            .read()
+            .map_err(|e| e.to_string()),
+        // XML files are small enough to be loaded completely
+        InputFormat::Xml => {
+            print!("Reading OSM XML file...");
+            osmpbf::XmlReader::new(BufReader::new(file))
+                .with_filter(filter)
+                .with_metadata(with_metadata)
+                .read()
+                .map_err(|e| e.to_string())
+        }
+        InputFormat::XmlGz => {
+            print!("Reading OSM XML file...");
+            osmpbf::XmlReader::new(BufReader::new(GzDecoder::new(file)))
+                .with_filter(filter)
+                .with_metadata(with_metadata)
+                .read()
+                .map_err(|e| e.to_string())
+        }
+    };
+    println!();
+    osm_file.unwrap_or_else(|e| {
+        eprintln!("Unable to read {}: {}", path, e);
+        std::process::exit(1);
+    })
+}
+
+/// Reads the input file, or its snapshot if there is an up-to-date one, and
+/// applies the change files to it.
+fn load_input(
+    args: &InputArgs,
+    filter: osmpbf::LoadFilter,
+    with_metadata: bool,
+) -> osmpbf::OsmFile {
+    let snapshot = args.cache.as_deref().map(|path| {
+        let key = osmpbf::SnapshotKey::new(Path::new(&args.input), &filter, with_metadata)
+            .expect("Unable to read file.");
+        (Path::new(path), key)
+    });
+    let cached = snapshot.as_ref().and_then(|(path, key)| {
+        osmpbf::OsmFile::read_snapshot(path, key).unwrap_or_else(|e| {
+            eprintln!("Ignoring cache {}: {}", path.display(), e);
+            None
+        })
+    });
+    let mut osm_file = match cached {
+        Some(osm_file) => {
+            println!("Read cached data from {}", args.cache.as_ref().unwrap());
+            osm_file
+        }
+        None => {
+            let osm_file = read_input(&args.input, filter, with_metadata);
+            if let Some((path, key)) = &snapshot {
+                match osm_file.write_snapshot(path, key) {
+                    Ok(()) => println!("Wrote cache {}", path.display()),
+                    Err(e) => eprintln!("Unable to write cache {}: {}", path.display(), e),
+                }
+            }
+            osm_file
+        }
+    };
+
+    for path in &args.change {
+        let mut file = fs::File::open(path).expect("Unable to read change file.");
+        let reader: Box<dyn io::BufRead> = match detect_format(path, &mut file) {
+            Ok(InputFormat::XmlGz) => Box::new(BufReader::new(GzDecoder::new(file))),
+            _ => Box::new(BufReader::new(file)),
+        };
+        let change = osmpbf::XmlReader::new(reader)
+            .with_metadata(with_metadata)
+            .read_change()
+            .unwrap_or_else(|e| {
+                eprintln!("Unable to read {}: {}", path, e);
+                std::process::exit(1);
+            });
+        if change.is_empty() {
+            println!("No changes in {}", path);
+            continue;
+        }
+        println!("Applying {} changes from {}", change.len(), path);
+        osm_file.apply_change(change);
+    }
+    if let Some(timestamp) = osm_file.header().replication_timestamp {
+        println!("Data timestamp: {}", osmpbf::format_timestamp(timestamp));
+    }
+    osm_file
 }
 
 fn main() {
     env_logger::init();
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    let file = fs::read(args.input).expect("Unable to read file.");
-    println!("Reading OSM file...");
-    let osm_file = osmpbf::read_osm_file(&file).unwrap();
+    match cli.command {
+        Some(Command::Extract(args)) => extract(args),
+        Some(Command::Fmt(args)) => fmt(args),
+        // Without a subcommand, clap requires the render arguments
+        None => render(cli.input.unwrap(), cli.render.unwrap()),
+    }
+}
 
-    let style_file = fs::read_to_string(args.style_file).expect("Unable to read style file.");
+fn fmt(args: FmtArgs) {
+    for path in &args.style_files {
+        let formatted = style_file::format_file(Path::new(path)).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        if args.write {
+            fs::write(path, formatted).expect("Unable to write file");
+        } else {
+            print!("{}", formatted);
+        }
+    }
+}
 
-    let config = style_file::style::StyleParser::new()
-        .parse(&style_file)
-        .unwrap();
-    println!("{:?}", config);
+fn extract(args: ExtractArgs) {
+    let style = args
+        .style_file
+        .as_deref()
+        .map(|path| read_style(path, None));
+
+    let mut filter = osmpbf::LoadFilter::default();
+    if !args.input.load_all {
+        filter.bbox = args.bbox.or_else(|| {
+            let meta = &style.as_ref()?.meta;
+            Some(render::view_bbox(meta, args.input.bbox_margin))
+        });
+    }
+    if args.input.filter_tags {
+        filter.tag_keys = style.as_ref().and_then(tag_keys);
+    }
+    let bbox = filter.bbox;
+
+    // Keep the metadata, so the extract is a faithful copy of the input
+    let osm_file = load_input(&args.input, filter, true);
+    let ids = style.map(|style| {
+        let mut ids = render::select_elements(&osm_file, &style);
+        ids.complete(&osm_file);
+        ids
+    });
+
+    let file = fs::File::create(&args.output).expect("Unable to create file");
+    let mut writer = osmpbf::PbfWriter::new(BufWriter::new(file));
+    if let Some(bbox) = bbox {
+        writer = writer.with_bbox(bbox);
+    }
+    writer
+        .write(&osm_file, ids.as_ref())
+        .expect("Can't write to file");
+}
+
+fn render(input: InputArgs, args: RenderArgs) {
+    let config = read_style(&args.style_file, args.scale);
+
+    let mut filter = osmpbf::LoadFilter::default();
+    if !input.load_all {
+        filter.bbox = Some(render::view_bbox(&config.meta, input.bbox_margin));
+    }
+    if input.filter_tags {
+        filter.tag_keys = tag_keys(&config);
+    }
+
+    let osm_file = load_input(&input, filter, config.uses_element_info());
 
     let surface = ImageSurface::create(
         Format::ARgb32,
@@ -57,3 +370,85 @@ fn main() {
         .write_to_png(&mut file)
         .expect("Can't write to file");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+
+    const SAMPLE: &[u8] = include_bytes!("../tests/data/sample.osm");
+
+    /// Writes `contents` to a temporary file whose name ends with `name`.
+    fn temp_file(name: &str, contents: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("chaze-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn format_of(name: &str, contents: &[u8]) -> InputFormat {
+        let path = temp_file(name, contents);
+        let mut file = fs::File::open(&path).unwrap();
+        let format = detect_format(&path, &mut file).unwrap();
+        // The file is rewound for the reader
+        assert_eq!(file.stream_position().unwrap(), 0);
+        fs::remove_file(path).unwrap();
+        format
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn detects_format_from_magic_bytes() {
+        let pbf = b"\0\0\0\x0d\x0a\x09OSMHeader";
+        assert!(matches!(format_of("magic", pbf), InputFormat::Pbf));
+        assert!(matches!(format_of("magic.pbf", SAMPLE), InputFormat::Xml));
+        let bom = [b"\xef\xbb\xbf".as_slice(), SAMPLE].concat();
+        assert!(matches!(format_of("bom.pbf", &bom), InputFormat::Xml));
+        let gz = gzip(SAMPLE);
+        assert!(matches!(format_of("magic.osm", &gz), InputFormat::XmlGz));
+    }
+
+    #[test]
+    fn detects_format_from_extension() {
+        assert!(matches!(format_of("a.osm.gz", b""), InputFormat::XmlGz));
+        assert!(matches!(format_of("a.osm", b""), InputFormat::Xml));
+        assert!(matches!(format_of("a.xml", b"x"), InputFormat::Xml));
+        assert!(matches!(format_of("a.osm.pbf", b""), InputFormat::Pbf));
+        assert!(matches!(format_of("a", b"x"), InputFormat::Pbf));
+    }
+
+    #[test]
+    fn scale_argument() {
+        let scale = |arg: &str| {
+            Cli::try_parse_from(["chaze", "-i", "a.osm", "-s", "a.chz", arg])
+                .map(|cli| cli.render.unwrap().scale)
+        };
+        assert_eq!(scale("--scale=25000").unwrap(), Some(25000.0));
+        assert_eq!(scale("--scale=0.5").unwrap(), Some(0.5));
+        for invalid in [
+            "--scale=0",
+            "--scale=-5000",
+            "--scale=inf",
+            "--scale=NaN",
+            "--scale=x",
+        ] {
+            assert!(scale(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn reads_gzipped_xml() {
+        let path = temp_file("sample.osm.gz", &gzip(SAMPLE));
+        let osm_file = read_input(&path, osmpbf::LoadFilter::default(), false);
+        fs::remove_file(path).unwrap();
+        assert_eq!(osm_file.nodes().count(), 7);
+        assert_eq!(osm_file.ways().count(), 4);
+        assert_eq!(osm_file.relations().count(), 2);
+        let node = osm_file.get_node(1).unwrap();
+        assert_eq!(node.get_tag_value("railway").unwrap(), "station");
+    }
+}
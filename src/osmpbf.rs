@@ -1,24 +1,141 @@
 use bimap;
 use flate2::read::ZlibDecoder;
 use prost::Message;
+use rayon::prelude::*;
 use std::collections::{BTreeMap, HashMap};
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
+
+mod change;
+mod error;
+mod filter;
+mod geometry;
+mod nodes;
+mod parents;
+mod snapshot;
+mod spatial;
+mod writer;
+mod xml;
+
+pub use error::{BlobError, PbfError};
+pub use filter::{BBox, LoadFilter};
+pub use nodes::ElementRef;
+use nodes::NodeStore;
+pub use snapshot::SnapshotKey;
+pub use writer::{ElementIds, PbfWriter};
+pub use xml::XmlReader;
 
 // Protobuf description for the OpenStreetMap PBF format
 mod osmpbf {
     include!(concat!(env!("OUT_DIR"), "/osmpbf.rs"));
 }
 
+#[derive(Clone)]
 pub struct OsmElement<T> {
     id: i64,
     tags: HashMap<u32, u32>,
+    info: Option<Box<OsmElementInfo>>,
     el: T,
 }
 
+/// Metadata about the last edit of an element.
+#[derive(Clone)]
+pub struct OsmElementInfo {
+    pub version: i32,
+    /// Seconds since the Unix epoch.
+    pub timestamp: Option<i64>,
+    pub changeset: Option<i64>,
+    pub uid: Option<i32>,
+    user_sid: Option<u32>,
+}
+
+/// Information from the header block of a PBF file.
+#[derive(Default)]
+pub struct OsmHeader {
+    pub bbox: Option<BBox>,
+    pub required_features: Vec<String>,
+    pub optional_features: Vec<String>,
+    pub writing_program: Option<String>,
+    pub source: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub replication_timestamp: Option<i64>,
+    pub replication_sequence_number: Option<i64>,
+    pub replication_base_url: Option<String>,
+}
+
+impl From<osmpbf::HeaderBlock> for OsmHeader {
+    fn from(header: osmpbf::HeaderBlock) -> Self {
+        // The header bbox is always in nanodegrees
+        let bbox = header.bbox.map(|bbox| BBox {
+            min_lat: bbox.bottom as f64 * 1e-9,
+            min_lon: bbox.left as f64 * 1e-9,
+            max_lat: bbox.top as f64 * 1e-9,
+            max_lon: bbox.right as f64 * 1e-9,
+        });
+        OsmHeader {
+            bbox,
+            required_features: header.required_features,
+            optional_features: header.optional_features,
+            writing_program: header.writingprogram,
+            source: header.source,
+            replication_timestamp: header.osmosis_replication_timestamp,
+            replication_sequence_number: header.osmosis_replication_sequence_number,
+            replication_base_url: header.osmosis_replication_base_url,
+        }
+    }
+}
+
+/// Formats seconds since the Unix epoch as an ISO 8601 UTC timestamp.
+pub fn format_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let secs = timestamp.rem_euclid(86400);
+    // Civil date from days since 1970-01-01, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Parses an ISO 8601 UTC timestamp as written by [`format_timestamp`] into
+/// seconds since the Unix epoch.
+pub fn parse_timestamp(s: &str) -> Option<i64> {
+    if s.len() != 20 || !s.ends_with('Z') {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| s.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, min, sec) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    // Inverse of the conversion in format_timestamp (days_from_civil)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some(days * 86400 + hour * 3600 + min * 60 + sec)
+}
+
+/// Node location in degrees (WGS84).
+#[derive(Clone, Copy)]
 pub struct OsmNodeData {
-    pub lat: i64,
-    pub lon: i64,
+    pub lat: f64,
+    pub lon: f64,
 }
+#[derive(Clone)]
 pub struct OsmWayData {
     pub refs: Vec<i64>,
 }
@@ -28,7 +145,7 @@ pub struct OsmRelationMemberInfo {
     pub role_sid: u32,
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OsmRelationMemberType {
     Node,
     Way,
@@ -63,10 +180,51 @@ impl StringTable {
     pub fn get(&self, id: u32) -> Option<&String> {
         self.strings.get_by_left(&id)
     }
+
+    /// Consumes the table, returning the strings ordered by their id.
+    fn into_strings(self) -> Vec<String> {
+        let mut strings = self.strings.into_iter().collect::<Vec<_>>();
+        strings.sort_unstable_by_key(|(id, _)| *id);
+        strings.into_iter().map(|(_, s)| s).collect()
+    }
+}
+
+/// Lazily translates the string ids of one string table into ids of another.
+struct StringRemap<'a> {
+    strings: Vec<String>,
+    ids: Vec<Option<u32>>,
+    table: &'a mut StringTable,
+}
+
+impl<'a> StringRemap<'a> {
+    fn new(from: StringTable, to: &'a mut StringTable) -> Self {
+        let strings = from.into_strings();
+        StringRemap {
+            ids: vec![None; strings.len()],
+            strings,
+            table: to,
+        }
+    }
+
+    fn get(&mut self, id: u32) -> u32 {
+        let table = &mut *self.table;
+        let strings = &self.strings;
+        *self.ids[id as usize].get_or_insert_with(|| table.insert(strings[id as usize].clone()))
+    }
+
+    fn element<T>(&mut self, el: &mut OsmElement<T>) {
+        el.tags = std::mem::take(&mut el.tags)
+            .into_iter()
+            .map(|(k, v)| (self.get(k), self.get(v)))
+            .collect();
+        if let Some(sid) = el.info.as_mut().and_then(|info| info.user_sid.as_mut()) {
+            *sid = self.get(*sid);
+        }
+    }
 }
 
 pub struct OsmFileElement<'a, T> {
-    pub el: &'a OsmElement<T>,
+    pub el: ElementRef<'a, T>,
     pub osm_file: &'a OsmFile,
 }
 
@@ -74,14 +232,20 @@ type IDMap<T> = std::collections::HashMap<i64, T>;
 
 #[derive(Default)]
 pub struct OsmFile {
-    header: osmpbf::HeaderBlock,
+    header: OsmHeader,
     string_table: StringTable,
-    nodes: IDMap<OsmElement<OsmNodeData>>,
+    nodes: NodeStore,
     ways: IDMap<OsmElement<OsmWayData>>,
     relations: IDMap<OsmElement<OsmRelationData>>,
+    parents: std::sync::OnceLock<parents::ParentIndex>,
+    spatial: std::sync::OnceLock<spatial::SpatialIndex>,
 }
 
 impl OsmFile {
+    pub fn header(&self) -> &OsmHeader {
+        &self.header
+    }
+
     pub fn get_string(&self, id: u32) -> Option<&String> {
         self.string_table.get(id)
     }
@@ -92,43 +256,74 @@ impl OsmFile {
 
     pub fn nodes(&self) -> impl Iterator<Item = OsmFileElement<OsmNodeData>> {
         self.nodes
-            .values()
+            .iter()
             .map(|el| OsmFileElement { el, osm_file: self })
     }
 
     pub fn ways(&self) -> impl Iterator<Item = OsmFileElement<OsmWayData>> {
-        self.ways
-            .values()
-            .map(|el| OsmFileElement { el, osm_file: self })
+        self.ways.values().map(|el| OsmFileElement {
+            el: ElementRef::Borrowed(el),
+            osm_file: self,
+        })
     }
 
     pub fn relations(&self) -> impl Iterator<Item = OsmFileElement<OsmRelationData>> {
-        self.relations
-            .values()
-            .map(|el| OsmFileElement { el, osm_file: self })
+        self.relations.values().map(|el| OsmFileElement {
+            el: ElementRef::Borrowed(el),
+            osm_file: self,
+        })
     }
 
     pub fn get_node(&self, id: i64) -> Option<OsmFileElement<OsmNodeData>> {
         Some(OsmFileElement {
-            el: self.nodes.get(&id)?,
+            el: self.nodes.get(id)?,
             osm_file: self,
         })
     }
 
     pub fn get_way(&self, id: i64) -> Option<OsmFileElement<OsmWayData>> {
         Some(OsmFileElement {
-            el: self.ways.get(&id)?,
+            el: ElementRef::Borrowed(self.ways.get(&id)?),
             osm_file: self,
         })
     }
 
     pub fn get_relation(&self, id: i64) -> Option<OsmFileElement<OsmRelationData>> {
         Some(OsmFileElement {
-            el: self.relations.get(&id)?,
+            el: ElementRef::Borrowed(self.relations.get(&id)?),
             osm_file: self,
         })
     }
 
+    /// Drops the indexes built on demand, after the elements have changed.
+    fn drop_indexes(&mut self) {
+        self.parents.take();
+        self.spatial.take();
+    }
+
+    /// Moves all elements of `other` into this file, translating the string
+    /// ids of `other` into ids of this file's string table. Only strings
+    /// that are still referenced by an element of `other` are added.
+    fn merge(&mut self, other: OsmFile) {
+        self.drop_indexes();
+        let mut remap = StringRemap::new(other.string_table, &mut self.string_table);
+
+        self.nodes.merge(other.nodes, |node| remap.element(node));
+        self.ways.reserve(other.ways.len());
+        for (id, mut way) in other.ways {
+            remap.element(&mut way);
+            self.ways.insert(id, way);
+        }
+        self.relations.reserve(other.relations.len());
+        for (id, mut relation) in other.relations {
+            remap.element(&mut relation);
+            for (_, member) in relation.el.members.iter_mut() {
+                member.role_sid = remap.get(member.role_sid);
+            }
+            self.relations.insert(id, relation);
+        }
+    }
+
     #[allow(dead_code)]
     pub fn get_el_name(&self, id: i64) -> String {
         if let Some(node) = self.get_node(id) {
@@ -156,6 +351,17 @@ impl<T> OsmFileElement<'_, T> {
         &self.el.el
     }
 
+    /// Returns the element's metadata, if it was read from the file.
+    pub fn info(&self) -> Option<&OsmElementInfo> {
+        self.el.info.as_deref()
+    }
+
+    /// Returns the name of the user who last edited the element.
+    pub fn user(&self) -> Option<&String> {
+        let sid = self.info()?.user_sid?;
+        self.osm_file.get_string(sid)
+    }
+
     #[allow(dead_code)]
     pub fn tags(&self) -> BTreeMap<&String, &String> {
         let mut res = BTreeMap::new();
@@ -174,20 +380,159 @@ impl<T> OsmFileElement<'_, T> {
     }
 }
 
-fn decompress_blob_data(blob: osmpbf::Blob) -> Result<Vec<u8>, std::io::Error> {
-    match blob.data {
+/// Coordinate and timestamp encoding of a single `PrimitiveBlock`.
+///
+/// Coordinates are stored as multiples of `granularity` nanodegrees
+/// relative to the block's offsets, timestamps as multiples of
+/// `date_granularity` milliseconds.
+pub struct BlockGranularity {
+    granularity: i64,
+    lat_offset: i64,
+    lon_offset: i64,
+    date_granularity: i64,
+}
+
+impl BlockGranularity {
+    fn new(block: &osmpbf::PrimitiveBlock) -> Self {
+        BlockGranularity {
+            granularity: block.granularity() as i64,
+            lat_offset: block.lat_offset(),
+            lon_offset: block.lon_offset(),
+            date_granularity: block.date_granularity() as i64,
+        }
+    }
+
+    fn lat(&self, lat: i64) -> Result<f64, BlobError> {
+        Self::coord(self.lat_offset, self.granularity, lat)
+    }
+
+    fn lon(&self, lon: i64) -> Result<f64, BlobError> {
+        Self::coord(self.lon_offset, self.granularity, lon)
+    }
+
+    fn coord(offset: i64, granularity: i64, value: i64) -> Result<f64, BlobError> {
+        granularity
+            .checked_mul(value)
+            .and_then(|nano| nano.checked_add(offset))
+            .map(|nano| nano as f64 * 1e-9)
+            .ok_or_else(|| BlobError::Invalid(format!("Coordinate {} out of range", value)))
+    }
+
+    /// Returns the timestamp in seconds since the Unix epoch.
+    fn timestamp(&self, timestamp: i64) -> Result<i64, BlobError> {
+        timestamp
+            .checked_mul(self.date_granularity)
+            .map(|millis| millis / 1000)
+            .ok_or_else(|| BlobError::Invalid(format!("Timestamp {} out of range", timestamp)))
+    }
+}
+
+/// Adds the next value of a delta coded array to the running `sum` and
+/// returns the decoded value.
+fn add_delta(sum: &mut i64, delta: i64) -> Result<i64, BlobError> {
+    *sum = sum
+        .checked_add(delta)
+        .ok_or_else(|| BlobError::Invalid("Delta coded value out of range".to_string()))?;
+    Ok(*sum)
+}
+
+/// Reads a decompression stream to its end. If the blob declares its
+/// uncompressed size, reading stops after one byte more than that, so that
+/// a wrong `raw_size` is detected without inflating the whole stream.
+fn read_decoder<R: Read>(
+    mut decoder: R,
+    raw_size: Option<usize>,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut blob_data = Vec::with_capacity(raw_size.unwrap_or(0));
+    match raw_size {
+        Some(size) => decoder.take(size as u64 + 1).read_to_end(&mut blob_data)?,
+        None => decoder.read_to_end(&mut blob_data)?,
+    };
+    Ok(blob_data)
+}
+
+fn decompress_blob_data(blob: osmpbf::Blob) -> Result<Vec<u8>, BlobError> {
+    let raw_size = match blob.raw_size {
+        Some(size) if !(0..=MAX_BLOB_SIZE).contains(&size) => {
+            return Err(BlobError::Invalid(format!(
+                "Invalid blob raw_size {}",
+                size
+            )))
+        }
+        size => size.map(|s| s as usize),
+    };
+    let blob_data = match blob.data {
+        Some(osmpbf::blob::Data::Raw(d)) => d,
         Some(osmpbf::blob::Data::ZlibData(d)) => {
-            let mut blob_data = Vec::new();
-            ZlibDecoder::new(&d[..])
-                .read_to_end(&mut blob_data)
-                .unwrap();
-            Ok(blob_data)
+            read_decoder(ZlibDecoder::new(&d[..]), raw_size).map_err(BlobError::Decompress)?
+        }
+        #[cfg(feature = "lzma")]
+        Some(osmpbf::blob::Data::LzmaData(d)) => {
+            // Accepts both the .xz and the legacy .lzma container
+            let stream = xz2::stream::Stream::new_auto_decoder(u64::MAX, 0)
+                .map_err(|e| BlobError::Decompress(e.into()))?;
+            read_decoder(xz2::read::XzDecoder::new_stream(&d[..], stream), raw_size)
+                .map_err(BlobError::Decompress)?
+        }
+        #[cfg(feature = "bzip2")]
+        Some(osmpbf::blob::Data::ObsoleteBzip2Data(d)) => {
+            read_decoder(bzip2::read::BzDecoder::new(&d[..]), raw_size)
+                .map_err(BlobError::Decompress)?
+        }
+        #[cfg(feature = "lz4")]
+        Some(osmpbf::blob::Data::Lz4Data(d)) => {
+            // LZ4 blobs are stored in the block format, which does not
+            // record the uncompressed size itself. One byte more is allowed,
+            // so that a wrong `raw_size` is reported like for other blobs.
+            let size = raw_size
+                .ok_or_else(|| BlobError::Invalid("LZ4 blob without raw_size".to_string()))?;
+            lz4_flex::block::decompress(&d, size + 1).map_err(|e| {
+                BlobError::Decompress(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            })?
+        }
+        #[cfg(feature = "zstd")]
+        Some(osmpbf::blob::Data::ZstdData(d)) => {
+            let decoder =
+                zstd::stream::read::Decoder::new(&d[..]).map_err(BlobError::Decompress)?;
+            read_decoder(decoder, raw_size).map_err(BlobError::Decompress)?
+        }
+        // Compressions whose cargo feature is disabled
+        #[cfg(not(feature = "lzma"))]
+        Some(osmpbf::blob::Data::LzmaData(_)) => {
+            return Err(BlobError::UnsupportedCompression("lzma"))
+        }
+        #[cfg(not(feature = "bzip2"))]
+        Some(osmpbf::blob::Data::ObsoleteBzip2Data(_)) => {
+            return Err(BlobError::UnsupportedCompression("bzip2"))
+        }
+        #[cfg(not(feature = "lz4"))]
+        Some(osmpbf::blob::Data::Lz4Data(_)) => {
+            return Err(BlobError::UnsupportedCompression("lz4"))
+        }
+        #[cfg(not(feature = "zstd"))]
+        Some(osmpbf::blob::Data::ZstdData(_)) => {
+            return Err(BlobError::UnsupportedCompression("zstd"))
+        }
+        None => return Err(BlobError::Invalid("Blob contains no data".to_string())),
+    };
+    if let Some(size) = raw_size {
+        if blob_data.len() != size {
+            return Err(BlobError::Invalid(format!(
+                "Blob raw_size is {}, but it decompressed to {} bytes",
+                size,
+                blob_data.len()
+            )));
         }
-        _ => Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Unknown blob data type",
-        )),
     }
+    Ok(blob_data)
+}
+
+/// Looks up an entry of a block's string table.
+fn block_string(osm_string_table: &[String], idx: i64) -> Result<&String, BlobError> {
+    usize::try_from(idx)
+        .ok()
+        .and_then(|idx| osm_string_table.get(idx))
+        .ok_or_else(|| BlobError::Invalid(format!("String index {} out of bounds", idx)))
 }
 
 fn tag_to_string_table_idxs(
@@ -195,19 +540,64 @@ fn tag_to_string_table_idxs(
     v: u32,
     osm_string_table: &[String],
     string_table: &mut StringTable,
-) -> (u32, u32) {
-    let k_str = osm_string_table[k as usize].clone();
-    let v_str = osm_string_table[v as usize].clone();
+) -> Result<(u32, u32), BlobError> {
+    let k_str = block_string(osm_string_table, k as i64)?.clone();
+    let v_str = block_string(osm_string_table, v as i64)?.clone();
     let k_id = string_table.insert(k_str);
     let v_id = string_table.insert(v_str);
-    (k_id, v_id)
+    Ok((k_id, v_id))
+}
+
+fn read_info(
+    osm_file: &mut OsmFile,
+    string_table: &[String],
+    granularity: &BlockGranularity,
+    info: &osmpbf::Info,
+) -> Result<Box<OsmElementInfo>, BlobError> {
+    let user_sid = match info.user_sid {
+        Some(sid) => {
+            let user = block_string(string_table, sid as i64)?;
+            Some(osm_file.string_table.insert(user.clone()))
+        }
+        None => None,
+    };
+    Ok(Box::new(OsmElementInfo {
+        version: info.version(),
+        timestamp: info
+            .timestamp
+            .map(|t| granularity.timestamp(t))
+            .transpose()?,
+        changeset: info.changeset,
+        uid: info.uid,
+        user_sid,
+    }))
+}
+
+fn read_tags(
+    osm_file: &mut OsmFile,
+    string_table: &[String],
+    keys: &[u32],
+    vals: &[u32],
+) -> Result<HashMap<u32, u32>, BlobError> {
+    if keys.len() != vals.len() {
+        return Err(BlobError::Invalid(
+            "Different number of tag keys and values".to_string(),
+        ));
+    }
+    let mut tags = HashMap::new();
+    for (k, v) in keys.iter().zip(vals.iter()) {
+        let (k_id, v_id) =
+            tag_to_string_table_idxs(*k, *v, string_table, &mut osm_file.string_table)?;
+        tags.insert(k_id, v_id);
+    }
+    Ok(tags)
 }
 
 fn read_dense_tags(
     osm_file: &mut OsmFile,
     string_table: &[String],
     keys_vals: &[i32],
-) -> Vec<HashMap<u32, u32>> {
+) -> Result<Vec<HashMap<u32, u32>>, BlobError> {
     let mut tags = HashMap::new();
     let mut res = Vec::new();
     let mut i = 0;
@@ -219,115 +609,233 @@ fn read_dense_tags(
             i += 1;
             continue;
         }
-        let v = keys_vals[i + 1];
+        let Some(&v) = keys_vals.get(i + 1) else {
+            return Err(BlobError::Invalid(
+                "Dense node tag without value".to_string(),
+            ));
+        };
         let (k_id, v_id) =
-            tag_to_string_table_idxs(k as u32, v as u32, string_table, &mut osm_file.string_table);
+            tag_to_string_table_idxs(k as u32, v as u32, string_table, &mut osm_file.string_table)?;
         tags.insert(k_id, v_id);
         i += 2;
     }
-    res
+    Ok(res)
 }
 
-pub fn read_osm_nodes(osm_file: &mut OsmFile, string_table: &[String], nodes: &[osmpbf::Node]) {
+pub fn read_osm_nodes(
+    osm_file: &mut OsmFile,
+    string_table: &[String],
+    granularity: &BlockGranularity,
+    with_metadata: bool,
+    nodes: &[osmpbf::Node],
+) -> Result<(), BlobError> {
     for node in nodes {
-        let mut tags = HashMap::new();
-        for (k, v) in node.keys.iter().zip(node.vals.iter()) {
-            let (k_id, v_id) =
-                tag_to_string_table_idxs(*k, *v, string_table, &mut osm_file.string_table);
-            tags.insert(k_id, v_id);
-        }
-        osm_file.nodes.insert(
-            node.id,
-            OsmElement {
-                id: node.id,
-                tags,
-                el: OsmNodeData {
-                    lat: node.lat,
-                    lon: node.lon,
-                },
+        let tags = read_tags(osm_file, string_table, &node.keys, &node.vals)?;
+        let info = match &node.info {
+            Some(info) if with_metadata => {
+                Some(read_info(osm_file, string_table, granularity, info)?)
+            }
+            _ => None,
+        };
+        osm_file.nodes.insert(OsmElement {
+            id: node.id,
+            tags,
+            info,
+            el: OsmNodeData {
+                lat: granularity.lat(node.lat)?,
+                lon: granularity.lon(node.lon)?,
             },
-        );
+        });
     }
+    Ok(())
 }
 
 pub fn read_osm_dense_nodes(
     osm_file: &mut OsmFile,
     string_table: &[String],
+    granularity: &BlockGranularity,
+    with_metadata: bool,
     dense_nodes: &osmpbf::DenseNodes,
-) {
+) -> Result<(), BlobError> {
+    let count = dense_nodes.id.len();
+    if dense_nodes.lat.len() != count || dense_nodes.lon.len() != count {
+        return Err(BlobError::Invalid(
+            "Dense node arrays have different lengths".to_string(),
+        ));
+    }
+    // keys_vals may be omitted if none of the nodes has tags
+    let mut tags = read_dense_tags(osm_file, string_table, &dense_nodes.keys_vals)?;
+    if dense_nodes.keys_vals.is_empty() {
+        tags.resize_with(count, HashMap::new);
+    } else if tags.len() != count {
+        return Err(BlobError::Invalid(
+            "Dense node tags do not match the number of nodes".to_string(),
+        ));
+    }
+
     let mut id = 0;
     let mut lat = 0;
     let mut lon = 0;
-    let tags = read_dense_tags(osm_file, string_table, &dense_nodes.keys_vals);
-    osm_file.nodes.reserve(tags.len());
+    let dense_info = dense_nodes.denseinfo.as_ref().filter(|_| with_metadata);
+    let mut timestamp = 0;
+    let mut changeset = 0;
+    let mut uid = 0;
+    let mut user_sid = 0;
+    osm_file.nodes.reserve(count);
     for (i, t) in tags.into_iter().enumerate() {
-        id += dense_nodes.id[i];
-        lat += dense_nodes.lat[i];
-        lon += dense_nodes.lon[i];
-        osm_file.nodes.insert(
+        add_delta(&mut id, dense_nodes.id[i])?;
+        add_delta(&mut lat, dense_nodes.lat[i])?;
+        add_delta(&mut lon, dense_nodes.lon[i])?;
+        let info = match dense_info {
+            Some(dense_info) => {
+                // All fields except the version are delta coded
+                let version = dense_info.version.get(i).copied();
+                let timestamp = match dense_info.timestamp.get(i) {
+                    Some(t) => Some(granularity.timestamp(add_delta(&mut timestamp, *t)?)?),
+                    None => None,
+                };
+                let changeset = match dense_info.changeset.get(i) {
+                    Some(c) => Some(add_delta(&mut changeset, *c)?),
+                    None => None,
+                };
+                let uid = match dense_info.uid.get(i) {
+                    Some(u) => {
+                        Some(i32::try_from(add_delta(&mut uid, *u as i64)?).map_err(|_| {
+                            BlobError::Invalid(format!("User id {} out of range", uid))
+                        })?)
+                    }
+                    None => None,
+                };
+                let user_sid = match dense_info.user_sid.get(i) {
+                    Some(sid) => {
+                        let user =
+                            block_string(string_table, add_delta(&mut user_sid, *sid as i64)?)?;
+                        Some(osm_file.string_table.insert(user.clone()))
+                    }
+                    None => None,
+                };
+                Some(Box::new(OsmElementInfo {
+                    version: version.unwrap_or(-1),
+                    timestamp,
+                    changeset,
+                    uid,
+                    user_sid,
+                }))
+            }
+            None => None,
+        };
+        osm_file.nodes.insert(OsmElement {
             id,
-            OsmElement {
-                id,
-                tags: t,
-                el: OsmNodeData { lat, lon },
+            tags: t,
+            info,
+            el: OsmNodeData {
+                lat: granularity.lat(lat)?,
+                lon: granularity.lon(lon)?,
             },
-        );
+        });
     }
+    Ok(())
 }
 
-pub fn read_osm_ways(osm_file: &mut OsmFile, string_table: &[String], ways: &[osmpbf::Way]) {
+pub fn read_osm_ways(
+    osm_file: &mut OsmFile,
+    string_table: &[String],
+    granularity: &BlockGranularity,
+    with_metadata: bool,
+    ways: &[osmpbf::Way],
+) -> Result<(), BlobError> {
     for way in ways {
-        let mut tags = HashMap::new();
-        for (k, v) in way.keys.iter().zip(way.vals.iter()) {
-            let (k_id, v_id) =
-                tag_to_string_table_idxs(*k, *v, string_table, &mut osm_file.string_table);
-            tags.insert(k_id, v_id);
-        }
+        let tags = read_tags(osm_file, string_table, &way.keys, &way.vals)?;
+        let mut id = 0;
         let refs = way
             .refs
             .iter()
-            .scan(0, |state, &x| {
-                *state += x;
-                Some(*state)
-            })
-            .collect();
+            .map(|delta| add_delta(&mut id, *delta))
+            .collect::<Result<Vec<i64>, _>>()?;
+        // Files with the "LocationsOnWays" feature store the node locations
+        // on the way itself, often without including the nodes
+        if (!way.lat.is_empty() || !way.lon.is_empty())
+            && (way.lat.len() != refs.len() || way.lon.len() != refs.len())
+        {
+            return Err(BlobError::Invalid(format!(
+                "Way {} has {} nodes, but {} latitudes and {} longitudes",
+                way.id,
+                refs.len(),
+                way.lat.len(),
+                way.lon.len()
+            )));
+        }
+        if !way.lat.is_empty() {
+            let mut lat = 0;
+            let mut lon = 0;
+            for (i, id) in refs.iter().enumerate() {
+                add_delta(&mut lat, way.lat[i])?;
+                add_delta(&mut lon, way.lon[i])?;
+                if !osm_file.nodes.contains(*id) {
+                    osm_file.nodes.insert(OsmElement {
+                        id: *id,
+                        tags: HashMap::new(),
+                        info: None,
+                        el: OsmNodeData {
+                            lat: granularity.lat(lat)?,
+                            lon: granularity.lon(lon)?,
+                        },
+                    });
+                }
+            }
+        }
+        let info = match &way.info {
+            Some(info) if with_metadata => {
+                Some(read_info(osm_file, string_table, granularity, info)?)
+            }
+            _ => None,
+        };
         osm_file.ways.insert(
             way.id,
             OsmElement {
                 id: way.id,
                 tags,
+                info,
                 el: OsmWayData { refs },
             },
         );
     }
+    Ok(())
 }
 
 pub fn read_osm_relations(
     osm_file: &mut OsmFile,
     string_table: &[String],
+    granularity: &BlockGranularity,
+    with_metadata: bool,
     relations: &[osmpbf::Relation],
-) {
+) -> Result<(), BlobError> {
     for relation in relations {
-        let mut tags = HashMap::new();
-        for (k, v) in relation.keys.iter().zip(relation.vals.iter()) {
-            let (k_id, v_id) =
-                tag_to_string_table_idxs(*k, *v, string_table, &mut osm_file.string_table);
-            tags.insert(k_id, v_id);
+        let tags = read_tags(osm_file, string_table, &relation.keys, &relation.vals)?;
+        let count = relation.memids.len();
+        if relation.roles_sid.len() != count || relation.types.len() != count {
+            return Err(BlobError::Invalid(format!(
+                "Member arrays of relation {} have different lengths",
+                relation.id
+            )));
         }
         let mut members = Vec::new();
         let mut id: i64 = 0;
         for (i, mem_id) in relation.memids.iter().enumerate() {
-            id += *mem_id;
-            let role_orig_sid = relation.roles_sid[i] as usize;
-            let role_sid = osm_file
-                .string_table
-                .insert(string_table[role_orig_sid].clone());
+            add_delta(&mut id, *mem_id)?;
+            let role = block_string(string_table, relation.roles_sid[i] as i64)?;
+            let role_sid = osm_file.string_table.insert(role.clone());
             let member = (
                 match osmpbf::relation::MemberType::try_from(relation.types[i]) {
                     Ok(osmpbf::relation::MemberType::Node) => OsmRelationMemberType::Node,
                     Ok(osmpbf::relation::MemberType::Way) => OsmRelationMemberType::Way,
                     Ok(osmpbf::relation::MemberType::Relation) => OsmRelationMemberType::Relation,
-                    Err(_) => panic!("Unknown member type"),
+                    Err(_) => {
+                        return Err(BlobError::Invalid(format!(
+                            "Unknown member type {} in relation {}",
+                            relation.types[i], relation.id
+                        )))
+                    }
                 },
                 OsmRelationMemberInfo {
                     ref_id: id,
@@ -336,69 +844,947 @@ pub fn read_osm_relations(
             );
             members.push(member);
         }
+        let info = match &relation.info {
+            Some(info) if with_metadata => {
+                Some(read_info(osm_file, string_table, granularity, info)?)
+            }
+            _ => None,
+        };
         osm_file.relations.insert(
             relation.id,
             OsmElement {
                 id: relation.id,
                 tags,
+                info,
                 el: OsmRelationData { members },
             },
         );
     }
+    Ok(())
 }
 
-pub fn read_osm_data(blob: osmpbf::PrimitiveBlock, osm_file: &mut OsmFile) {
+pub fn read_osm_data(
+    blob: osmpbf::PrimitiveBlock,
+    osm_file: &mut OsmFile,
+    with_metadata: bool,
+) -> Result<(), BlobError> {
+    let granularity = BlockGranularity::new(&blob);
     let blob_stringtable = blob
         .stringtable
         .s
         .into_iter()
-        .map(|s| String::from_utf8(s).unwrap())
-        .collect::<Vec<String>>();
+        .map(String::from_utf8)
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(|e| BlobError::Invalid(format!("Invalid string table: {}", e)))?;
     for group in blob.primitivegroup {
         if group.nodes.len() > 0 {
-            read_osm_nodes(osm_file, &blob_stringtable, &group.nodes);
+            read_osm_nodes(
+                osm_file,
+                &blob_stringtable,
+                &granularity,
+                with_metadata,
+                &group.nodes,
+            )?;
         }
         if let Some(dense_nodes) = group.dense {
-            read_osm_dense_nodes(osm_file, &blob_stringtable, &dense_nodes);
+            read_osm_dense_nodes(
+                osm_file,
+                &blob_stringtable,
+                &granularity,
+                with_metadata,
+                &dense_nodes,
+            )?;
         }
         if group.ways.len() > 0 {
-            read_osm_ways(osm_file, &blob_stringtable, &group.ways);
+            read_osm_ways(
+                osm_file,
+                &blob_stringtable,
+                &granularity,
+                with_metadata,
+                &group.ways,
+            )?;
         }
         if group.relations.len() > 0 {
-            read_osm_relations(osm_file, &blob_stringtable, &group.relations);
+            read_osm_relations(
+                osm_file,
+                &blob_stringtable,
+                &granularity,
+                with_metadata,
+                &group.relations,
+            )?;
         }
     }
+    Ok(())
 }
 
-pub fn read_osm_blob(bytes: &[u8], osm_file: &mut OsmFile) -> Result<usize, std::io::Error> {
-    let header_size = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
-    let header = osmpbf::BlobHeader::decode(&bytes[4..4 + header_size]).unwrap();
-    let spos = 4 + header_size as usize;
-    let data = osmpbf::Blob::decode(&bytes[spos..spos + header.datasize as usize])?;
-    let blob_data = decompress_blob_data(data)?;
+/// Features in `HeaderBlock.required_features` that the reader supports.
+const SUPPORTED_FEATURES: [&str; 3] = ["OsmSchema-V0.6", "DenseNodes", "LocationsOnWays"];
 
-    if header.r#type == "OSMHeader" {
-        osm_file.header = osmpbf::HeaderBlock::decode(blob_data.as_slice())?;
-    } else if header.r#type == "OSMData" {
-        let blob = osmpbf::PrimitiveBlock::decode(blob_data.as_slice())?;
-        read_osm_data(blob, osm_file);
-    } else {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Unknown blob type",
-        ));
-    }
-    Ok(spos + header.datasize as usize)
+/// Maximum sizes of blob headers and (uncompressed) blobs, as given by the
+/// format specification.
+const MAX_BLOB_HEADER_SIZE: usize = 64 * 1024;
+const MAX_BLOB_SIZE: i32 = 32 * 1024 * 1024;
+
+/// A blob as stored in the file, before it is decoded.
+struct RawBlob {
+    ty: String,
+    data: Vec<u8>,
+    /// Position of the blob in the file, for error messages
+    index: usize,
+    offset: u64,
+}
+
+enum DecodedBlob {
+    Header(OsmHeader),
+    Data(Box<OsmFile>),
 }
 
-pub fn read_osm_file(file: &[u8]) -> Result<OsmFile, std::io::Error> {
-    let mut f = OsmFile::default();
+/// Like `read_exact`, but returns `false` if the reader is at EOF before
+/// reading the first byte.
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, std::io::Error> {
     let mut pos = 0;
-    while pos < file.len() {
-        match read_osm_blob(&file[pos..], &mut f) {
+    while pos < buf.len() {
+        match reader.read(&mut buf[pos..]) {
+            Ok(0) if pos == 0 => return Ok(false),
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
             Ok(n) => pos += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
             Err(e) => return Err(e),
         }
     }
-    Ok(f)
+    Ok(true)
+}
+
+/// Reads the blob with the given index starting at `offset` and returns it
+/// along with the number of bytes it occupied in the file.
+fn read_raw_blob<R: Read>(
+    reader: &mut R,
+    index: usize,
+    offset: u64,
+) -> Result<Option<(RawBlob, u64)>, PbfError> {
+    let truncated = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => PbfError::Truncated { index, offset },
+        _ => PbfError::Io(e),
+    };
+    let invalid = |error| PbfError::Blob {
+        index,
+        offset,
+        error,
+    };
+
+    let mut size_buf = [0; 4];
+    if !read_exact_or_eof(reader, &mut size_buf).map_err(truncated)? {
+        return Ok(None);
+    }
+    let header_size = u32::from_be_bytes(size_buf) as usize;
+    if header_size > MAX_BLOB_HEADER_SIZE {
+        return Err(invalid(BlobError::Invalid(format!(
+            "Blob header size {} exceeds the maximum",
+            header_size
+        ))));
+    }
+    let mut header_buf = vec![0; header_size];
+    reader.read_exact(&mut header_buf).map_err(truncated)?;
+    let header = osmpbf::BlobHeader::decode(header_buf.as_slice())
+        .map_err(|e| invalid(BlobError::Decode(e)))?;
+    if !(0..=MAX_BLOB_SIZE).contains(&header.datasize) {
+        return Err(invalid(BlobError::Invalid(format!(
+            "Invalid blob size {}",
+            header.datasize
+        ))));
+    }
+    let mut data = vec![0; header.datasize as usize];
+    reader.read_exact(&mut data).map_err(truncated)?;
+    let size = 4 + header_size as u64 + data.len() as u64;
+    Ok(Some((
+        RawBlob {
+            ty: header.r#type,
+            data,
+            index,
+            offset,
+        },
+        size,
+    )))
+}
+
+fn decode_blob_data(raw: RawBlob, with_metadata: bool) -> Result<DecodedBlob, BlobError> {
+    let blob = osmpbf::Blob::decode(raw.data.as_slice())?;
+    let blob_data = decompress_blob_data(blob)?;
+
+    if raw.ty == "OSMHeader" {
+        let header = osmpbf::HeaderBlock::decode(blob_data.as_slice())?;
+        let unsupported = header
+            .required_features
+            .iter()
+            .filter(|feature| !SUPPORTED_FEATURES.contains(&feature.as_str()))
+            .cloned()
+            .collect::<Vec<String>>();
+        if !unsupported.is_empty() {
+            return Err(BlobError::UnsupportedFeatures(unsupported));
+        }
+        Ok(DecodedBlob::Header(header.into()))
+    } else if raw.ty == "OSMData" {
+        let block = osmpbf::PrimitiveBlock::decode(blob_data.as_slice())?;
+        let mut osm_file = OsmFile::default();
+        read_osm_data(block, &mut osm_file, with_metadata)?;
+        Ok(DecodedBlob::Data(Box::new(osm_file)))
+    } else {
+        Err(BlobError::UnknownType(raw.ty))
+    }
+}
+
+fn decode_blob(raw: RawBlob, with_metadata: bool) -> Result<DecodedBlob, PbfError> {
+    let (index, offset) = (raw.index, raw.offset);
+    decode_blob_data(raw, with_metadata).map_err(|error| PbfError::Blob {
+        index,
+        offset,
+        error,
+    })
+}
+
+/// Progress information passed to the callback of a [`PbfReader`].
+pub struct ReadProgress {
+    /// Number of the pass over the file, starting at 1. Files are only read
+    /// more than once if a [`LoadFilter`] is set.
+    pub pass: usize,
+    pub bytes_read: u64,
+    pub total_bytes: u64,
+    pub blobs_read: usize,
+}
+
+type ProgressCallback<'a> = Box<dyn FnMut(&ReadProgress) + 'a>;
+
+/// Streaming PBF reader.
+///
+/// Blobs are read sequentially from the source, while decompression and
+/// decoding of the blocks happens in parallel on the rayon thread pool. The
+/// decoded blocks are merged in file order, so the result does not depend on
+/// the number of threads.
+pub struct PbfReader<'a, R> {
+    reader: R,
+    progress: Option<ProgressCallback<'a>>,
+    filter: Option<LoadFilter>,
+    with_metadata: bool,
+}
+
+impl<'a, R: Read + Seek + Send> PbfReader<'a, R> {
+    pub fn new(reader: R) -> Self {
+        PbfReader {
+            reader,
+            progress: None,
+            filter: None,
+            with_metadata: false,
+        }
+    }
+
+    /// Sets a callback that is invoked after every batch of decoded blobs.
+    pub fn with_progress(mut self, progress: impl FnMut(&ReadProgress) + 'a) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Only keeps the elements selected by `filter` in memory.
+    pub fn with_filter(mut self, filter: LoadFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Also reads the metadata of every element (see [`OsmElementInfo`]).
+    pub fn with_metadata(mut self, with_metadata: bool) -> Self {
+        self.with_metadata = with_metadata;
+        self
+    }
+
+    /// Reads up to `batch_size` blobs. `index` and `offset` track the
+    /// position of the next blob in the file.
+    fn read_batch(
+        reader: &mut R,
+        batch_size: usize,
+        index: &mut usize,
+        offset: &mut u64,
+    ) -> Result<(Vec<RawBlob>, u64), PbfError> {
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut bytes = 0;
+        while batch.len() < batch_size {
+            match read_raw_blob(reader, *index, *offset)? {
+                Some((blob, size)) => {
+                    batch.push(blob);
+                    bytes += size;
+                    *index += 1;
+                    *offset += size;
+                }
+                None => break,
+            }
+        }
+        Ok((batch, bytes))
+    }
+
+    /// Reads all blobs starting at `start`. `prefilter` is applied to every
+    /// decoded data block on the worker threads, after which the blocks are
+    /// handed to `merge` in file order.
+    fn read_pass(
+        &mut self,
+        start: u64,
+        pass: usize,
+        osm_file: &mut OsmFile,
+        prefilter: impl Fn(&mut OsmFile) + Sync,
+        mut merge: impl FnMut(&mut OsmFile, OsmFile),
+    ) -> Result<(), PbfError> {
+        let total_bytes = self.reader.seek(SeekFrom::End(0))? - start;
+        self.reader.seek(SeekFrom::Start(start))?;
+
+        // Read ahead a few blobs per thread so the pool stays busy
+        let batch_size = rayon::current_num_threads() * 4;
+        let mut progress = ReadProgress {
+            pass,
+            bytes_read: 0,
+            total_bytes,
+            blobs_read: 0,
+        };
+        let with_metadata = self.with_metadata;
+        let reader = &mut self.reader;
+        let (mut index, mut offset) = (0, start);
+        let (mut batch, mut batch_bytes) =
+            Self::read_batch(reader, batch_size, &mut index, &mut offset)?;
+        while !batch.is_empty() {
+            let batch_len = batch.len();
+            // Decode the current batch while reading the next one
+            let (decoded, next) = rayon::join(
+                || {
+                    batch
+                        .into_par_iter()
+                        .map(|raw| {
+                            let mut blob = decode_blob(raw, with_metadata)?;
+                            if let DecodedBlob::Data(data) = &mut blob {
+                                prefilter(data);
+                            }
+                            Ok(blob)
+                        })
+                        .collect::<Vec<Result<DecodedBlob, PbfError>>>()
+                },
+                || Self::read_batch(reader, batch_size, &mut index, &mut offset),
+            );
+            for blob in decoded {
+                match blob? {
+                    DecodedBlob::Header(header) => osm_file.header = header,
+                    DecodedBlob::Data(data) => merge(osm_file, *data),
+                }
+            }
+            progress.bytes_read += batch_bytes;
+            progress.blobs_read += batch_len;
+            if let Some(callback) = &mut self.progress {
+                callback(&progress);
+            }
+            (batch, batch_bytes) = next?;
+        }
+        Ok(())
+    }
+
+    pub fn read(mut self) -> Result<OsmFile, PbfError> {
+        let start = self.reader.stream_position()?;
+        let mut osm_file = OsmFile::default();
+        let Some(filter) = self.filter.take() else {
+            self.read_pass(start, 1, &mut osm_file, |_| {}, OsmFile::merge)?;
+            return Ok(osm_file);
+        };
+
+        self.read_pass(
+            start,
+            1,
+            &mut osm_file,
+            |block| filter.retain_nodes_in_bbox(block),
+            |osm_file, mut block| {
+                filter.retain_touching(osm_file, &mut block);
+                osm_file.merge(block);
+            },
+        )?;
+        let mut pass = 2;
+
+        // Complete the elements which were only partially inside the bbox
+        let missing_ways = filter.retain_selected(&mut osm_file);
+        if !missing_ways.is_empty() {
+            self.read_pass(
+                start,
+                pass,
+                &mut osm_file,
+                |block| {
+                    block.nodes.clear();
+                    block.relations.clear();
+                    block.ways.retain(|id, _| missing_ways.contains(id));
+                },
+                OsmFile::merge,
+            )?;
+            pass += 1;
+        }
+        let missing_nodes = filter::missing_nodes(&osm_file);
+        if !missing_nodes.is_empty() {
+            self.read_pass(
+                start,
+                pass,
+                &mut osm_file,
+                |block| {
+                    block.nodes.retain(|node| missing_nodes.contains(&node.id));
+                    block.ways.clear();
+                    block.relations.clear();
+                },
+                OsmFile::merge,
+            )?;
+        }
+        Ok(osm_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Block with coordinates in µ° relative to 0.001°/-0.002°, and
+    /// timestamps in half seconds.
+    fn block(group: osmpbf::PrimitiveGroup) -> osmpbf::PrimitiveBlock {
+        osmpbf::PrimitiveBlock {
+            stringtable: osmpbf::StringTable {
+                s: vec![b"".to_vec()],
+            },
+            primitivegroup: vec![group],
+            granularity: Some(1000),
+            lat_offset: Some(1_000_000),
+            lon_offset: Some(-2_000_000),
+            date_granularity: Some(500),
+        }
+    }
+
+    fn read(block: osmpbf::PrimitiveBlock) -> OsmFile {
+        let mut osm_file = OsmFile::default();
+        read_osm_data(block, &mut osm_file, true).unwrap();
+        osm_file
+    }
+
+    fn assert_node(osm_file: &OsmFile, id: i64, lat: f64, lon: f64, timestamp: i64) {
+        let node = osm_file.get_node(id).unwrap();
+        assert!(
+            (node.data().lat - lat).abs() < 1e-9,
+            "lat {}",
+            node.data().lat
+        );
+        assert!(
+            (node.data().lon - lon).abs() < 1e-9,
+            "lon {}",
+            node.data().lon
+        );
+        assert_eq!(node.info().unwrap().timestamp, Some(timestamp));
+    }
+
+    #[test]
+    fn granularity_of_nodes() {
+        let osm_file = read(block(osmpbf::PrimitiveGroup {
+            nodes: vec![osmpbf::Node {
+                id: 1,
+                lat: 47_378_000,
+                lon: 8_540_000,
+                info: Some(osmpbf::Info {
+                    timestamp: Some(3_400_000_000),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }));
+        assert_node(&osm_file, 1, 47.379, 8.538, 1_700_000_000);
+    }
+
+    #[test]
+    fn granularity_of_dense_nodes() {
+        let osm_file = read(block(osmpbf::PrimitiveGroup {
+            dense: Some(osmpbf::DenseNodes {
+                id: vec![5, 1],
+                lat: vec![47_378_000, 1_000],
+                lon: vec![8_540_000, -1_000],
+                denseinfo: Some(osmpbf::DenseInfo {
+                    version: vec![1, 1],
+                    timestamp: vec![3_400_000_000, 2],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }));
+        assert_node(&osm_file, 5, 47.379, 8.538, 1_700_000_000);
+        assert_node(&osm_file, 6, 47.38, 8.537, 1_700_000_001);
+    }
+
+    fn way_with_locations(lat: Vec<i64>, lon: Vec<i64>) -> osmpbf::PrimitiveGroup {
+        osmpbf::PrimitiveGroup {
+            ways: vec![osmpbf::Way {
+                id: 10,
+                refs: vec![1, 1, 1],
+                lat,
+                lon,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn locations_on_ways() {
+        let mut osm_file = read(block(osmpbf::PrimitiveGroup {
+            dense: Some(osmpbf::DenseNodes {
+                id: vec![2],
+                lat: vec![0],
+                lon: vec![0],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }));
+        let group = way_with_locations(vec![47_378_000, 1_000, 1_000], vec![8_540_000, 0, -1_000]);
+        read_osm_data(block(group), &mut osm_file, true).unwrap();
+        assert_eq!(osm_file.get_way(10).unwrap().data().refs, [1, 2, 3]);
+        let location = |id| {
+            let node = osm_file.get_node(id).unwrap();
+            (node.data().lat, node.data().lon)
+        };
+        assert!((location(1).0 - 47.379).abs() < 1e-9 && (location(1).1 - 8.538).abs() < 1e-9);
+        assert!((location(3).0 - 47.381).abs() < 1e-9 && (location(3).1 - 8.537).abs() < 1e-9);
+        // Nodes that are part of the file keep their own location
+        assert!((location(2).0 - 0.001).abs() < 1e-9 && (location(2).1 + 0.002).abs() < 1e-9);
+
+        let group = way_with_locations(vec![], vec![]);
+        read_osm_data(block(group), &mut OsmFile::default(), true).unwrap();
+        assert_invalid(way_with_locations(vec![1, 1], vec![1, 1]));
+        assert_invalid(way_with_locations(vec![1, 1, 1], vec![]));
+    }
+
+    fn assert_invalid(group: osmpbf::PrimitiveGroup) {
+        let result = read_osm_data(block(group), &mut OsmFile::default(), true);
+        assert!(matches!(result, Err(BlobError::Invalid(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn overflowing_values() {
+        assert_invalid(osmpbf::PrimitiveGroup {
+            dense: Some(osmpbf::DenseNodes {
+                id: vec![i64::MAX, 1],
+                lat: vec![0, 0],
+                lon: vec![0, 0],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            dense: Some(osmpbf::DenseNodes {
+                id: vec![1, 1],
+                lat: vec![0, i64::MIN],
+                lon: vec![0, 0],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        // Overflows only when multiplied by the granularity
+        assert_invalid(osmpbf::PrimitiveGroup {
+            nodes: vec![osmpbf::Node {
+                id: 1,
+                lat: 0,
+                lon: i64::MAX / 100,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            dense: Some(osmpbf::DenseNodes {
+                id: vec![1],
+                lat: vec![0],
+                lon: vec![0],
+                denseinfo: Some(osmpbf::DenseInfo {
+                    timestamp: vec![i64::MAX / 100],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            dense: Some(osmpbf::DenseNodes {
+                id: vec![1, 1],
+                lat: vec![0, 0],
+                lon: vec![0, 0],
+                denseinfo: Some(osmpbf::DenseInfo {
+                    changeset: vec![i64::MIN, -1],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            dense: Some(osmpbf::DenseNodes {
+                id: vec![1, 1],
+                lat: vec![0, 0],
+                lon: vec![0, 0],
+                denseinfo: Some(osmpbf::DenseInfo {
+                    uid: vec![i32::MAX, 1],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            ways: vec![osmpbf::Way {
+                id: 1,
+                refs: vec![i64::MAX, 1],
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            ways: vec![osmpbf::Way {
+                id: 1,
+                refs: vec![1, 1],
+                lat: vec![i64::MAX, 1],
+                lon: vec![0, 0],
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            relations: vec![osmpbf::Relation {
+                id: 1,
+                memids: vec![i64::MIN, -1],
+                roles_sid: vec![0, 0],
+                types: vec![0, 0],
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn bad_string_indexes() {
+        assert_invalid(osmpbf::PrimitiveGroup {
+            nodes: vec![osmpbf::Node {
+                id: 1,
+                keys: vec![3],
+                vals: vec![0],
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            dense: Some(osmpbf::DenseNodes {
+                id: vec![1],
+                lat: vec![0],
+                lon: vec![0],
+                keys_vals: vec![-1, 0, 0],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            dense: Some(osmpbf::DenseNodes {
+                id: vec![1, 1],
+                lat: vec![0, 0],
+                lon: vec![0, 0],
+                denseinfo: Some(osmpbf::DenseInfo {
+                    user_sid: vec![0, -1],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            ways: vec![osmpbf::Way {
+                id: 1,
+                info: Some(osmpbf::Info {
+                    user_sid: Some(1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        assert_invalid(osmpbf::PrimitiveGroup {
+            relations: vec![osmpbf::Relation {
+                id: 1,
+                memids: vec![1],
+                roles_sid: vec![7],
+                types: vec![0],
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+    }
+
+    /// A small file with a node, a way and a relation, written by
+    /// [`PbfWriter`].
+    fn sample_pbf() -> Vec<u8> {
+        let mut osm_file = OsmFile::default();
+        let k = osm_file.string_table.insert("highway".to_string());
+        let v = osm_file.string_table.insert("path".to_string());
+        for id in 1..=3 {
+            osm_file.nodes.insert(OsmElement {
+                id,
+                tags: HashMap::from([(k, v)]),
+                info: None,
+                el: OsmNodeData {
+                    lat: 47.0 + id as f64 * 0.001,
+                    lon: 8.0,
+                },
+            });
+        }
+        osm_file.ways.insert(
+            1,
+            OsmElement {
+                id: 1,
+                tags: HashMap::from([(k, v)]),
+                info: None,
+                el: OsmWayData {
+                    refs: vec![3, 1, 2],
+                },
+            },
+        );
+        let mut data = Vec::new();
+        PbfWriter::new(&mut data).write(&osm_file, None).unwrap();
+        data
+    }
+
+    fn read_pbf(data: &[u8]) -> Result<OsmFile, PbfError> {
+        PbfReader::new(std::io::Cursor::new(data))
+            .with_metadata(true)
+            .read()
+    }
+
+    /// Offsets at which the blobs of `data` end.
+    fn blob_ends(data: &[u8]) -> Vec<usize> {
+        let mut ends = Vec::new();
+        let mut end = 0;
+        let mut reader = std::io::Cursor::new(data);
+        while let Some((_, size)) = read_raw_blob(&mut reader, 0, 0).unwrap() {
+            end += size as usize;
+            ends.push(end);
+        }
+        ends
+    }
+
+    #[test]
+    fn truncated_files() {
+        let data = sample_pbf();
+        assert_eq!(read_pbf(&data).unwrap().ways().count(), 1);
+        let ends = blob_ends(&data);
+        for len in 1..data.len() {
+            let result = read_pbf(&data[..len]);
+            if ends.contains(&len) {
+                assert!(result.is_ok(), "{}", len);
+            } else {
+                assert!(matches!(result, Err(PbfError::Truncated { .. })), "{}", len);
+            }
+        }
+    }
+
+    fn raw_blob(ty: &str, datasize: i32, blob: &[u8]) -> Vec<u8> {
+        let header = osmpbf::BlobHeader {
+            r#type: ty.to_string(),
+            indexdata: None,
+            datasize,
+        }
+        .encode_to_vec();
+        let mut data = (header.len() as u32).to_be_bytes().to_vec();
+        data.extend(header);
+        data.extend(blob);
+        data
+    }
+
+    fn assert_invalid_blob(data: &[u8]) {
+        let result = read_pbf(data);
+        assert!(
+            matches!(
+                result,
+                Err(PbfError::Blob {
+                    error: BlobError::Invalid(_),
+                    ..
+                })
+            ),
+            "{:?}",
+            result.err()
+        );
+    }
+
+    #[test]
+    fn oversized_blobs() {
+        let size = MAX_BLOB_HEADER_SIZE as u32 + 1;
+        assert_invalid_blob(&size.to_be_bytes());
+        assert_invalid_blob(&raw_blob("OSMData", MAX_BLOB_SIZE + 1, &[]));
+        assert_invalid_blob(&raw_blob("OSMData", -1, &[]));
+        let blob = osmpbf::Blob {
+            raw_size: Some(MAX_BLOB_SIZE + 1),
+            data: Some(osmpbf::blob::Data::Raw(Vec::new())),
+        }
+        .encode_to_vec();
+        assert_invalid_blob(&raw_blob("OSMData", blob.len() as i32, &blob));
+    }
+
+    #[test]
+    fn garbage() {
+        // Reading must fail or succeed, but never panic
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for len in 0..200 {
+            let data = (0..len).map(|_| random() as u8).collect::<Vec<_>>();
+            let _ = read_pbf(&data);
+        }
+        let data = sample_pbf();
+        for i in 0..data.len() {
+            let mut corrupt = data.clone();
+            corrupt[i] ^= (random() as u8).max(1);
+            let _ = read_pbf(&corrupt);
+        }
+    }
+
+    fn decompress(data: osmpbf::blob::Data, raw_size: Option<usize>) -> Result<Vec<u8>, BlobError> {
+        decompress_blob_data(osmpbf::Blob {
+            raw_size: raw_size.map(|size| size as i32),
+            data: Some(data),
+        })
+    }
+
+    /// Variant of blob data with a compression.
+    type Compression = fn(Vec<u8>) -> osmpbf::blob::Data;
+
+    /// Checks that `compressed` decompresses to `raw`, and that a wrong
+    /// `raw_size` is detected.
+    fn assert_decompresses(data: Compression, compressed: Vec<u8>, raw: &[u8]) {
+        let result = decompress(data(compressed.clone()), Some(raw.len()));
+        assert_eq!(result.unwrap(), raw);
+        for size in [raw.len() - 1, raw.len() + 1] {
+            let result = decompress(data(compressed.clone()), Some(size));
+            assert!(matches!(result, Err(BlobError::Invalid(_))), "{:?}", result);
+        }
+    }
+
+    const RAW: &[u8] = b"OpenStreetMap OpenStreetMap OpenStreetMap";
+
+    #[test]
+    fn raw_blobs() {
+        assert_decompresses(osmpbf::blob::Data::Raw, RAW.to_vec(), RAW);
+        let result = decompress(osmpbf::blob::Data::Raw(RAW.to_vec()), None);
+        assert_eq!(result.unwrap(), RAW);
+        let result = decompress_blob_data(osmpbf::Blob {
+            raw_size: None,
+            data: None,
+        });
+        assert!(matches!(result, Err(BlobError::Invalid(_))));
+    }
+
+    #[test]
+    fn zlib_blobs() {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, RAW).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_decompresses(osmpbf::blob::Data::ZlibData, compressed.clone(), RAW);
+        let result = decompress(osmpbf::blob::Data::ZlibData(compressed), None);
+        assert_eq!(result.unwrap(), RAW);
+        let result = decompress(osmpbf::blob::Data::ZlibData(RAW.to_vec()), None);
+        assert!(matches!(result, Err(BlobError::Decompress(_))));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_blobs() {
+        let compressed = zstd::encode_all(RAW, 0).unwrap();
+        assert_decompresses(osmpbf::blob::Data::ZstdData, compressed, RAW);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_blobs() {
+        let compressed = lz4_flex::block::compress(RAW);
+        assert_decompresses(osmpbf::blob::Data::Lz4Data, compressed.clone(), RAW);
+        let result = decompress(osmpbf::blob::Data::Lz4Data(compressed), None);
+        assert!(matches!(result, Err(BlobError::Invalid(_))));
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn bzip2_blobs() {
+        let mut compressed = Vec::new();
+        bzip2::read::BzEncoder::new(RAW, bzip2::Compression::default())
+            .read_to_end(&mut compressed)
+            .unwrap();
+        assert_decompresses(osmpbf::blob::Data::ObsoleteBzip2Data, compressed, RAW);
+    }
+
+    #[cfg(feature = "lzma")]
+    #[test]
+    fn lzma_blobs() {
+        let mut compressed = Vec::new();
+        xz2::read::XzEncoder::new(RAW, 6)
+            .read_to_end(&mut compressed)
+            .unwrap();
+        assert_decompresses(osmpbf::blob::Data::LzmaData, compressed, RAW);
+    }
+
+    #[test]
+    fn disabled_compressions() {
+        let disabled: &[(&str, Compression)] = &[
+            #[cfg(not(feature = "lzma"))]
+            ("lzma", osmpbf::blob::Data::LzmaData),
+            #[cfg(not(feature = "bzip2"))]
+            ("bzip2", osmpbf::blob::Data::ObsoleteBzip2Data),
+            #[cfg(not(feature = "lz4"))]
+            ("lz4", osmpbf::blob::Data::Lz4Data),
+            #[cfg(not(feature = "zstd"))]
+            ("zstd", osmpbf::blob::Data::ZstdData),
+        ];
+        for (feature, data) in disabled {
+            let result = decompress(data(RAW.to_vec()), Some(RAW.len()));
+            assert!(
+                matches!(result, Err(BlobError::UnsupportedCompression(f)) if f == *feature),
+                "{:?}",
+                result
+            );
+        }
+    }
+
+    fn header_blob(required_features: &[&str]) -> Vec<u8> {
+        let header = osmpbf::HeaderBlock {
+            required_features: required_features.iter().map(|f| f.to_string()).collect(),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let blob = osmpbf::Blob {
+            raw_size: Some(header.len() as i32),
+            data: Some(osmpbf::blob::Data::Raw(header)),
+        }
+        .encode_to_vec();
+        raw_blob("OSMHeader", blob.len() as i32, &blob)
+    }
+
+    #[test]
+    fn required_features() {
+        let data = header_blob(&["OsmSchema-V0.6", "DenseNodes", "LocationsOnWays"]);
+        assert!(read_pbf(&data).is_ok());
+        let data = header_blob(&[
+            "OsmSchema-V0.6",
+            "HistoricalInformation",
+            "Sort.Type_then_ID",
+        ]);
+        let result = read_pbf(&data);
+        assert!(
+            matches!(
+                &result,
+                Err(PbfError::Blob {
+                    error: BlobError::UnsupportedFeatures(features),
+                    ..
+                }) if features == &["HistoricalInformation", "Sort.Type_then_ID"]
+            ),
+            "{:?}",
+            result.err()
+        );
+    }
 }
@@ -0,0 +1,172 @@
+use super::xml::XmlElement;
+use super::{OsmFile, StringRemap, StringTable};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChangeAction {
+    Create,
+    Modify,
+    Delete,
+}
+
+/// Edits read from an OsmChange file, see [`super::XmlReader::read_change`].
+pub struct OsmChange {
+    pub(super) string_table: StringTable,
+    pub(super) changes: Vec<(ChangeAction, XmlElement)>,
+}
+
+impl OsmChange {
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl OsmFile {
+    /// Applies the `create`, `modify` and `delete` blocks of `change` in
+    /// file order. Created and modified elements replace the elements with
+    /// the same id, even if those were not loaded, deleting an element that
+    /// does not exist has no effect.
+    pub fn apply_change(&mut self, change: OsmChange) {
+        self.drop_indexes();
+        let mut remap = StringRemap::new(change.string_table, &mut self.string_table);
+        for (action, el) in change.changes {
+            match (action, el) {
+                (ChangeAction::Delete, XmlElement::Node(node)) => {
+                    self.nodes.remove(node.id);
+                }
+                (ChangeAction::Delete, XmlElement::Way(way)) => {
+                    self.ways.remove(&way.id);
+                }
+                (ChangeAction::Delete, XmlElement::Relation(rel)) => {
+                    self.relations.remove(&rel.id);
+                }
+                (_, XmlElement::Node(mut node)) => {
+                    remap.element(&mut node);
+                    self.nodes.insert(node);
+                }
+                (_, XmlElement::Way(mut way)) => {
+                    remap.element(&mut way);
+                    self.ways.insert(way.id, way);
+                }
+                (_, XmlElement::Relation(mut rel)) => {
+                    remap.element(&mut rel);
+                    for (_, member) in rel.el.members.iter_mut() {
+                        member.role_sid = remap.get(member.role_sid);
+                    }
+                    self.relations.insert(rel.id, rel);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{OsmRelationMemberType, XmlReader};
+    use super::*;
+
+    fn sample() -> OsmFile {
+        let xml = include_str!("../../tests/data/sample.osm");
+        XmlReader::new(xml.as_bytes()).read().unwrap()
+    }
+
+    fn apply(osm_file: &mut OsmFile, changes: &str) {
+        let xml = format!(r#"<osmChange version="0.6">{}</osmChange>"#, changes);
+        let change = XmlReader::new(xml.as_bytes()).read_change().unwrap();
+        osm_file.apply_change(change);
+    }
+
+    #[test]
+    fn create() {
+        let mut osm_file = sample();
+        apply(
+            &mut osm_file,
+            r#"<create>
+                <node id="-1" lat="47.383" lon="8.541"><tag k="shop" v="bakery"/></node>
+                <way id="-2"><nd ref="4"/><nd ref="-1"/><tag k="highway" v="footway"/></way>
+            </create>"#,
+        );
+        let node = osm_file.get_node(-1).unwrap();
+        assert_eq!(node.data().lat, 47.383);
+        assert_eq!(node.get_tag_value("shop").unwrap(), "bakery");
+        let way = osm_file.get_way(-2).unwrap();
+        assert_eq!(way.data().refs, [4, -1]);
+        assert_eq!(way.get_tag_value("highway").unwrap(), "footway");
+        assert_eq!(osm_file.parent_ways(-1), [-2]);
+    }
+
+    #[test]
+    fn modify() {
+        let mut osm_file = sample();
+        assert_eq!(osm_file.parent_ways(4), [12]);
+        apply(
+            &mut osm_file,
+            r#"<modify>
+                <node id="1" lat="47.3785" lon="8.5375"><tag k="name" v="Zürich Hauptbahnhof"/></node>
+                <way id="10"><nd ref="1"/><nd ref="4"/><tag k="highway" v="primary"/></way>
+            </modify>"#,
+        );
+        let node = osm_file.get_node(1).unwrap();
+        assert_eq!(node.data().lon, 8.5375);
+        assert_eq!(node.get_tag_value("name").unwrap(), "Zürich Hauptbahnhof");
+        assert!(node.get_tag_value("railway").is_none());
+        let way = osm_file.get_way(10).unwrap();
+        assert_eq!(way.data().refs, [1, 4]);
+        assert!(way.get_tag_value("name").is_none());
+        assert_eq!(osm_file.parent_ways(4), [10, 12]);
+        assert!(osm_file.parent_ways(2).is_empty());
+    }
+
+    #[test]
+    fn delete() {
+        let mut osm_file = sample();
+        apply(
+            &mut osm_file,
+            r#"<delete>
+                <relation id="21"/>
+                <way id="13"/>
+                <node id="9"/>
+                <node id="1000"/>
+            </delete>"#,
+        );
+        assert!(osm_file.get_relation(21).is_none());
+        assert!(osm_file.get_way(13).is_none());
+        assert!(osm_file.get_node(9).is_none());
+        assert!(osm_file.get_node(6).is_some());
+        assert!(osm_file
+            .parent_relations(OsmRelationMemberType::Way, 10)
+            .is_empty());
+    }
+
+    #[test]
+    fn delete_referenced() {
+        let mut osm_file = sample();
+        let length = osm_file.length(OsmRelationMemberType::Way, 12);
+        apply(
+            &mut osm_file,
+            r#"<delete><node id="5"/><way id="11"/></delete>"#,
+        );
+        // The references stay, but are skipped like elements that were not
+        // loaded
+        assert_eq!(osm_file.get_way(12).unwrap().data().refs, [3, 4, 5]);
+        assert!(osm_file.length(OsmRelationMemberType::Way, 12) < length);
+        let rel = osm_file.get_relation(20).unwrap();
+        assert_eq!(rel.data().members.len(), 2);
+        assert_eq!(osm_file.relation_rings(rel.data()), [vec![3, 4, 5]]);
+        assert_eq!(osm_file.area(OsmRelationMemberType::Relation, 20), 0.0);
+    }
+
+    #[test]
+    fn empty_blocks() {
+        let xml =
+            r#"<osmChange><delete/><node id="1" lat="0" lon="0"/><create></create></osmChange>"#;
+        let change = XmlReader::new(xml.as_bytes()).read_change().unwrap();
+        assert!(change.is_empty());
+        let mut osm_file = sample();
+        osm_file.apply_change(change);
+        assert!(osm_file.get_node(1).is_some());
+    }
+}
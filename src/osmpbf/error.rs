@@ -0,0 +1,98 @@
+use std::fmt;
+
+/// Error while reading a PBF file.
+#[derive(Debug)]
+pub enum PbfError {
+    /// Reading from the source failed.
+    Io(std::io::Error),
+    /// The file ends in the middle of the blob starting at `offset`.
+    Truncated { index: usize, offset: u64 },
+    /// The blob starting at `offset` is invalid.
+    Blob {
+        index: usize,
+        offset: u64,
+        error: BlobError,
+    },
+}
+
+/// Reason why a single blob could not be read.
+#[derive(Debug)]
+pub enum BlobError {
+    Decode(prost::DecodeError),
+    Decompress(std::io::Error),
+    /// The blob is compressed with a codec whose cargo feature is disabled.
+    UnsupportedCompression(&'static str),
+    /// The header requires features that the reader does not support.
+    UnsupportedFeatures(Vec<String>),
+    UnknownType(String),
+    Invalid(String),
+}
+
+impl fmt::Display for PbfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PbfError::Io(e) => write!(f, "{}", e),
+            PbfError::Truncated { index, offset } => write!(
+                f,
+                "File is truncated in blob {} (at byte {})",
+                index, offset
+            ),
+            PbfError::Blob {
+                index,
+                offset,
+                error,
+            } => write!(f, "Invalid blob {} (at byte {}): {}", index, offset, error),
+        }
+    }
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::Decode(e) => write!(f, "{}", e),
+            BlobError::Decompress(e) => write!(f, "Decompression failed: {}", e),
+            BlobError::UnsupportedCompression(feature) => {
+                write!(f, "Blob compression requires the \"{}\" feature", feature)
+            }
+            BlobError::UnsupportedFeatures(features) => write!(
+                f,
+                "File requires unsupported features: {}",
+                features.join(", ")
+            ),
+            BlobError::UnknownType(ty) => write!(f, "Unknown blob type \"{}\"", ty),
+            BlobError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for PbfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PbfError::Io(e) => Some(e),
+            PbfError::Truncated { .. } => None,
+            PbfError::Blob { error, .. } => Some(error),
+        }
+    }
+}
+
+impl std::error::Error for BlobError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlobError::Decode(e) => Some(e),
+            BlobError::Decompress(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PbfError {
+    fn from(e: std::io::Error) -> Self {
+        PbfError::Io(e)
+    }
+}
+
+impl From<prost::DecodeError> for BlobError {
+    fn from(e: prost::DecodeError) -> Self {
+        BlobError::Decode(e)
+    }
+}
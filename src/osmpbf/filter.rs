@@ -0,0 +1,298 @@
+use super::{OsmFile, OsmRelationMemberType};
+use std::collections::{HashMap, HashSet};
+
+/// Geographic bounding box in degrees.
+#[derive(Clone, Copy, Debug)]
+pub struct BBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl BBox {
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        lat >= self.min_lat && lat <= self.max_lat && lon >= self.min_lon && lon <= self.max_lon
+    }
+
+    /// Grows the box on every side by `margin` times its width or height.
+    pub fn with_margin(&self, margin: f64) -> BBox {
+        let d_lat = (self.max_lat - self.min_lat) * margin;
+        let d_lon = (self.max_lon - self.min_lon) * margin;
+        BBox {
+            min_lat: self.min_lat - d_lat,
+            min_lon: self.min_lon - d_lon,
+            max_lat: self.max_lat + d_lat,
+            max_lon: self.max_lon + d_lon,
+        }
+    }
+}
+
+/// Selects the elements that a [`super::PbfReader`] or
+/// [`super::XmlReader`] keeps in memory.
+///
+/// With a `bbox`, only nodes inside it are kept, along with the ways and
+/// relations that reference at least one of them. Ways are completed with
+/// all of their nodes, and multipolygon and boundary relations with all of
+/// their member ways, so that the geometry of anything touching the box
+/// stays intact.
+///
+/// With `tag_keys`, only elements carrying at least one of the keys are
+/// kept, plus the members of kept relations and the nodes of kept ways.
+///
+/// Nodes are expected to precede the ways referencing them, which holds for
+/// all files sorted by type and id.
+#[derive(Default)]
+pub struct LoadFilter {
+    pub bbox: Option<BBox>,
+    pub tag_keys: Option<HashSet<String>>,
+}
+
+impl LoadFilter {
+    pub(super) fn retain_nodes_in_bbox(&self, block: &mut OsmFile) {
+        if let Some(bbox) = &self.bbox {
+            block
+                .nodes
+                .retain(|node| bbox.contains(node.el.lat, node.el.lon));
+        }
+    }
+
+    /// Drops the ways and relations of `block` that reference none of the
+    /// elements kept so far.
+    pub(super) fn retain_touching(&self, osm_file: &OsmFile, block: &mut OsmFile) {
+        if self.bbox.is_none() {
+            return;
+        }
+        let has_node = |id: &i64| osm_file.nodes.contains(*id) || block.nodes.contains(*id);
+        block.ways.retain(|_, way| way.el.refs.iter().any(has_node));
+
+        let has_member = |ty: &OsmRelationMemberType, id: &i64| match ty {
+            OsmRelationMemberType::Node => has_node(id),
+            OsmRelationMemberType::Way => {
+                osm_file.ways.contains_key(id) || block.ways.contains_key(id)
+            }
+            OsmRelationMemberType::Relation => osm_file.relations.contains_key(id),
+        };
+        let touching = block
+            .relations
+            .iter()
+            .filter(|(_, rel)| {
+                rel.el
+                    .members
+                    .iter()
+                    .any(|(ty, member)| has_member(ty, &member.ref_id))
+            })
+            .map(|(id, _)| *id)
+            .collect::<HashSet<i64>>();
+        block.relations.retain(|id, _| touching.contains(id));
+    }
+
+    /// Applies the tag filter to the elements read in the first pass and
+    /// returns the ids of member ways that are needed to complete the kept
+    /// multipolygons, but have not been read.
+    pub(super) fn retain_selected(&self, osm_file: &mut OsmFile) -> HashSet<i64> {
+        if let Some(tag_keys) = &self.tag_keys {
+            let key_ids = tag_keys
+                .iter()
+                .filter_map(|k| osm_file.get_string_idx(k))
+                .collect::<HashSet<u32>>();
+            let matches = |tags: &HashMap<u32, u32>| tags.keys().any(|k| key_ids.contains(k));
+
+            // Member relations are kept as well, so that the members of every
+            // kept relation are complete
+            let mut kept = osm_file
+                .relations
+                .iter()
+                .filter(|(_, rel)| matches(&rel.tags))
+                .map(|(id, _)| *id)
+                .collect::<HashSet<i64>>();
+            let mut pending = kept.iter().copied().collect::<Vec<i64>>();
+            while let Some(id) = pending.pop() {
+                for (ty, member) in &osm_file.relations[&id].el.members {
+                    if *ty == OsmRelationMemberType::Relation
+                        && osm_file.relations.contains_key(&member.ref_id)
+                        && kept.insert(member.ref_id)
+                    {
+                        pending.push(member.ref_id);
+                    }
+                }
+            }
+            osm_file.relations.retain(|id, _| kept.contains(id));
+            let member_ways = members_of_type(osm_file, OsmRelationMemberType::Way);
+            osm_file
+                .ways
+                .retain(|id, way| matches(&way.tags) || member_ways.contains(id));
+
+            let mut referenced = members_of_type(osm_file, OsmRelationMemberType::Node);
+            for way in osm_file.ways.values() {
+                referenced.extend(way.el.refs.iter());
+            }
+            osm_file
+                .nodes
+                .retain(|node| matches(&node.tags) || referenced.contains(&node.id));
+        }
+
+        if self.bbox.is_none() {
+            return HashSet::new();
+        }
+        let type_sid = osm_file.get_string_idx("type");
+        let area_sids = ["multipolygon", "boundary"].map(|s| osm_file.get_string_idx(s));
+        let mut missing = HashSet::new();
+        for rel in osm_file.relations.values() {
+            let rel_type = type_sid.and_then(|sid| rel.tags.get(&sid));
+            if !area_sids
+                .iter()
+                .any(|sid| sid.is_some() && sid.as_ref() == rel_type)
+            {
+                continue;
+            }
+            for (ty, member) in &rel.el.members {
+                if *ty == OsmRelationMemberType::Way && !osm_file.ways.contains_key(&member.ref_id)
+                {
+                    missing.insert(member.ref_id);
+                }
+            }
+        }
+        missing
+    }
+
+    /// Applies the filter to a file that was read completely, like the
+    /// passes of [`super::PbfReader::read`] do while reading.
+    pub(super) fn apply(&self, osm_file: &mut OsmFile) {
+        // Elements outside the bbox may be needed to complete the geometry
+        let all = self
+            .bbox
+            .map(|_| (osm_file.nodes.clone(), osm_file.ways.clone()));
+        self.retain_nodes_in_bbox(osm_file);
+        self.retain_touching(&OsmFile::default(), osm_file);
+        let missing_ways = self.retain_selected(osm_file);
+        let Some((mut nodes, mut ways)) = all else {
+            return;
+        };
+        ways.retain(|id, _| missing_ways.contains(id));
+        osm_file.ways.extend(ways);
+        let missing_nodes = missing_nodes(osm_file);
+        nodes.retain(|node| missing_nodes.contains(&node.id));
+        osm_file.nodes.merge(nodes, |_| {});
+    }
+}
+
+fn members_of_type(osm_file: &OsmFile, member_type: OsmRelationMemberType) -> HashSet<i64> {
+    osm_file
+        .relations
+        .values()
+        .flat_map(|rel| rel.el.members.iter())
+        .filter(|(ty, _)| *ty == member_type)
+        .map(|(_, member)| member.ref_id)
+        .collect()
+}
+
+/// Returns the ids of all nodes referenced by a way of `osm_file`, but not
+/// contained in it.
+pub(super) fn missing_nodes(osm_file: &OsmFile) -> HashSet<i64> {
+    osm_file
+        .ways
+        .values()
+        .flat_map(|way| way.el.refs.iter())
+        .filter(|id| !osm_file.nodes.contains(**id))
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{OsmFileElement, PbfReader, PbfWriter, XmlReader};
+    use super::*;
+
+    const SAMPLE: &str = include_str!("../../tests/data/sample.osm");
+
+    fn ids<'a, T: 'a>(elements: impl Iterator<Item = OsmFileElement<'a, T>>) -> Vec<i64> {
+        let mut ids = elements.map(|el| el.id()).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    /// Reads the sample file converted to PBF with `filter`, and returns
+    /// the ids of the elements and the number of passes.
+    fn read_pbf(filter: LoadFilter) -> ([Vec<i64>; 3], usize) {
+        let osm_file = XmlReader::new(SAMPLE.as_bytes()).read().unwrap();
+        let mut data = Vec::new();
+        PbfWriter::new(&mut data).write(&osm_file, None).unwrap();
+        let mut passes = 0;
+        let osm_file = PbfReader::new(std::io::Cursor::new(data))
+            .with_filter(filter)
+            .with_progress(|progress| passes = passes.max(progress.pass))
+            .read()
+            .unwrap();
+        let ids = [
+            ids(osm_file.nodes()),
+            ids(osm_file.ways()),
+            ids(osm_file.relations()),
+        ];
+        (ids, passes)
+    }
+
+    const BBOX: BBox = BBox {
+        min_lat: 47.375,
+        min_lon: 8.535,
+        max_lat: 47.385,
+        max_lon: 8.545,
+    };
+
+    #[test]
+    fn passes() {
+        // Way 11 of multipolygon 20 is read in the second pass, its node 6
+        // and node 5 of way 12 in the third
+        let (ids, passes) = read_pbf(LoadFilter {
+            bbox: Some(BBOX),
+            tag_keys: None,
+        });
+        assert_eq!(
+            ids,
+            [vec![1, 2, 3, 4, 5, 6], vec![10, 11, 12], vec![20, 21]]
+        );
+        assert_eq!(passes, 3);
+
+        // Only the bus route and its members are selected
+        let (ids, passes) = read_pbf(LoadFilter {
+            bbox: Some(BBOX),
+            tag_keys: Some(HashSet::from(["route".to_string()])),
+        });
+        assert_eq!(ids, [vec![1, 2, 3], vec![10], vec![21]]);
+        assert_eq!(passes, 1);
+
+        let (ids, passes) = read_pbf(LoadFilter {
+            bbox: None,
+            tag_keys: Some(HashSet::from(["natural".to_string()])),
+        });
+        assert_eq!(ids, [vec![3, 4, 5, 6], vec![11, 12], vec![20]]);
+        assert_eq!(passes, 1);
+    }
+
+    #[test]
+    fn same_as_xml() {
+        let filters = || {
+            [
+                (Some(BBOX), None),
+                (Some(BBOX), Some(["highway"])),
+                (None, Some(["amenity"])),
+            ]
+            .map(|(bbox, keys)| LoadFilter {
+                bbox,
+                tag_keys: keys.map(|keys| keys.iter().map(|k| k.to_string()).collect()),
+            })
+        };
+        for (pbf_filter, xml_filter) in filters().into_iter().zip(filters()) {
+            let osm_file = XmlReader::new(SAMPLE.as_bytes())
+                .with_filter(xml_filter)
+                .read()
+                .unwrap();
+            let xml_ids = [
+                ids(osm_file.nodes()),
+                ids(osm_file.ways()),
+                ids(osm_file.relations()),
+            ];
+            assert_eq!(read_pbf(pbf_filter).0, xml_ids);
+        }
+    }
+}
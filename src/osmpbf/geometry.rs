@@ -0,0 +1,242 @@
+use super::{OsmFile, OsmNodeData, OsmRelationData, OsmRelationMemberType, OsmWayData};
+
+/// Equatorial radius of the WGS 84 ellipsoid in meters.
+const EARTH_RADIUS: f64 = 6_378_137.0;
+
+/// Joins ways that share end nodes into rings. Ways that cannot be closed
+/// are returned as open rings.
+fn assemble_rings(mut ways: Vec<&OsmWayData>) -> Vec<Vec<i64>> {
+    let mut rings = vec![];
+    while !ways.is_empty() {
+        let mut ring = ways.remove(0).refs.clone();
+        while let Some(&last) = ring.last() {
+            if ring[0] == last {
+                break;
+            }
+            let Some(idx) = ways
+                .iter()
+                .position(|way| way.refs.first() == Some(&last) || way.refs.last() == Some(&last))
+            else {
+                break;
+            };
+            // The shared node is already the last one of the ring
+            let way = ways.remove(idx);
+            if way.refs[0] == last {
+                ring.extend(&way.refs[1..]);
+            } else {
+                ring.extend(way.refs.iter().rev().skip(1));
+            }
+        }
+        rings.push(ring);
+    }
+    rings
+}
+
+fn is_closed_ring(refs: &[i64]) -> bool {
+    refs.len() > 2 && refs.first() == refs.last()
+}
+
+/// Great-circle distance between two locations in meters.
+fn distance(a: OsmNodeData, b: OsmNodeData) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (b.lon - a.lon).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().asin()
+}
+
+impl OsmFile {
+    fn locations(&self, refs: &[i64]) -> Vec<OsmNodeData> {
+        refs.iter()
+            .filter_map(|id| Some(*self.get_node(*id)?.data()))
+            .collect()
+    }
+
+    fn member_ways<'a>(
+        &'a self,
+        rel: &'a OsmRelationData,
+    ) -> impl Iterator<Item = (&'a String, &'a OsmWayData)> + 'a {
+        rel.members.iter().filter_map(|(ty, member)| {
+            if *ty != OsmRelationMemberType::Way {
+                return None;
+            }
+            let way = &self.ways.get(&member.ref_id)?.el;
+            Some((self.get_string(member.role_sid)?, way))
+        })
+    }
+
+    /// Assembles the member ways of a relation into rings of node ids.
+    pub fn relation_rings(&self, rel: &OsmRelationData) -> Vec<Vec<i64>> {
+        assemble_rings(self.member_ways(rel).map(|(_, way)| way).collect())
+    }
+
+    /// Returns the area enclosed by a ring in square meters, on a sphere.
+    fn ring_area(&self, refs: &[i64]) -> f64 {
+        let points = self.locations(refs);
+        let sum: f64 = points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .map(|(a, b)| {
+                let d_lon = (b.lon - a.lon).to_radians();
+                d_lon * (2.0 + a.lat.to_radians().sin() + b.lat.to_radians().sin())
+            })
+            .sum();
+        (sum * EARTH_RADIUS * EARTH_RADIUS / 2.0).abs()
+    }
+
+    fn path_length(&self, refs: &[i64]) -> f64 {
+        self.locations(refs)
+            .windows(2)
+            .map(|pair| distance(pair[0], pair[1]))
+            .sum()
+    }
+
+    /// Returns whether the element is a closed way, or a relation whose
+    /// member ways form only closed rings.
+    pub fn is_closed(&self, ty: OsmRelationMemberType, id: i64) -> bool {
+        match ty {
+            OsmRelationMemberType::Node => false,
+            OsmRelationMemberType::Way => self
+                .ways
+                .get(&id)
+                .is_some_and(|way| is_closed_ring(&way.el.refs)),
+            OsmRelationMemberType::Relation => self.relations.get(&id).is_some_and(|rel| {
+                let rings = self.relation_rings(&rel.el);
+                !rings.is_empty() && rings.iter().all(|ring| is_closed_ring(ring))
+            }),
+        }
+    }
+
+    /// Returns the area of a closed way or multipolygon in square meters.
+    /// The rings of members with the role `inner` are subtracted, open
+    /// rings are ignored.
+    pub fn area(&self, ty: OsmRelationMemberType, id: i64) -> f64 {
+        match ty {
+            OsmRelationMemberType::Node => 0.0,
+            OsmRelationMemberType::Way => match self.ways.get(&id) {
+                Some(way) if is_closed_ring(&way.el.refs) => self.ring_area(&way.el.refs),
+                _ => 0.0,
+            },
+            OsmRelationMemberType::Relation => {
+                let Some(rel) = self.relations.get(&id) else {
+                    return 0.0;
+                };
+                let (inner, outer): (Vec<_>, Vec<_>) = self
+                    .member_ways(&rel.el)
+                    .partition(|(role, _)| *role == "inner");
+                let area = |ways: Vec<(&String, &OsmWayData)>| -> f64 {
+                    assemble_rings(ways.into_iter().map(|(_, way)| way).collect())
+                        .iter()
+                        .filter(|ring| is_closed_ring(ring))
+                        .map(|ring| self.ring_area(ring))
+                        .sum()
+                };
+                (area(outer) - area(inner)).max(0.0)
+            }
+        }
+    }
+
+    /// Returns the length of a way, or the total length of the member ways
+    /// of a relation, in meters.
+    pub fn length(&self, ty: OsmRelationMemberType, id: i64) -> f64 {
+        match ty {
+            OsmRelationMemberType::Node => 0.0,
+            OsmRelationMemberType::Way => self
+                .ways
+                .get(&id)
+                .map_or(0.0, |way| self.path_length(&way.el.refs)),
+            OsmRelationMemberType::Relation => self.relations.get(&id).map_or(0.0, |rel| {
+                self.member_ways(&rel.el)
+                    .map(|(_, way)| self.path_length(&way.refs))
+                    .sum()
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::XmlReader;
+    use super::*;
+
+    use OsmRelationMemberType::{Node, Relation, Way};
+
+    /// A square of 0.01° around the equator, split into three ways, with a
+    /// square hole of 0.006° in it.
+    const XML: &str = r#"<osm version="0.6">
+        <node id="1" lat="0.0" lon="0.0"/>
+        <node id="2" lat="0.0" lon="0.01"/>
+        <node id="3" lat="0.01" lon="0.01"/>
+        <node id="4" lat="0.01" lon="0.0"/>
+        <node id="5" lat="0.002" lon="0.002"/>
+        <node id="6" lat="0.002" lon="0.008"/>
+        <node id="7" lat="0.008" lon="0.008"/>
+        <node id="8" lat="0.008" lon="0.002"/>
+        <way id="10"><nd ref="1"/><nd ref="2"/></way>
+        <way id="11"><nd ref="4"/><nd ref="3"/><nd ref="2"/></way>
+        <way id="12"><nd ref="4"/><nd ref="1"/></way>
+        <way id="13"><nd ref="5"/><nd ref="6"/><nd ref="7"/><nd ref="8"/><nd ref="5"/></way>
+        <way id="14"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+        <relation id="20">
+            <member type="way" ref="10" role="outer"/>
+            <member type="way" ref="12" role="outer"/>
+            <member type="way" ref="11" role="outer"/>
+            <member type="way" ref="13" role="inner"/>
+        </relation>
+        <relation id="21">
+            <member type="way" ref="10" role="outer"/>
+            <member type="way" ref="11" role="outer"/>
+        </relation>
+    </osm>"#;
+
+    /// Area of a square of 1° at the equator in square meters.
+    const SQUARE_DEGREE: f64 = (EARTH_RADIUS * std::f64::consts::PI / 180.0)
+        * (EARTH_RADIUS * std::f64::consts::PI / 180.0);
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < expected * 1e-3,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn rings() {
+        let osm_file = XmlReader::new(XML.as_bytes()).read().unwrap();
+        let rel = |id| &osm_file.relations.get(&id).unwrap().el;
+        assert_eq!(
+            osm_file.relation_rings(rel(20)),
+            [vec![1, 2, 3, 4, 1], vec![5, 6, 7, 8, 5]]
+        );
+        assert_eq!(osm_file.relation_rings(rel(21)), [vec![1, 2, 3, 4]]);
+
+        assert!(osm_file.is_closed(Way, 13));
+        assert!(!osm_file.is_closed(Way, 10));
+        assert!(osm_file.is_closed(Relation, 20));
+        assert!(!osm_file.is_closed(Relation, 21));
+        assert!(!osm_file.is_closed(Node, 1));
+    }
+
+    #[test]
+    fn area() {
+        let osm_file = XmlReader::new(XML.as_bytes()).read().unwrap();
+        assert_close(osm_file.area(Way, 14), 1e-4 * SQUARE_DEGREE);
+        assert_close(osm_file.area(Way, 13), 0.36e-4 * SQUARE_DEGREE);
+        assert_close(osm_file.area(Relation, 20), 0.64e-4 * SQUARE_DEGREE);
+        assert_eq!(osm_file.area(Way, 11), 0.0);
+        assert_eq!(osm_file.area(Relation, 21), 0.0);
+        assert_eq!(osm_file.area(Node, 1), 0.0);
+    }
+
+    #[test]
+    fn length() {
+        let osm_file = XmlReader::new(XML.as_bytes()).read().unwrap();
+        let side = 0.01 * EARTH_RADIUS * std::f64::consts::PI / 180.0;
+        assert_close(osm_file.length(Way, 10), side);
+        assert_close(osm_file.length(Way, 11), 2.0 * side);
+        assert_close(osm_file.length(Relation, 21), 3.0 * side);
+        assert_eq!(osm_file.length(Node, 1), 0.0);
+    }
+}
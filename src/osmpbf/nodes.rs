@@ -0,0 +1,497 @@
+use super::{BBox, IDMap, OsmElement, OsmNodeData};
+use std::collections::{BTreeMap, HashMap};
+
+/// Node location in multiples of 100 nanodegrees, the precision of the OSM
+/// database and the default granularity of PBF files.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct PackedLocation {
+    lat: i32,
+    lon: i32,
+}
+
+/// Marks an entry of the sorted arrays that was removed. Such entries are
+/// dropped the next time the arrays are rebuilt.
+const REMOVED: PackedLocation = PackedLocation {
+    lat: i32::MIN,
+    lon: i32::MIN,
+};
+
+/// Every `INDEX_STEP`th id of the sorted arrays is copied into a small
+/// index, so that lookups only need to search a short range of the large
+/// arrays.
+const INDEX_STEP: usize = 128;
+
+/// Nodes inserted out of order are merged into the sorted arrays once there
+/// are more than this many, or more than an eighth of the sorted ones.
+const MIN_UNSORTED: usize = 4096;
+
+impl PackedLocation {
+    fn new(data: &OsmNodeData) -> Self {
+        PackedLocation {
+            lat: (data.lat * 1e7).round() as i32,
+            lon: (data.lon * 1e7).round() as i32,
+        }
+    }
+
+    fn data(self) -> OsmNodeData {
+        // Same computation as for nodes read with the default granularity
+        OsmNodeData {
+            lat: (self.lat as i64 * 100) as f64 * 1e-9,
+            lon: (self.lon as i64 * 100) as f64 * 1e-9,
+        }
+    }
+}
+
+/// Reference to an element of an [`super::OsmFile`]. Untagged nodes are
+/// not stored as elements, so they are created on demand.
+pub enum ElementRef<'a, T> {
+    Borrowed(&'a OsmElement<T>),
+    Owned(OsmElement<T>),
+}
+
+impl<T> std::ops::Deref for ElementRef<'_, T> {
+    type Target = OsmElement<T>;
+
+    fn deref(&self) -> &OsmElement<T> {
+        match self {
+            ElementRef::Borrowed(el) => el,
+            ElementRef::Owned(el) => el,
+        }
+    }
+}
+
+/// The nodes of an [`super::OsmFile`].
+///
+/// Most nodes only carry the location of a way vertex. Nodes without tags
+/// and metadata are therefore stored as packed locations in arrays sorted
+/// by id, which takes 16 bytes per node, while all other nodes are kept as
+/// full elements. Locations of untagged nodes are rounded to 100
+/// nanodegrees.
+///
+/// Files sorted by id are appended to the arrays directly. Nodes arriving
+/// out of order are collected in a B-tree first and merged into the arrays
+/// in bulk.
+#[derive(Clone, Default)]
+pub struct NodeStore {
+    ids: Vec<i64>,
+    index: Vec<i64>,
+    locations: Vec<PackedLocation>,
+    /// Number of `REMOVED` entries in `locations`
+    removed: usize,
+    unsorted: BTreeMap<i64, PackedLocation>,
+    tagged: IDMap<OsmElement<OsmNodeData>>,
+}
+
+fn is_packable(node: &OsmElement<OsmNodeData>) -> bool {
+    node.tags.is_empty() && node.info.is_none()
+}
+
+fn unpack(id: i64, location: PackedLocation) -> OsmElement<OsmNodeData> {
+    OsmElement {
+        id,
+        tags: HashMap::new(),
+        info: None,
+        el: location.data(),
+    }
+}
+
+impl NodeStore {
+    pub fn reserve(&mut self, additional: usize) {
+        self.ids.reserve(additional);
+        self.locations.reserve(additional);
+    }
+
+    /// Returns the position of `id` in the sorted arrays, including
+    /// removed entries.
+    fn find(&self, id: i64) -> Option<usize> {
+        let start = self
+            .index
+            .partition_point(|first| *first <= id)
+            .checked_sub(1)?;
+        let start = start * INDEX_STEP;
+        let end = self.ids.len().min(start + INDEX_STEP);
+        Some(start + self.ids[start..end].binary_search(&id).ok()?)
+    }
+
+    fn sorted_index(&self, id: i64) -> Option<usize> {
+        self.find(id).filter(|idx| self.locations[*idx] != REMOVED)
+    }
+
+    fn push_sorted(&mut self, id: i64, location: PackedLocation) {
+        if self.ids.len().is_multiple_of(INDEX_STEP) {
+            self.index.push(id);
+        }
+        self.ids.push(id);
+        self.locations.push(location);
+    }
+
+    fn location(&self, id: i64) -> Option<PackedLocation> {
+        match self.sorted_index(id) {
+            Some(idx) => Some(self.locations[idx]),
+            None => self.unsorted.get(&id).copied(),
+        }
+    }
+
+    pub fn contains(&self, id: i64) -> bool {
+        self.tagged.contains_key(&id) || self.location(id).is_some()
+    }
+
+    pub fn get(&self, id: i64) -> Option<ElementRef<'_, OsmNodeData>> {
+        match self.location(id) {
+            Some(loc) => Some(ElementRef::Owned(unpack(id, loc))),
+            None => self.tagged.get(&id).map(ElementRef::Borrowed),
+        }
+    }
+
+    /// Iterates over all nodes in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = ElementRef<'_, OsmNodeData>> {
+        let sorted = self
+            .ids
+            .iter()
+            .zip(&self.locations)
+            .filter(|(_, loc)| **loc != REMOVED)
+            .map(|(id, loc)| (*id, *loc));
+        let unsorted = self.unsorted.iter().map(|(id, loc)| (*id, *loc));
+        self.tagged.values().map(ElementRef::Borrowed).chain(
+            sorted
+                .chain(unsorted)
+                .map(|(id, loc)| ElementRef::Owned(unpack(id, loc))),
+        )
+    }
+
+    /// Inserts `node`, replacing the node with the same id.
+    pub fn insert(&mut self, node: OsmElement<OsmNodeData>) {
+        if is_packable(&node) {
+            self.tagged.remove(&node.id);
+            self.insert_location(node.id, PackedLocation::new(&node.el));
+        } else {
+            self.remove_location(node.id);
+            self.tagged.insert(node.id, node);
+        }
+    }
+
+    pub fn remove(&mut self, id: i64) {
+        self.tagged.remove(&id);
+        self.remove_location(id);
+    }
+
+    fn insert_location(&mut self, id: i64, location: PackedLocation) {
+        if self.ids.last().is_none_or(|last| id > *last) {
+            self.unsorted.remove(&id);
+            self.push_sorted(id, location);
+            return;
+        }
+        if let Some(idx) = self.find(id) {
+            if self.locations[idx] == REMOVED {
+                self.removed -= 1;
+            }
+            self.locations[idx] = location;
+            return;
+        }
+        self.unsorted.insert(id, location);
+        if self.unsorted.len() > MIN_UNSORTED.max(self.ids.len() / 8) {
+            self.rebuild();
+        }
+    }
+
+    fn remove_location(&mut self, id: i64) {
+        self.unsorted.remove(&id);
+        if let Some(idx) = self.sorted_index(id) {
+            self.locations[idx] = REMOVED;
+            self.removed += 1;
+        }
+    }
+
+    /// Merges the unsorted nodes into the sorted arrays and drops removed
+    /// entries.
+    fn rebuild(&mut self) {
+        let len = self.packed_len();
+        let ids = std::mem::replace(&mut self.ids, Vec::with_capacity(len));
+        let locations = std::mem::replace(&mut self.locations, Vec::with_capacity(len));
+        let mut unsorted = std::mem::take(&mut self.unsorted).into_iter().peekable();
+        self.index.clear();
+        self.removed = 0;
+        for (id, loc) in ids.into_iter().zip(locations) {
+            while let Some((next_id, next_loc)) = unsorted.next_if(|(next_id, _)| *next_id < id) {
+                self.push_sorted(next_id, next_loc);
+            }
+            if loc != REMOVED {
+                self.push_sorted(id, loc);
+            }
+        }
+        for (id, loc) in unsorted {
+            self.push_sorted(id, loc);
+        }
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&OsmElement<OsmNodeData>) -> bool) {
+        self.tagged.retain(|_, node| f(node));
+        self.unsorted.retain(|id, loc| f(&unpack(*id, *loc)));
+        for (id, loc) in self.ids.iter().zip(self.locations.iter_mut()) {
+            if *loc != REMOVED && !f(&unpack(*id, *loc)) {
+                *loc = REMOVED;
+                self.removed += 1;
+            }
+        }
+        if self.removed > self.ids.len() / 8 {
+            self.rebuild();
+        }
+    }
+
+    pub(super) fn packed_len(&self) -> usize {
+        self.ids.len() - self.removed + self.unsorted.len()
+    }
+
+    /// Returns the untagged nodes with their location in units of 100
+    /// nanodegrees, not necessarily sorted.
+    pub(super) fn packed(&self) -> impl Iterator<Item = (i64, i32, i32)> + '_ {
+        let sorted = self
+            .ids
+            .iter()
+            .zip(&self.locations)
+            .filter(|(_, loc)| **loc != REMOVED);
+        sorted
+            .chain(&self.unsorted)
+            .map(|(id, loc)| (*id, loc.lat, loc.lon))
+    }
+
+    pub(super) fn insert_packed(&mut self, id: i64, lat: i32, lon: i32) {
+        self.tagged.remove(&id);
+        self.insert_location(id, PackedLocation { lat, lon });
+    }
+
+    /// Returns the ids of the untagged nodes inside `bbox`.
+    pub(super) fn untagged_in(&self, bbox: BBox) -> impl Iterator<Item = i64> + '_ {
+        let sorted = self.ids.iter().zip(&self.locations);
+        sorted
+            .chain(&self.unsorted)
+            .filter(move |(_, loc)| {
+                let data = loc.data();
+                **loc != REMOVED && bbox.contains(data.lat, data.lon)
+            })
+            .map(|(id, _)| *id)
+    }
+
+    /// Returns the nodes with tags or metadata.
+    pub(super) fn tagged(&self) -> impl ExactSizeIterator<Item = &OsmElement<OsmNodeData>> {
+        self.tagged.values()
+    }
+
+    pub fn clear(&mut self) {
+        *self = NodeStore::default();
+    }
+
+    /// Moves the nodes of `other` into this store, calling `prepare` on
+    /// every node with tags or metadata first. Untagged nodes of `other`
+    /// do not replace existing nodes, as they may be bare locations taken
+    /// from ways.
+    pub(super) fn merge(
+        &mut self,
+        mut other: NodeStore,
+        mut prepare: impl FnMut(&mut OsmElement<OsmNodeData>),
+    ) {
+        self.tagged.reserve(other.tagged.len());
+        for (id, mut node) in other.tagged.drain() {
+            prepare(&mut node);
+            self.remove_location(id);
+            self.tagged.insert(id, node);
+        }
+        if !other.unsorted.is_empty() || other.removed > 0 {
+            other.rebuild();
+        }
+        self.reserve(other.ids.len());
+        for (id, loc) in other.ids.into_iter().zip(other.locations) {
+            let exists = if self.ids.last().is_none_or(|last| id > *last) {
+                self.tagged.contains_key(&id) || self.unsorted.contains_key(&id)
+            } else {
+                self.contains(id)
+            };
+            if !exists {
+                self.insert_location(id, loc);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: i64, tagged: bool) -> OsmElement<OsmNodeData> {
+        OsmElement {
+            id,
+            tags: if tagged {
+                HashMap::from([(1, 2)])
+            } else {
+                HashMap::new()
+            },
+            info: None,
+            el: OsmNodeData {
+                lat: id as f64 * 1.234_567_8e-5 - 45.0,
+                lon: 179.999_999_9 - id as f64 * 3.3e-6,
+            },
+        }
+    }
+
+    /// Checks that the store holds exactly the nodes `ids`, with the
+    /// locations given by [`node`], and that its arrays are consistent.
+    fn assert_nodes(store: &NodeStore, ids: &[i64]) {
+        for id in ids {
+            let stored = store.get(*id).unwrap();
+            let expected = node(*id, false);
+            assert_eq!(stored.id, *id);
+            assert!((stored.el.lat - expected.el.lat).abs() < 1e-7, "{}", id);
+            assert!((stored.el.lon - expected.el.lon).abs() < 1e-7, "{}", id);
+        }
+        let mut stored = store.iter().map(|node| node.id).collect::<Vec<_>>();
+        stored.sort();
+        let mut expected = ids.to_vec();
+        expected.sort();
+        assert_eq!(stored, expected);
+
+        assert!(store.ids.is_sorted());
+        assert_eq!(store.ids.len(), store.locations.len());
+        let index = store
+            .ids
+            .iter()
+            .step_by(INDEX_STEP)
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(store.index, index);
+        let removed = store
+            .locations
+            .iter()
+            .filter(|loc| **loc == REMOVED)
+            .count();
+        assert_eq!(store.removed, removed);
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let mut store = NodeStore::default();
+        let ids = (0..1000).map(|i| i * 3 - 500).collect::<Vec<_>>();
+        for id in &ids {
+            store.insert(node(*id, *id % 7 == 0));
+        }
+        assert_eq!(
+            store.tagged().len(),
+            ids.iter().filter(|id| *id % 7 == 0).count()
+        );
+        assert_eq!(store.packed_len() + store.tagged().len(), ids.len());
+        assert!(store.unsorted.is_empty());
+        assert_nodes(&store, &ids);
+        assert!(!store.get(-497).unwrap().tags.is_empty());
+
+        // Ids before, between and after the stored ones
+        for id in [-501, -499, 0, 2, 2495, 2498, i64::MIN, i64::MAX] {
+            assert!(store.get(id).is_none(), "{}", id);
+            assert!(!store.contains(id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn replace() {
+        let mut store = NodeStore::default();
+        for id in 0..10 {
+            store.insert(node(id, false));
+        }
+        store.insert(node(5, true));
+        assert!(!store.get(5).unwrap().tags.is_empty());
+        assert_eq!(store.packed_len(), 9);
+        store.insert(node(5, false));
+        assert!(store.get(5).unwrap().tags.is_empty());
+        assert_eq!(store.tagged().len(), 0);
+        assert_nodes(&store, &(0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn remove() {
+        let mut store = NodeStore::default();
+        for id in 0..300 {
+            store.insert(node(id, id == 7));
+        }
+        for id in [0, 7, 128, 299, 1000] {
+            store.remove(id);
+        }
+        // Removed entries stay in the arrays until they are rebuilt
+        assert_eq!(store.ids.len(), 299);
+        assert_eq!(store.removed, 3);
+        assert!(store.locations[store.find(128).unwrap()] == REMOVED);
+        let ids = (1..299)
+            .filter(|id| ![7, 128].contains(id))
+            .collect::<Vec<_>>();
+        assert_nodes(&store, &ids);
+        assert!(store.get(128).is_none());
+
+        // Inserting a removed node reuses its entry
+        store.insert(node(128, false));
+        assert_eq!(store.removed, 2);
+        assert!(store.get(128).is_some());
+
+        // More than an eighth removed rebuilds the arrays
+        store.retain(|node| node.id % 4 != 1);
+        assert_eq!(store.removed, 0);
+        let ids = (1..299)
+            .filter(|id| *id != 7 && id % 4 != 1)
+            .collect::<Vec<_>>();
+        assert_eq!(store.ids, ids);
+        assert_nodes(&store, &ids);
+    }
+
+    #[test]
+    fn rebuild() {
+        let mut store = NodeStore::default();
+        let sorted = (0..5000).map(|i| i * 10).collect::<Vec<_>>();
+        for id in &sorted {
+            store.insert(node(*id, false));
+        }
+        // Out of order nodes are kept aside until there are enough of them
+        let unsorted = (0..MIN_UNSORTED as i64)
+            .rev()
+            .map(|i| i * 10 + 5)
+            .collect::<Vec<_>>();
+        for id in &unsorted {
+            store.insert(node(*id, false));
+        }
+        assert_eq!(store.unsorted.len(), MIN_UNSORTED);
+        let mut ids = sorted.iter().chain(&unsorted).copied().collect::<Vec<_>>();
+        assert_nodes(&store, &ids);
+        store.remove(10);
+        store.insert(node(3, false));
+        assert!(store.unsorted.is_empty());
+        ids.retain(|id| *id != 10);
+        ids.push(3);
+        ids.sort();
+        assert_eq!(store.ids, ids);
+        assert_nodes(&store, &ids);
+    }
+
+    #[test]
+    fn merge() {
+        let mut store = NodeStore::default();
+        let mut other = NodeStore::default();
+        for id in 0..1000 {
+            if id % 3 == 0 {
+                store.insert(node(id, false));
+            } else {
+                other.insert(node(id, id % 3 == 1 && id % 2 == 0));
+            }
+        }
+        // Nodes of `other` in the way of existing ones
+        let mut moved = node(300, false);
+        moved.el.lat = 0.0;
+        other.insert(moved);
+        other.insert(node(333, true));
+        let mut prepared = 0;
+        store.merge(other, |_| prepared += 1);
+        assert_eq!(prepared, 167);
+        assert!(!store.get(333).unwrap().tags.is_empty());
+        assert_nodes(&store, &(0..1000).collect::<Vec<_>>());
+        store.rebuild();
+        let untagged = (0..1000)
+            .filter(|id| *id != 333 && !(id % 3 == 1 && id % 2 == 0))
+            .collect::<Vec<_>>();
+        assert_eq!(store.ids, untagged);
+        assert_nodes(&store, &(0..1000).collect::<Vec<_>>());
+    }
+}
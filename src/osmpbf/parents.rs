@@ -0,0 +1,74 @@
+use super::{OsmFile, OsmRelationMemberType};
+use std::collections::HashMap;
+
+/// Membership of an element in a relation.
+pub struct ParentRelation {
+    pub id: i64,
+    /// Role of the element in the relation
+    pub role_sid: u32,
+}
+
+/// Upward references of all elements of an [`OsmFile`].
+#[derive(Default)]
+pub(super) struct ParentIndex {
+    node_ways: HashMap<i64, Vec<i64>>,
+    member_relations: HashMap<(OsmRelationMemberType, i64), Vec<ParentRelation>>,
+}
+
+impl ParentIndex {
+    fn new(osm_file: &OsmFile) -> Self {
+        let mut index = ParentIndex::default();
+        for way in osm_file.ways.values() {
+            for node_id in &way.el.refs {
+                index.node_ways.entry(*node_id).or_default().push(way.id);
+            }
+        }
+        for rel in osm_file.relations.values() {
+            for (ty, member) in &rel.el.members {
+                index
+                    .member_relations
+                    .entry((*ty, member.ref_id))
+                    .or_default()
+                    .push(ParentRelation {
+                        id: rel.id,
+                        role_sid: member.role_sid,
+                    });
+            }
+        }
+        // Sorted, so that the parents are visited in the same order on
+        // every run
+        for ways in index.node_ways.values_mut() {
+            ways.sort_unstable();
+            ways.dedup();
+        }
+        for relations in index.member_relations.values_mut() {
+            relations.sort_by_key(|parent| parent.id);
+        }
+        index
+    }
+}
+
+impl OsmFile {
+    /// The index is built on first use and dropped when elements are added
+    /// or changed.
+    fn parent_index(&self) -> &ParentIndex {
+        self.parents.get_or_init(|| ParentIndex::new(self))
+    }
+
+    /// Returns the ids of the ways that contain the node.
+    pub fn parent_ways(&self, node_id: i64) -> &[i64] {
+        self.parent_index()
+            .node_ways
+            .get(&node_id)
+            .map_or(&[], |ways| ways.as_slice())
+    }
+
+    /// Returns the relations that have the element as a member. A relation
+    /// is returned once for every time it contains the element.
+    pub fn parent_relations(&self, ty: OsmRelationMemberType, id: i64) -> &[ParentRelation] {
+        self.parent_index()
+            .member_relations
+            .get(&(ty, id))
+            .map_or(&[], |relations| relations.as_slice())
+    }
+}
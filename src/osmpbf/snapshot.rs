@@ -0,0 +1,676 @@
+use super::{
+    BBox, LoadFilter, OsmElement, OsmElementInfo, OsmFile, OsmNodeData, OsmRelationData,
+    OsmRelationMemberInfo, OsmRelationMemberType, OsmWayData,
+};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Start of every snapshot. The number is increased whenever the layout
+/// changes.
+const MAGIC: &[u8; 8] = b"CHZSNAP1";
+
+/// Number of bytes at the start and at the end of the source file that are
+/// hashed for the [`SnapshotKey`].
+const HASHED_BYTES: u64 = 64 * 1024;
+
+/// 64-bit FNV-1a hash. Unlike the hashers of the standard library, its
+/// result does not change between Rust versions, so snapshots stay valid.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Identifies the data stored in a snapshot: the source file and the
+/// options it was loaded with.
+#[derive(PartialEq, Eq)]
+pub struct SnapshotKey {
+    size: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    mtime: u64,
+    hash: u64,
+}
+
+impl SnapshotKey {
+    /// Computes the key of `source` when loaded with `filter` and
+    /// `with_metadata`.
+    ///
+    /// Besides the size and modification time of the file, its first and
+    /// last 64 KiB are hashed. This detects replaced files even if they have
+    /// the same size and time, without reading all of the file.
+    pub fn new(source: &Path, filter: &LoadFilter, with_metadata: bool) -> io::Result<Self> {
+        let mut file = File::open(source)?;
+        let metadata = file.metadata()?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+
+        let mut hasher = Fnv::new();
+        let mut buf = Vec::new();
+        (&mut file).take(HASHED_BYTES).read_to_end(&mut buf)?;
+        if metadata.len() > HASHED_BYTES {
+            file.seek(SeekFrom::Start(
+                metadata
+                    .len()
+                    .saturating_sub(HASHED_BYTES)
+                    .max(HASHED_BYTES),
+            ))?;
+            file.read_to_end(&mut buf)?;
+        }
+        hasher.write(&buf);
+
+        // Every value is preceded by a byte that marks whether it is set,
+        // and strings by their length, so that different options never
+        // hash the same bytes
+        hasher.write(&[filter.bbox.is_some() as u8]);
+        if let Some(bbox) = &filter.bbox {
+            for v in [bbox.min_lat, bbox.min_lon, bbox.max_lat, bbox.max_lon] {
+                hasher.write(&v.to_le_bytes());
+            }
+        }
+        hasher.write(&[filter.tag_keys.is_some() as u8]);
+        if let Some(tag_keys) = &filter.tag_keys {
+            let mut keys = tag_keys.iter().collect::<Vec<_>>();
+            keys.sort_unstable();
+            for key in keys {
+                hasher.write(&(key.len() as u64).to_le_bytes());
+                hasher.write(key.as_bytes());
+            }
+        }
+        hasher.write(&[with_metadata as u8]);
+
+        Ok(SnapshotKey {
+            size: metadata.len(),
+            mtime,
+            hash: hasher.0,
+        })
+    }
+}
+
+struct Encoder<W> {
+    writer: W,
+}
+
+impl<W: Write> Encoder<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)
+    }
+
+    fn u8(&mut self, v: u8) -> io::Result<()> {
+        self.bytes(&[v])
+    }
+
+    fn u32(&mut self, v: u32) -> io::Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn i32(&mut self, v: i32) -> io::Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u64(&mut self, v: u64) -> io::Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn i64(&mut self, v: i64) -> io::Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn f64(&mut self, v: f64) -> io::Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn len(&mut self, len: usize) -> io::Result<()> {
+        self.u64(len as u64)
+    }
+
+    fn string(&mut self, s: &str) -> io::Result<()> {
+        self.len(s.len())?;
+        self.bytes(s.as_bytes())
+    }
+
+    fn strings(&mut self, strings: &[String]) -> io::Result<()> {
+        self.len(strings.len())?;
+        strings.iter().try_for_each(|s| self.string(s))
+    }
+
+    fn option<T>(
+        &mut self,
+        value: Option<T>,
+        f: impl FnOnce(&mut Self, T) -> io::Result<()>,
+    ) -> io::Result<()> {
+        match value {
+            Some(value) => {
+                self.u8(1)?;
+                f(self, value)
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn element<T>(&mut self, el: &OsmElement<T>) -> io::Result<()> {
+        self.i64(el.id)?;
+        self.len(el.tags.len())?;
+        for (k, v) in &el.tags {
+            self.u32(*k)?;
+            self.u32(*v)?;
+        }
+        self.option(el.info.as_deref(), |enc, info| {
+            enc.i32(info.version)?;
+            enc.option(info.timestamp, Self::i64)?;
+            enc.option(info.changeset, Self::i64)?;
+            enc.option(info.uid, Self::i32)?;
+            enc.option(info.user_sid, Self::u32)
+        })
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(invalid("Snapshot is truncated"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    /// Reads a length, which cannot exceed the remaining data as every
+    /// counted item takes at least a byte.
+    fn len(&mut self) -> io::Result<usize> {
+        let len = self.u64()?;
+        if len > self.data.len() as u64 {
+            return Err(invalid("Snapshot is truncated"));
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.len()?;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| invalid("Invalid string"))
+    }
+
+    fn strings(&mut self) -> io::Result<Vec<String>> {
+        (0..self.len()?).map(|_| self.string()).collect()
+    }
+
+    fn option<T>(&mut self, f: impl FnOnce(&mut Self) -> io::Result<T>) -> io::Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => f(self).map(Some),
+            _ => Err(invalid("Invalid option")),
+        }
+    }
+
+    fn element<T>(
+        &mut self,
+        data: impl FnOnce(&mut Self) -> io::Result<T>,
+    ) -> io::Result<OsmElement<T>> {
+        let id = self.i64()?;
+        let tag_count = self.len()?;
+        let mut tags = HashMap::with_capacity(tag_count);
+        for _ in 0..tag_count {
+            tags.insert(self.u32()?, self.u32()?);
+        }
+        let info = self.option(|dec| {
+            Ok(Box::new(OsmElementInfo {
+                version: dec.i32()?,
+                timestamp: dec.option(Self::i64)?,
+                changeset: dec.option(Self::i64)?,
+                uid: dec.option(Self::i32)?,
+                user_sid: dec.option(Self::u32)?,
+            }))
+        })?;
+        Ok(OsmElement {
+            id,
+            tags,
+            info,
+            el: data(self)?,
+        })
+    }
+}
+
+/// Checks that a string id read from a snapshot exists in its string
+/// table, as an invalid id would only fail later on lookup.
+fn check_sid(sid: u32, string_count: u32) -> io::Result<u32> {
+    if sid < string_count {
+        Ok(sid)
+    } else {
+        Err(invalid("Invalid string id"))
+    }
+}
+
+fn check_strings<T>(el: &OsmElement<T>, string_count: u32) -> io::Result<()> {
+    for (k, v) in &el.tags {
+        check_sid(*k, string_count)?;
+        check_sid(*v, string_count)?;
+    }
+    if let Some(sid) = el.info.as_ref().and_then(|info| info.user_sid) {
+        check_sid(sid, string_count)?;
+    }
+    Ok(())
+}
+
+fn member_type_id(ty: &OsmRelationMemberType) -> u8 {
+    match ty {
+        OsmRelationMemberType::Node => 0,
+        OsmRelationMemberType::Way => 1,
+        OsmRelationMemberType::Relation => 2,
+    }
+}
+
+impl OsmFile {
+    /// Writes the file to `path` in a binary format that is much faster to
+    /// read than PBF, see [`OsmFile::read_snapshot`].
+    pub fn write_snapshot(&self, path: &Path, key: &SnapshotKey) -> io::Result<()> {
+        // Replace the snapshot only once it is complete, which also keeps
+        // memory maps of the old one intact
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".partial");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut enc = Encoder {
+            writer: BufWriter::new(File::create(&tmp_path)?),
+        };
+        enc.bytes(MAGIC)?;
+        enc.u64(key.size)?;
+        enc.u64(key.mtime)?;
+        enc.u64(key.hash)?;
+
+        let header = &self.header;
+        enc.option(header.bbox, |enc, bbox| {
+            [bbox.min_lat, bbox.min_lon, bbox.max_lat, bbox.max_lon]
+                .into_iter()
+                .try_for_each(|v| enc.f64(v))
+        })?;
+        enc.strings(&header.required_features)?;
+        enc.strings(&header.optional_features)?;
+        enc.option(header.writing_program.as_deref(), Encoder::string)?;
+        enc.option(header.source.as_deref(), Encoder::string)?;
+        enc.option(header.replication_timestamp, Encoder::i64)?;
+        enc.option(header.replication_sequence_number, Encoder::i64)?;
+        enc.option(header.replication_base_url.as_deref(), Encoder::string)?;
+
+        // String ids are assigned consecutively
+        enc.len(self.string_table.curr_id as usize)?;
+        for id in 0..self.string_table.curr_id {
+            enc.string(self.string_table.get(id).map_or("", |s| s.as_str()))?;
+        }
+
+        enc.len(self.nodes.packed_len())?;
+        for (id, lat, lon) in self.nodes.packed() {
+            enc.i64(id)?;
+            enc.i32(lat)?;
+            enc.i32(lon)?;
+        }
+        let tagged = self.nodes.tagged();
+        enc.len(tagged.len())?;
+        for node in tagged {
+            enc.element(node)?;
+            enc.f64(node.el.lat)?;
+            enc.f64(node.el.lon)?;
+        }
+
+        enc.len(self.ways.len())?;
+        for way in self.ways.values() {
+            enc.element(way)?;
+            enc.len(way.el.refs.len())?;
+            way.el.refs.iter().try_for_each(|id| enc.i64(*id))?;
+        }
+
+        enc.len(self.relations.len())?;
+        for rel in self.relations.values() {
+            enc.element(rel)?;
+            enc.len(rel.el.members.len())?;
+            for (ty, member) in &rel.el.members {
+                enc.u8(member_type_id(ty))?;
+                enc.i64(member.ref_id)?;
+                enc.u32(member.role_sid)?;
+            }
+        }
+        enc.writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// Reads a snapshot written by [`OsmFile::write_snapshot`]. Returns
+    /// `None` if there is no snapshot at `path` or it was written for a
+    /// different key.
+    pub fn read_snapshot(path: &Path, key: &SnapshotKey) -> io::Result<Option<OsmFile>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // SAFETY: snapshots are never modified in place, `write_snapshot`
+        // replaces them with a new file
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let mut dec = Decoder { data: &map };
+        if dec.bytes(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Ok(None);
+        }
+        let stored_key = SnapshotKey {
+            size: dec.u64()?,
+            mtime: dec.u64()?,
+            hash: dec.u64()?,
+        };
+        if stored_key != *key {
+            return Ok(None);
+        }
+
+        let mut osm_file = OsmFile::default();
+        let header = &mut osm_file.header;
+        header.bbox = dec.option(|dec| {
+            Ok(BBox {
+                min_lat: dec.f64()?,
+                min_lon: dec.f64()?,
+                max_lat: dec.f64()?,
+                max_lon: dec.f64()?,
+            })
+        })?;
+        header.required_features = dec.strings()?;
+        header.optional_features = dec.strings()?;
+        header.writing_program = dec.option(Decoder::string)?;
+        header.source = dec.option(Decoder::string)?;
+        header.replication_timestamp = dec.option(Decoder::i64)?;
+        header.replication_sequence_number = dec.option(Decoder::i64)?;
+        header.replication_base_url = dec.option(Decoder::string)?;
+
+        for _ in 0..dec.len()? {
+            osm_file.string_table.insert(dec.string()?);
+        }
+        let string_count = osm_file.string_table.curr_id;
+
+        let packed_count = dec.len()?;
+        osm_file.nodes.reserve(packed_count);
+        for _ in 0..packed_count {
+            osm_file
+                .nodes
+                .insert_packed(dec.i64()?, dec.i32()?, dec.i32()?);
+        }
+        for _ in 0..dec.len()? {
+            let node = dec.element(|dec| {
+                Ok(OsmNodeData {
+                    lat: dec.f64()?,
+                    lon: dec.f64()?,
+                })
+            })?;
+            check_strings(&node, string_count)?;
+            osm_file.nodes.insert(node);
+        }
+
+        let way_count = dec.len()?;
+        osm_file.ways.reserve(way_count);
+        for _ in 0..way_count {
+            let way = dec.element(|dec| {
+                let refs = (0..dec.len()?)
+                    .map(|_| dec.i64())
+                    .collect::<io::Result<_>>()?;
+                Ok(OsmWayData { refs })
+            })?;
+            check_strings(&way, string_count)?;
+            osm_file.ways.insert(way.id, way);
+        }
+
+        let relation_count = dec.len()?;
+        osm_file.relations.reserve(relation_count);
+        for _ in 0..relation_count {
+            let rel = dec.element(|dec| {
+                let members = (0..dec.len()?)
+                    .map(|_| {
+                        let ty = match dec.u8()? {
+                            0 => OsmRelationMemberType::Node,
+                            1 => OsmRelationMemberType::Way,
+                            2 => OsmRelationMemberType::Relation,
+                            _ => return Err(invalid("Invalid member type")),
+                        };
+                        let member = OsmRelationMemberInfo {
+                            ref_id: dec.i64()?,
+                            role_sid: check_sid(dec.u32()?, string_count)?,
+                        };
+                        Ok((ty, member))
+                    })
+                    .collect::<io::Result<_>>()?;
+                Ok(OsmRelationData { members })
+            })?;
+            check_strings(&rel, string_count)?;
+            osm_file.relations.insert(rel.id, rel);
+        }
+        Ok(Some(osm_file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::XmlReader;
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    const SAMPLE: &[u8] = include_bytes!("../../tests/data/sample.osm");
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("chaze-snapshot-{}-{}", std::process::id(), name))
+    }
+
+    fn key(source: &Path) -> SnapshotKey {
+        SnapshotKey::new(source, &LoadFilter::default(), true).unwrap()
+    }
+
+    /// One line per element and header field, sorted by element id.
+    fn describe(osm_file: &OsmFile) -> Vec<String> {
+        let info = |info: Option<&OsmElementInfo>| {
+            info.map(|i| (i.version, i.timestamp, i.changeset, i.uid))
+        };
+        let mut lines = vec![format!("{:?}", osm_file.header.replication_timestamp)];
+        lines.extend(osm_file.nodes().map(|node| {
+            let (el, user) = (node.data(), node.user());
+            let tags = node.tags();
+            let info = info(node.info());
+            format!(
+                "n{} {} {} {:?} {:?} {:?}",
+                node.id(),
+                el.lat,
+                el.lon,
+                tags,
+                info,
+                user
+            )
+        }));
+        lines.extend(osm_file.ways().map(|way| {
+            let tags = way.tags();
+            format!("w{} {:?} {:?}", way.id(), way.data().refs, tags)
+        }));
+        lines.extend(osm_file.relations().map(|rel| {
+            let members = rel
+                .data()
+                .members
+                .iter()
+                .map(|(ty, m)| {
+                    (
+                        member_type_id(ty),
+                        m.ref_id,
+                        osm_file.get_string(m.role_sid),
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!("r{} {:?} {:?}", rel.id(), members, rel.tags())
+        }));
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn fnv() {
+        let mut hasher = Fnv::new();
+        assert_eq!(hasher.0, 0xcbf2_9ce4_8422_2325);
+        hasher.write(b"a");
+        assert_eq!(hasher.0, 0xaf63_dc4c_8601_ec8c);
+        hasher.write(b"bc");
+        assert_eq!(hasher.0, 0xe71f_a219_0541_574b);
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = temp_path("round-trip.osm");
+        fs::write(&source, SAMPLE).unwrap();
+        let osm_file = XmlReader::new(SAMPLE).with_metadata(true).read().unwrap();
+        // Ends with the extension that was used for incomplete snapshots
+        let path = temp_path("round-trip.tmp");
+        osm_file.write_snapshot(&path, &key(&source)).unwrap();
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        assert!(!Path::new(&partial).exists());
+
+        let read = OsmFile::read_snapshot(&path, &key(&source))
+            .unwrap()
+            .unwrap();
+        assert_eq!(describe(&read), describe(&osm_file));
+        let bbox = read.header.bbox.unwrap();
+        assert_eq!((bbox.min_lat, bbox.max_lon), (47.37, 8.55));
+        assert_eq!(read.header.writing_program.as_deref(), Some("hand-written"));
+
+        // Overwriting an existing snapshot
+        osm_file.write_snapshot(&path, &key(&source)).unwrap();
+        assert!(OsmFile::read_snapshot(&path, &key(&source))
+            .unwrap()
+            .is_some());
+        fs::remove_file(path).unwrap();
+        fs::remove_file(source).unwrap();
+    }
+
+    #[test]
+    fn invalidation() {
+        let source = temp_path("invalidation.osm");
+        fs::write(&source, SAMPLE).unwrap();
+        let osm_file = XmlReader::new(SAMPLE).read().unwrap();
+        let path = temp_path("invalidation.snap");
+        let written = key(&source);
+        osm_file.write_snapshot(&path, &written).unwrap();
+        let is_valid = |key: &SnapshotKey| OsmFile::read_snapshot(&path, key).unwrap().is_some();
+        assert!(is_valid(&key(&source)));
+
+        // Options
+        let bbox = BBox {
+            min_lat: 47.0,
+            min_lon: 8.0,
+            max_lat: 48.0,
+            max_lon: 9.0,
+        };
+        let filters = [
+            LoadFilter {
+                bbox: Some(bbox),
+                tag_keys: None,
+            },
+            LoadFilter {
+                bbox: Some(BBox {
+                    max_lon: 9.5,
+                    ..bbox
+                }),
+                tag_keys: None,
+            },
+            LoadFilter {
+                bbox: None,
+                tag_keys: Some(HashSet::from(["highway".to_string()])),
+            },
+            LoadFilter {
+                bbox: None,
+                tag_keys: Some(HashSet::from(["high".to_string(), "way".to_string()])),
+            },
+            LoadFilter {
+                bbox: None,
+                tag_keys: Some(HashSet::new()),
+            },
+        ];
+        let mut hashes = vec![written.hash];
+        for filter in &filters {
+            let key = SnapshotKey::new(&source, filter, true).unwrap();
+            assert!(!is_valid(&key));
+            hashes.push(key.hash);
+        }
+        hashes.push(
+            SnapshotKey::new(&source, &LoadFilter::default(), false)
+                .unwrap()
+                .hash,
+        );
+        let unique = hashes.iter().collect::<HashSet<_>>();
+        assert_eq!(unique.len(), hashes.len());
+
+        // Modification time
+        let file = File::options().write(true).open(&source).unwrap();
+        let mtime = file.metadata().unwrap().modified().unwrap();
+        file.set_modified(mtime + Duration::from_secs(1)).unwrap();
+        assert!(!is_valid(&key(&source)));
+        file.set_modified(mtime).unwrap();
+        assert!(is_valid(&key(&source)));
+
+        // Contents of the same size and time
+        let mut changed = SAMPLE.to_vec();
+        changed[SAMPLE.len() - 3] = b'X';
+        fs::write(&source, &changed).unwrap();
+        File::options()
+            .write(true)
+            .open(&source)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        assert!(!is_valid(&key(&source)));
+
+        // Size
+        changed.push(b'\n');
+        fs::write(&source, &changed).unwrap();
+        assert!(!is_valid(&key(&source)));
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(source).unwrap();
+    }
+}
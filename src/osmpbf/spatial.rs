@@ -0,0 +1,257 @@
+use super::{BBox, OsmFile, OsmRelationMemberType};
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::{RTree, AABB};
+use std::collections::{HashMap, HashSet};
+
+type IndexedElement = GeomWithData<Rectangle<[f64; 2]>, (OsmRelationMemberType, i64)>;
+
+/// R-tree over the bounding boxes of the ways, relations and tagged nodes
+/// of an [`OsmFile`]. Points are stored as `[lon, lat]`.
+pub(super) struct SpatialIndex {
+    tree: RTree<IndexedElement>,
+    /// Bounding boxes by id, which the tree cannot look up
+    ways: HashMap<i64, Option<BBox>>,
+    relations: HashMap<i64, Option<BBox>>,
+}
+
+fn union(a: Option<BBox>, b: BBox) -> BBox {
+    match a {
+        Some(a) => BBox {
+            min_lat: a.min_lat.min(b.min_lat),
+            min_lon: a.min_lon.min(b.min_lon),
+            max_lat: a.max_lat.max(b.max_lat),
+            max_lon: a.max_lon.max(b.max_lon),
+        },
+        None => b,
+    }
+}
+
+fn point_bbox(lat: f64, lon: f64) -> BBox {
+    BBox {
+        min_lat: lat,
+        min_lon: lon,
+        max_lat: lat,
+        max_lon: lon,
+    }
+}
+
+fn to_aabb(bbox: &BBox) -> AABB<[f64; 2]> {
+    AABB::from_corners([bbox.min_lon, bbox.min_lat], [bbox.max_lon, bbox.max_lat])
+}
+
+/// Bounding boxes of elements, computed on demand.
+struct Bounds<'a> {
+    osm_file: &'a OsmFile,
+    ways: HashMap<i64, Option<BBox>>,
+    relations: HashMap<i64, Option<BBox>>,
+    /// Number of cyclic references skipped so far
+    cycles: usize,
+}
+
+impl<'a> Bounds<'a> {
+    fn new(osm_file: &'a OsmFile) -> Self {
+        Bounds {
+            osm_file,
+            ways: HashMap::new(),
+            relations: HashMap::new(),
+            cycles: 0,
+        }
+    }
+
+    fn node(&self, id: i64) -> Option<BBox> {
+        let node = self.osm_file.get_node(id)?;
+        Some(point_bbox(node.data().lat, node.data().lon))
+    }
+
+    fn way(&mut self, id: i64) -> Option<BBox> {
+        if let Some(bbox) = self.ways.get(&id) {
+            return *bbox;
+        }
+        let bbox = self
+            .osm_file
+            .ways
+            .get(&id)?
+            .el
+            .refs
+            .iter()
+            .filter_map(|id| self.node(*id))
+            .reduce(|a, b| union(Some(a), b));
+        self.ways.insert(id, bbox);
+        bbox
+    }
+
+    /// Returns the bounding box of the relation's members. Members that
+    /// are not loaded are ignored, as are cyclic references between
+    /// relations. Boxes that are missing a skipped relation are only cached
+    /// for the relation the cycle was entered from.
+    fn relation(&mut self, id: i64, visiting: &mut HashSet<i64>) -> Option<BBox> {
+        if let Some(bbox) = self.relations.get(&id) {
+            return *bbox;
+        }
+        let rel = self.osm_file.relations.get(&id)?;
+        if !visiting.insert(id) {
+            self.cycles += 1;
+            return None;
+        }
+        let cycles = self.cycles;
+        let mut bbox = None;
+        for (ty, member) in &rel.el.members {
+            let member_bbox = match ty {
+                OsmRelationMemberType::Node => self.node(member.ref_id),
+                OsmRelationMemberType::Way => self.way(member.ref_id),
+                OsmRelationMemberType::Relation => self.relation(member.ref_id, visiting),
+            };
+            if let Some(member_bbox) = member_bbox {
+                bbox = Some(union(bbox, member_bbox));
+            }
+        }
+        visiting.remove(&id);
+        if visiting.is_empty() || self.cycles == cycles {
+            self.relations.insert(id, bbox);
+        }
+        bbox
+    }
+}
+
+impl SpatialIndex {
+    fn new(osm_file: &OsmFile) -> Self {
+        let mut bounds = Bounds::new(osm_file);
+        bounds.ways.reserve(osm_file.ways.len());
+        bounds.relations.reserve(osm_file.relations.len());
+        for id in osm_file.ways.keys() {
+            bounds.way(*id);
+        }
+        for id in osm_file.relations.keys() {
+            bounds.relation(*id, &mut HashSet::new());
+        }
+
+        let nodes = osm_file.nodes.tagged().map(|node| {
+            let bbox = point_bbox(node.el.lat, node.el.lon);
+            (OsmRelationMemberType::Node, node.id, bbox)
+        });
+        let ways = bounds
+            .ways
+            .iter()
+            .filter_map(|(id, bbox)| Some((OsmRelationMemberType::Way, *id, (*bbox)?)));
+        let relations = bounds
+            .relations
+            .iter()
+            .filter_map(|(id, bbox)| Some((OsmRelationMemberType::Relation, *id, (*bbox)?)));
+        let elements = nodes
+            .chain(ways)
+            .chain(relations)
+            .map(|(ty, id, bbox)| {
+                let aabb = to_aabb(&bbox);
+                let rect = Rectangle::from_corners(aabb.lower(), aabb.upper());
+                GeomWithData::new(rect, (ty, id))
+            })
+            .collect();
+        SpatialIndex {
+            tree: RTree::bulk_load(elements),
+            ways: bounds.ways,
+            relations: bounds.relations,
+        }
+    }
+}
+
+impl OsmFile {
+    fn spatial_index(&self) -> &SpatialIndex {
+        self.spatial.get_or_init(|| SpatialIndex::new(self))
+    }
+
+    /// Returns the bounding box of an element, or `None` if none of its
+    /// nodes are loaded. The boxes of ways and relations are computed along
+    /// with the index.
+    pub fn bbox(&self, ty: OsmRelationMemberType, id: i64) -> Option<BBox> {
+        match ty {
+            OsmRelationMemberType::Node => {
+                let node = self.get_node(id)?;
+                Some(point_bbox(node.data().lat, node.data().lon))
+            }
+            OsmRelationMemberType::Way => *self.spatial_index().ways.get(&id)?,
+            OsmRelationMemberType::Relation => *self.spatial_index().relations.get(&id)?,
+        }
+    }
+
+    /// Returns the elements whose bounding box intersects `bbox`, in no
+    /// particular order. The index is built on first use. Untagged nodes
+    /// are not indexed, so their locations are scanned instead.
+    pub fn query_bbox(
+        &self,
+        bbox: &BBox,
+    ) -> impl Iterator<Item = (OsmRelationMemberType, i64)> + '_ {
+        let untagged = self
+            .nodes
+            .untagged_in(*bbox)
+            .map(|id| (OsmRelationMemberType::Node, id));
+        self.spatial_index()
+            .tree
+            .locate_in_envelope_intersecting(&to_aabb(bbox))
+            .map(|el| el.data)
+            .chain(untagged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::XmlReader;
+    use super::*;
+
+    const XML: &str = r#"<osm version="0.6">
+        <node id="1" lat="1.0" lon="1.0"/>
+        <node id="2" lat="2.0" lon="3.0"/>
+        <node id="3" lat="5.0" lon="5.0"><tag k="a" v="b"/></node>
+        <node id="4" lat="9.0" lon="9.0"/>
+        <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="1000"/></way>
+        <way id="11"><nd ref="1000"/></way>
+        <relation id="20"><member type="way" ref="10" role=""/><member type="node" ref="4" role=""/></relation>
+        <relation id="21"><member type="relation" ref="22" role=""/></relation>
+        <relation id="22"><member type="relation" ref="21" role=""/><member type="node" ref="3" role=""/></relation>
+        <relation id="23"><member type="relation" ref="20" role=""/><member type="relation" ref="21" role=""/></relation>
+    </osm>"#;
+
+    fn bbox(osm_file: &OsmFile, ty: OsmRelationMemberType, id: i64) -> Option<[f64; 4]> {
+        let bbox = osm_file.bbox(ty, id)?;
+        Some([bbox.min_lat, bbox.min_lon, bbox.max_lat, bbox.max_lon])
+    }
+
+    #[test]
+    fn bboxes() {
+        let osm_file = XmlReader::new(XML.as_bytes()).read().unwrap();
+        use OsmRelationMemberType::*;
+        assert_eq!(bbox(&osm_file, Node, 2), Some([2.0, 3.0, 2.0, 3.0]));
+        assert_eq!(bbox(&osm_file, Way, 10), Some([1.0, 1.0, 2.0, 3.0]));
+        assert_eq!(bbox(&osm_file, Way, 11), None);
+        assert_eq!(bbox(&osm_file, Relation, 20), Some([1.0, 1.0, 9.0, 9.0]));
+        // Cyclic references are skipped
+        assert_eq!(bbox(&osm_file, Relation, 21), Some([5.0, 5.0, 5.0, 5.0]));
+        assert_eq!(bbox(&osm_file, Relation, 22), Some([5.0, 5.0, 5.0, 5.0]));
+        assert_eq!(bbox(&osm_file, Relation, 23), Some([1.0, 1.0, 9.0, 9.0]));
+        assert_eq!(bbox(&osm_file, Node, 1000), None);
+        assert_eq!(bbox(&osm_file, Relation, 1000), None);
+    }
+
+    #[test]
+    fn query() {
+        let osm_file = XmlReader::new(XML.as_bytes()).read().unwrap();
+        let query = |min: f64, max: f64| {
+            let bbox = BBox {
+                min_lat: min,
+                min_lon: min,
+                max_lat: max,
+                max_lon: max,
+            };
+            let mut ids = osm_file
+                .query_bbox(&bbox)
+                .map(|(_, id)| id)
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        assert_eq!(query(1.5, 1.6), [10, 20, 23]);
+        assert_eq!(query(0.9, 1.1), [1, 10, 20, 23]);
+        assert_eq!(query(1.0, 9.0), [1, 2, 3, 4, 10, 20, 21, 22, 23]);
+        assert_eq!(query(4.9, 5.1), [3, 20, 21, 22, 23]);
+        assert_eq!(query(10.0, 11.0), []);
+    }
+}
@@ -0,0 +1,586 @@
+use super::{osmpbf, BBox, ElementRef, OsmElement, OsmElementInfo, OsmFile, OsmRelationMemberType};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use prost::Message;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+/// Maximum number of elements per block, as recommended by the format
+/// specification.
+const BLOCK_SIZE: usize = 8000;
+/// Coordinate granularity in nanodegrees (the format's default).
+const GRANULARITY: i32 = 100;
+/// Timestamp granularity in milliseconds.
+const DATE_GRANULARITY: i32 = 1000;
+
+/// Ids of the elements that a [`PbfWriter`] writes.
+#[derive(Default)]
+pub struct ElementIds {
+    pub nodes: HashSet<i64>,
+    pub ways: HashSet<i64>,
+    pub relations: HashSet<i64>,
+}
+
+impl ElementIds {
+    /// Adds the elements needed for the geometry of the selected ones: the
+    /// members of relations (recursively) and the nodes of ways.
+    pub fn complete(&mut self, osm_file: &OsmFile) {
+        let mut pending = self.relations.iter().copied().collect::<Vec<i64>>();
+        while let Some(id) = pending.pop() {
+            let Some(rel) = osm_file.relations.get(&id) else {
+                continue;
+            };
+            for (ty, member) in &rel.el.members {
+                match ty {
+                    OsmRelationMemberType::Node => {
+                        self.nodes.insert(member.ref_id);
+                    }
+                    OsmRelationMemberType::Way => {
+                        self.ways.insert(member.ref_id);
+                    }
+                    OsmRelationMemberType::Relation => {
+                        if self.relations.insert(member.ref_id) {
+                            pending.push(member.ref_id);
+                        }
+                    }
+                }
+            }
+        }
+        for id in &self.ways {
+            if let Some(way) = osm_file.ways.get(id) {
+                self.nodes.extend(way.el.refs.iter());
+            }
+        }
+    }
+}
+
+/// Replaces every value after the first by its difference to the previous
+/// one.
+fn delta_encode<T: Copy + std::ops::Sub<Output = T>>(values: &mut [T]) {
+    for i in (1..values.len()).rev() {
+        values[i] = values[i] - values[i - 1];
+    }
+}
+
+fn to_raw_coord(deg: f64) -> i64 {
+    (deg * 1e9 / GRANULARITY as f64).round() as i64
+}
+
+/// String table of a single block. Index 0 is reserved, as it is used as a
+/// delimiter in dense nodes.
+struct BlockStrings<'a> {
+    osm_file: &'a OsmFile,
+    ids: HashMap<u32, u32>,
+    strings: Vec<Vec<u8>>,
+}
+
+impl<'a> BlockStrings<'a> {
+    fn new(osm_file: &'a OsmFile) -> Self {
+        BlockStrings {
+            osm_file,
+            ids: HashMap::new(),
+            strings: vec![Vec::new()],
+        }
+    }
+
+    fn get(&mut self, sid: u32) -> u32 {
+        let osm_file = self.osm_file;
+        let strings = &mut self.strings;
+        *self.ids.entry(sid).or_insert_with(|| {
+            let s = osm_file.get_string(sid).map(|s| s.as_bytes());
+            strings.push(s.unwrap_or_default().to_vec());
+            strings.len() as u32 - 1
+        })
+    }
+
+    /// Returns the keys and values of the element's tags, sorted by key so
+    /// that the output is reproducible.
+    fn tags<T>(&mut self, el: &OsmElement<T>) -> (Vec<u32>, Vec<u32>) {
+        let osm_file = self.osm_file;
+        let mut tags = el.tags.iter().collect::<Vec<_>>();
+        tags.sort_by_key(|(k, _)| osm_file.get_string(**k));
+        tags.into_iter()
+            .map(|(k, v)| (self.get(*k), self.get(*v)))
+            .unzip()
+    }
+
+    fn info(&mut self, info: &OsmElementInfo) -> osmpbf::Info {
+        osmpbf::Info {
+            version: Some(info.version),
+            timestamp: info.timestamp,
+            changeset: info.changeset,
+            uid: info.uid,
+            user_sid: info.user_sid.map(|sid| self.get(sid)),
+            visible: None,
+        }
+    }
+
+    fn into_block(self, group: osmpbf::PrimitiveGroup) -> osmpbf::PrimitiveBlock {
+        osmpbf::PrimitiveBlock {
+            stringtable: osmpbf::StringTable { s: self.strings },
+            primitivegroup: vec![group],
+            granularity: Some(GRANULARITY),
+            lat_offset: None,
+            lon_offset: None,
+            date_granularity: Some(DATE_GRANULARITY),
+        }
+    }
+}
+
+/// Writes an [`OsmFile`] as PBF.
+///
+/// The elements are sorted by type and id, nodes are written as dense
+/// nodes and all blobs are zlib-compressed.
+pub struct PbfWriter<W> {
+    writer: W,
+    bbox: Option<BBox>,
+}
+
+impl<W: Write> PbfWriter<W> {
+    pub fn new(writer: W) -> Self {
+        PbfWriter { writer, bbox: None }
+    }
+
+    /// Sets the bbox stored in the file header, instead of the one of the
+    /// written file.
+    pub fn with_bbox(mut self, bbox: BBox) -> Self {
+        self.bbox = Some(bbox);
+        self
+    }
+
+    /// Writes the elements of `osm_file` that are contained in `ids`, or all
+    /// of them.
+    pub fn write(mut self, osm_file: &OsmFile, ids: Option<&ElementIds>) -> std::io::Result<()> {
+        self.write_header(osm_file)?;
+
+        let mut nodes = osm_file
+            .nodes
+            .iter()
+            .filter(|node| ids.is_none_or(|ids| ids.nodes.contains(&node.id)))
+            .collect::<Vec<_>>();
+        nodes.sort_unstable_by_key(|node| node.id);
+        // Dense nodes either all have metadata or none, so nodes without it
+        // are written to blocks of their own
+        for run in nodes.chunk_by(|a, b| a.info.is_some() == b.info.is_some()) {
+            for chunk in run.chunks(BLOCK_SIZE) {
+                self.write_nodes(osm_file, chunk)?;
+            }
+        }
+
+        let mut ways = osm_file
+            .ways
+            .values()
+            .filter(|way| ids.is_none_or(|ids| ids.ways.contains(&way.id)))
+            .collect::<Vec<_>>();
+        ways.sort_unstable_by_key(|way| way.id);
+        for chunk in ways.chunks(BLOCK_SIZE) {
+            let mut strings = BlockStrings::new(osm_file);
+            let ways = chunk
+                .iter()
+                .map(|way| {
+                    let (keys, vals) = strings.tags(way);
+                    let mut refs = way.el.refs.clone();
+                    delta_encode(&mut refs);
+                    osmpbf::Way {
+                        id: way.id,
+                        keys,
+                        vals,
+                        info: way.info.as_ref().map(|info| strings.info(info)),
+                        refs,
+                        lat: Vec::new(),
+                        lon: Vec::new(),
+                    }
+                })
+                .collect();
+            let group = osmpbf::PrimitiveGroup {
+                ways,
+                ..Default::default()
+            };
+            self.write_blob("OSMData", &strings.into_block(group).encode_to_vec())?;
+        }
+
+        let mut relations = osm_file
+            .relations
+            .values()
+            .filter(|rel| ids.is_none_or(|ids| ids.relations.contains(&rel.id)))
+            .collect::<Vec<_>>();
+        relations.sort_unstable_by_key(|rel| rel.id);
+        for chunk in relations.chunks(BLOCK_SIZE) {
+            let mut strings = BlockStrings::new(osm_file);
+            let relations = chunk
+                .iter()
+                .map(|rel| {
+                    let (keys, vals) = strings.tags(rel);
+                    let members = &rel.el.members;
+                    let mut memids = members.iter().map(|(_, m)| m.ref_id).collect::<Vec<_>>();
+                    delta_encode(&mut memids);
+                    osmpbf::Relation {
+                        id: rel.id,
+                        keys,
+                        vals,
+                        info: rel.info.as_ref().map(|info| strings.info(info)),
+                        roles_sid: members
+                            .iter()
+                            .map(|(_, m)| strings.get(m.role_sid) as i32)
+                            .collect(),
+                        memids,
+                        types: members
+                            .iter()
+                            .map(|(ty, _)| {
+                                let ty = match ty {
+                                    OsmRelationMemberType::Node => {
+                                        osmpbf::relation::MemberType::Node
+                                    }
+                                    OsmRelationMemberType::Way => osmpbf::relation::MemberType::Way,
+                                    OsmRelationMemberType::Relation => {
+                                        osmpbf::relation::MemberType::Relation
+                                    }
+                                };
+                                ty as i32
+                            })
+                            .collect(),
+                    }
+                })
+                .collect();
+            let group = osmpbf::PrimitiveGroup {
+                relations,
+                ..Default::default()
+            };
+            self.write_blob("OSMData", &strings.into_block(group).encode_to_vec())?;
+        }
+        self.writer.flush()
+    }
+
+    fn write_header(&mut self, osm_file: &OsmFile) -> std::io::Result<()> {
+        let header = &osm_file.header;
+        let bbox = self.bbox.or(header.bbox).map(|bbox| osmpbf::HeaderBBox {
+            left: (bbox.min_lon * 1e9).round() as i64,
+            right: (bbox.max_lon * 1e9).round() as i64,
+            top: (bbox.max_lat * 1e9).round() as i64,
+            bottom: (bbox.min_lat * 1e9).round() as i64,
+        });
+        let block = osmpbf::HeaderBlock {
+            bbox,
+            required_features: vec!["OsmSchema-V0.6".to_string(), "DenseNodes".to_string()],
+            optional_features: vec!["Sort.Type_then_ID".to_string()],
+            writingprogram: Some(format!("chaze {}", env!("CARGO_PKG_VERSION"))),
+            source: header.source.clone(),
+            osmosis_replication_timestamp: header.replication_timestamp,
+            osmosis_replication_sequence_number: header.replication_sequence_number,
+            osmosis_replication_base_url: header.replication_base_url.clone(),
+        };
+        self.write_blob("OSMHeader", &block.encode_to_vec())
+    }
+
+    fn write_nodes(
+        &mut self,
+        osm_file: &OsmFile,
+        nodes: &[ElementRef<super::OsmNodeData>],
+    ) -> std::io::Result<()> {
+        let mut strings = BlockStrings::new(osm_file);
+        let mut dense = osmpbf::DenseNodes::default();
+        for node in nodes {
+            dense.id.push(node.id);
+            dense.lat.push(to_raw_coord(node.el.lat));
+            dense.lon.push(to_raw_coord(node.el.lon));
+            let (keys, vals) = strings.tags(node);
+            for (k, v) in keys.into_iter().zip(vals) {
+                dense.keys_vals.extend([k as i32, v as i32]);
+            }
+            dense.keys_vals.push(0);
+        }
+        delta_encode(&mut dense.id);
+        delta_encode(&mut dense.lat);
+        delta_encode(&mut dense.lon);
+
+        let infos = nodes
+            .iter()
+            .map(|node| node.info.as_deref())
+            .collect::<Option<Vec<_>>>();
+        if let Some(infos) = infos {
+            let mut info = osmpbf::DenseInfo::default();
+            for node_info in infos {
+                let node_info = strings.info(node_info);
+                info.version.push(node_info.version());
+                info.timestamp.push(node_info.timestamp());
+                info.changeset.push(node_info.changeset());
+                info.uid.push(node_info.uid());
+                info.user_sid.push(node_info.user_sid() as i32);
+            }
+            delta_encode(&mut info.timestamp);
+            delta_encode(&mut info.changeset);
+            delta_encode(&mut info.uid);
+            delta_encode(&mut info.user_sid);
+            dense.denseinfo = Some(info);
+        }
+
+        let group = osmpbf::PrimitiveGroup {
+            dense: Some(dense),
+            ..Default::default()
+        };
+        self.write_blob("OSMData", &strings.into_block(group).encode_to_vec())
+    }
+
+    fn write_blob(&mut self, ty: &str, data: &[u8]) -> std::io::Result<()> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let blob = osmpbf::Blob {
+            raw_size: Some(data.len() as i32),
+            data: Some(osmpbf::blob::Data::ZlibData(encoder.finish()?)),
+        }
+        .encode_to_vec();
+        let header = osmpbf::BlobHeader {
+            r#type: ty.to_string(),
+            indexdata: None,
+            datasize: blob.len() as i32,
+        }
+        .encode_to_vec();
+        self.writer
+            .write_all(&(header.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&blob)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        OsmNodeData, OsmRelationData, OsmRelationMemberInfo, OsmWayData, PbfReader,
+    };
+    use super::*;
+    use std::io::Cursor;
+
+    const NODE_COUNT: i64 = BLOCK_SIZE as i64 + 10;
+
+    fn element<T>(osm_file: &mut OsmFile, id: i64, tags: &[(&str, &str)], el: T) -> OsmElement<T> {
+        let tags = tags
+            .iter()
+            .map(|(k, v)| {
+                let k = osm_file.string_table.insert(k.to_string());
+                (k, osm_file.string_table.insert(v.to_string()))
+            })
+            .collect();
+        OsmElement {
+            id,
+            tags,
+            info: None,
+            el,
+        }
+    }
+
+    fn info(osm_file: &mut OsmFile, i: i64) -> Option<Box<OsmElementInfo>> {
+        Some(Box::new(OsmElementInfo {
+            version: (i % 5) as i32 + 1,
+            // Decreasing timestamps, changesets and uids give negative deltas
+            timestamp: Some(1_700_000_000 - i * 60),
+            changeset: Some(150_000_000 - i * 3),
+            uid: Some((1000 - i % 7 * 100) as i32),
+            user_sid: Some(osm_file.string_table.insert(format!("user{}", i % 7))),
+        }))
+    }
+
+    /// Nodes with negative ids and coordinates going back and forth, split
+    /// over two blocks, a way referencing them in reverse order, and
+    /// relations with members of all types.
+    fn sample() -> OsmFile {
+        let mut osm_file = OsmFile::default();
+        osm_file.header.bbox = Some(BBox {
+            min_lat: -33.9,
+            min_lon: 18.4,
+            max_lat: 47.4,
+            max_lon: 151.2,
+        });
+        osm_file.header.replication_timestamp = Some(1_700_000_000);
+        osm_file.header.replication_sequence_number = Some(42);
+        for i in 0..NODE_COUNT {
+            let id = i * 2 - 100;
+            let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+            let data = OsmNodeData {
+                lat: sign * (i as f64 * 1e-7 + 0.1234567),
+                lon: -sign * (180.0 - i as f64 * 1e-7),
+            };
+            let tags: &[(&str, &str)] = match i % 3 {
+                0 => &[("amenity", "bench")],
+                1 => &[("name", "Ölberg"), ("natural", "peak")],
+                _ => &[],
+            };
+            let mut node = element(&mut osm_file, id, tags, data);
+            node.info = info(&mut osm_file, i);
+            osm_file.nodes.insert(node);
+        }
+        let refs = vec![500, -100, 16, 14, 12, -98];
+        let mut way = element(
+            &mut osm_file,
+            7,
+            &[("highway", "path")],
+            OsmWayData { refs },
+        );
+        way.info = info(&mut osm_file, 3);
+        osm_file.ways.insert(way.id, way);
+        let refs = vec![-100, -98, -96, -100];
+        let way = element(
+            &mut osm_file,
+            -5,
+            &[("building", "yes")],
+            OsmWayData { refs },
+        );
+        osm_file.ways.insert(way.id, way);
+
+        let mut member = |ty, ref_id, role: &str| {
+            let role_sid = osm_file.string_table.insert(role.to_string());
+            (ty, OsmRelationMemberInfo { ref_id, role_sid })
+        };
+        let members = vec![
+            member(OsmRelationMemberType::Way, 7, "outer"),
+            member(OsmRelationMemberType::Way, -5, "inner"),
+            member(OsmRelationMemberType::Node, 1000, "label"),
+            member(OsmRelationMemberType::Node, -100, ""),
+            member(OsmRelationMemberType::Relation, -3, "subarea"),
+        ];
+        let tags = [("type", "multipolygon"), ("landuse", "meadow")];
+        let mut rel = element(&mut osm_file, 3, &tags, OsmRelationData { members });
+        rel.info = info(&mut osm_file, 8);
+        osm_file.relations.insert(rel.id, rel);
+        let rel = element(
+            &mut osm_file,
+            -3,
+            &[("type", "boundary")],
+            OsmRelationData {
+                members: Vec::new(),
+            },
+        );
+        osm_file.relations.insert(rel.id, rel);
+        osm_file
+    }
+
+    fn write(osm_file: &OsmFile, ids: Option<&ElementIds>) -> Vec<u8> {
+        let mut data = Vec::new();
+        PbfWriter::new(&mut data).write(osm_file, ids).unwrap();
+        data
+    }
+
+    fn read(data: Vec<u8>) -> OsmFile {
+        PbfReader::new(Cursor::new(data))
+            .with_metadata(true)
+            .read()
+            .unwrap()
+    }
+
+    fn blob_types(mut data: &[u8]) -> Vec<String> {
+        let mut types = Vec::new();
+        while !data.is_empty() {
+            let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            let header = osmpbf::BlobHeader::decode(&data[4..4 + len]).unwrap();
+            data = &data[4 + len + header.datasize as usize..];
+            types.push(header.r#type);
+        }
+        types
+    }
+
+    fn assert_info(a: Option<&OsmElementInfo>, b: Option<&OsmElementInfo>) {
+        let (Some(a), Some(b)) = (a, b) else {
+            assert_eq!(a.is_some(), b.is_some());
+            return;
+        };
+        assert_eq!(a.version, b.version);
+        assert_eq!(a.timestamp, b.timestamp);
+        assert_eq!(a.changeset, b.changeset);
+        assert_eq!(a.uid, b.uid);
+    }
+
+    fn assert_same(expected: &OsmFile, actual: &OsmFile) {
+        assert_eq!(expected.nodes().count(), actual.nodes().count());
+        for node in expected.nodes() {
+            let other = actual.get_node(node.id()).unwrap();
+            assert!((node.data().lat - other.data().lat).abs() < 1e-9);
+            assert!((node.data().lon - other.data().lon).abs() < 1e-9);
+            assert_eq!(node.tags(), other.tags());
+            assert_info(node.info(), other.info());
+            assert_eq!(node.user(), other.user());
+        }
+        assert_eq!(expected.ways().count(), actual.ways().count());
+        for way in expected.ways() {
+            let other = actual.get_way(way.id()).unwrap();
+            assert_eq!(way.data().refs, other.data().refs);
+            assert_eq!(way.tags(), other.tags());
+            assert_info(way.info(), other.info());
+            assert_eq!(way.user(), other.user());
+        }
+        assert_eq!(expected.relations().count(), actual.relations().count());
+        for rel in expected.relations() {
+            let other = actual.get_relation(rel.id()).unwrap();
+            let members = |file: &OsmFile, data: &OsmRelationData| {
+                data.members
+                    .iter()
+                    .map(|(ty, m)| (*ty, m.ref_id, file.get_string(m.role_sid).cloned()))
+                    .collect::<Vec<_>>()
+            };
+            assert!(members(expected, rel.data()) == members(actual, other.data()));
+            assert_eq!(rel.tags(), other.tags());
+            assert_info(rel.info(), other.info());
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let osm_file = sample();
+        let data = write(&osm_file, None);
+        assert_eq!(
+            blob_types(&data),
+            ["OSMHeader", "OSMData", "OSMData", "OSMData", "OSMData"]
+        );
+        let read = read(data);
+        assert_same(&osm_file, &read);
+
+        let header = read.header();
+        let bbox = header.bbox.unwrap();
+        assert!((bbox.min_lat + 33.9).abs() < 1e-9 && (bbox.max_lon - 151.2).abs() < 1e-9);
+        assert_eq!(header.replication_timestamp, Some(1_700_000_000));
+        assert_eq!(header.replication_sequence_number, Some(42));
+    }
+
+    #[test]
+    fn round_trip_mixed_info() {
+        // Nodes without metadata, like the locations of ways in files with
+        // LocationsOnWays, in between nodes with metadata
+        let mut osm_file = sample();
+        let bare = osm_file
+            .nodes
+            .iter()
+            .filter(|node| node.id == -96 || (0..400).contains(&node.id) || node.id > 15000)
+            .map(|node| OsmElement {
+                info: None,
+                ..(*node).clone()
+            })
+            .collect::<Vec<_>>();
+        for node in bare {
+            osm_file.nodes.insert(node);
+        }
+        let data = write(&osm_file, None);
+        // Six runs of nodes with or without metadata, the ways and the
+        // relations
+        let types = blob_types(&data);
+        assert_eq!(types.iter().filter(|ty| *ty == "OSMData").count(), 8);
+        assert_same(&osm_file, &read(data));
+    }
+
+    #[test]
+    fn round_trip_selected() {
+        let osm_file = sample();
+        let mut ids = ElementIds::default();
+        ids.relations.insert(3);
+        ids.complete(&osm_file);
+        let read = read(write(&osm_file, Some(&ids)));
+
+        let mut node_ids = read.nodes().map(|node| node.id()).collect::<Vec<_>>();
+        node_ids.sort();
+        assert_eq!(node_ids, [-100, -98, -96, 12, 14, 16, 500, 1000]);
+        assert_eq!(read.ways().count(), 2);
+        assert_eq!(read.relations().count(), 2);
+        for node in read.nodes() {
+            let expected = osm_file.get_node(node.id()).unwrap();
+            assert!((node.data().lat - expected.data().lat).abs() < 1e-9);
+            assert_eq!(node.tags(), expected.tags());
+        }
+    }
+}
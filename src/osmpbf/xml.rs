@@ -0,0 +1,412 @@
+use super::change::{ChangeAction, OsmChange};
+use super::{
+    parse_timestamp, BBox, LoadFilter, OsmElement, OsmElementInfo, OsmFile, OsmNodeData,
+    OsmRelationData, OsmRelationMemberInfo, OsmRelationMemberType, OsmWayData,
+};
+use quick_xml::events::{BytesStart, Event};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::str::FromStr;
+
+/// Reader for OSM XML files (`.osm`), such as JOSM exports or Overpass
+/// results.
+///
+/// The elements end up in the same [`OsmFile`] as with the
+/// [`super::PbfReader`]. Elements marked as deleted, either by JOSM
+/// (`action="delete"`) or in history files (`visible="false"`), are skipped.
+/// A [`LoadFilter`] is applied after the whole file has been read.
+pub struct XmlReader<R> {
+    reader: R,
+    filter: Option<LoadFilter>,
+    with_metadata: bool,
+}
+
+pub(super) enum XmlElement {
+    Node(OsmElement<OsmNodeData>),
+    Way(OsmElement<OsmWayData>),
+    Relation(OsmElement<OsmRelationData>),
+}
+
+impl XmlElement {
+    fn tags_mut(&mut self) -> &mut HashMap<u32, u32> {
+        match self {
+            XmlElement::Node(node) => &mut node.tags,
+            XmlElement::Way(way) => &mut way.tags,
+            XmlElement::Relation(rel) => &mut rel.tags,
+        }
+    }
+}
+
+type Attributes = HashMap<String, String>;
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+fn attributes(e: &BytesStart) -> Result<Attributes, std::io::Error> {
+    e.attributes()
+        .map(|attr| {
+            let attr = attr.map_err(invalid_data)?;
+            let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+            let value = attr.unescape_value().map_err(invalid_data)?.into_owned();
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn required<T: FromStr>(attrs: &Attributes, name: &str) -> Result<T, std::io::Error> {
+    attrs
+        .get(name)
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| invalid_data(format!("Missing or invalid attribute '{}'", name)))
+}
+
+fn optional<T: FromStr>(attrs: &Attributes, name: &str) -> Option<T> {
+    attrs.get(name).and_then(|v| v.parse().ok())
+}
+
+struct XmlState {
+    osm_file: OsmFile,
+    current: Option<XmlElement>,
+    with_metadata: bool,
+    /// Elements read from an OsmChange file, which are collected here
+    /// instead of being added to `osm_file`
+    changes: Option<Vec<(ChangeAction, XmlElement)>>,
+    action: Option<ChangeAction>,
+}
+
+impl XmlState {
+    fn read_info(&mut self, attrs: &Attributes) -> Option<Box<OsmElementInfo>> {
+        if !self.with_metadata {
+            return None;
+        }
+        Some(Box::new(OsmElementInfo {
+            version: optional(attrs, "version").unwrap_or(-1),
+            timestamp: attrs.get("timestamp").and_then(|t| parse_timestamp(t)),
+            changeset: optional(attrs, "changeset"),
+            uid: optional(attrs, "uid"),
+            user_sid: attrs
+                .get("user")
+                .map(|user| self.osm_file.string_table.insert(user.clone())),
+        }))
+    }
+
+    fn start(&mut self, e: &BytesStart, is_empty: bool) -> Result<(), std::io::Error> {
+        let name = e.name();
+        match name.as_ref() {
+            b"osm" => {
+                let attrs = attributes(e)?;
+                self.osm_file.header.writing_program = attrs.get("generator").cloned();
+            }
+            b"bounds" => {
+                let attrs = attributes(e)?;
+                self.osm_file.header.bbox = Some(BBox {
+                    min_lat: required(&attrs, "minlat")?,
+                    min_lon: required(&attrs, "minlon")?,
+                    max_lat: required(&attrs, "maxlat")?,
+                    max_lon: required(&attrs, "maxlon")?,
+                });
+            }
+            // Overpass API: timestamp of the data
+            b"meta" => {
+                let attrs = attributes(e)?;
+                self.osm_file.header.replication_timestamp =
+                    attrs.get("osm_base").and_then(|t| parse_timestamp(t));
+            }
+            b"create" | b"modify" | b"delete" if self.changes.is_some() => {
+                // An empty block like `<delete/>` has no end event that would
+                // reset the action
+                self.action = match name.as_ref() {
+                    _ if is_empty => None,
+                    b"create" => Some(ChangeAction::Create),
+                    b"modify" => Some(ChangeAction::Modify),
+                    _ => Some(ChangeAction::Delete),
+                };
+            }
+            b"node" | b"way" | b"relation" => {
+                let attrs = attributes(e)?;
+                let deleted = attrs.get("action").is_some_and(|a| a == "delete")
+                    || attrs.get("visible").is_some_and(|v| v == "false");
+                if deleted && self.changes.is_none() {
+                    return Ok(());
+                }
+                let id = required(&attrs, "id")?;
+                let info = self.read_info(&attrs);
+                let tags = HashMap::new();
+                self.current = Some(match name.as_ref() {
+                    // Deleted nodes do not need a location
+                    b"node" if self.action == Some(ChangeAction::Delete) => {
+                        XmlElement::Node(OsmElement {
+                            id,
+                            tags,
+                            info,
+                            el: OsmNodeData {
+                                lat: optional(&attrs, "lat").unwrap_or_default(),
+                                lon: optional(&attrs, "lon").unwrap_or_default(),
+                            },
+                        })
+                    }
+                    b"node" => XmlElement::Node(OsmElement {
+                        id,
+                        tags,
+                        info,
+                        el: OsmNodeData {
+                            lat: required(&attrs, "lat")?,
+                            lon: required(&attrs, "lon")?,
+                        },
+                    }),
+                    b"way" => XmlElement::Way(OsmElement {
+                        id,
+                        tags,
+                        info,
+                        el: OsmWayData { refs: Vec::new() },
+                    }),
+                    _ => XmlElement::Relation(OsmElement {
+                        id,
+                        tags,
+                        info,
+                        el: OsmRelationData {
+                            members: Vec::new(),
+                        },
+                    }),
+                });
+                if is_empty {
+                    self.finish();
+                }
+            }
+            b"tag" => {
+                if let Some(current) = &mut self.current {
+                    let attrs = attributes(e)?;
+                    let k = required::<String>(&attrs, "k")?;
+                    let v = required::<String>(&attrs, "v")?;
+                    let k_id = self.osm_file.string_table.insert(k);
+                    let v_id = self.osm_file.string_table.insert(v);
+                    current.tags_mut().insert(k_id, v_id);
+                }
+            }
+            b"nd" => {
+                if let Some(XmlElement::Way(way)) = &mut self.current {
+                    way.el.refs.push(required(&attributes(e)?, "ref")?);
+                }
+            }
+            b"member" => {
+                if let Some(XmlElement::Relation(rel)) = &mut self.current {
+                    let attrs = attributes(e)?;
+                    let ty = match attrs.get("type").map(|t| t.as_str()) {
+                        Some("node") => OsmRelationMemberType::Node,
+                        Some("way") => OsmRelationMemberType::Way,
+                        Some("relation") => OsmRelationMemberType::Relation,
+                        _ => return Err(invalid_data("Invalid relation member type")),
+                    };
+                    let role = attrs.get("role").cloned().unwrap_or_default();
+                    let info = OsmRelationMemberInfo {
+                        ref_id: required(&attrs, "ref")?,
+                        role_sid: self.osm_file.string_table.insert(role),
+                    };
+                    rel.el.members.push((ty, info));
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        if let Some(changes) = &mut self.changes {
+            if let (Some(action), Some(el)) = (self.action, self.current.take()) {
+                changes.push((action, el));
+            }
+            return;
+        }
+        match self.current.take() {
+            Some(XmlElement::Node(node)) => {
+                self.osm_file.nodes.insert(node);
+            }
+            Some(XmlElement::Way(way)) => {
+                self.osm_file.ways.insert(way.id, way);
+            }
+            Some(XmlElement::Relation(rel)) => {
+                self.osm_file.relations.insert(rel.id, rel);
+            }
+            None => (),
+        }
+    }
+}
+
+impl<R: BufRead> XmlReader<R> {
+    pub fn new(reader: R) -> Self {
+        XmlReader {
+            reader,
+            filter: None,
+            with_metadata: false,
+        }
+    }
+
+    /// Only keeps the elements selected by `filter` in memory.
+    pub fn with_filter(mut self, filter: LoadFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Also reads the metadata of every element (see [`OsmElementInfo`]).
+    pub fn with_metadata(mut self, with_metadata: bool) -> Self {
+        self.with_metadata = with_metadata;
+        self
+    }
+
+    pub fn read(mut self) -> Result<OsmFile, std::io::Error> {
+        let filter = self.filter.take();
+        let mut osm_file = self.parse(false)?.osm_file;
+        if let Some(filter) = filter {
+            filter.apply(&mut osm_file);
+        }
+        Ok(osm_file)
+    }
+
+    /// Reads an OsmChange file (`.osc`).
+    pub fn read_change(self) -> Result<OsmChange, std::io::Error> {
+        let state = self.parse(true)?;
+        Ok(OsmChange {
+            string_table: state.osm_file.string_table,
+            changes: state.changes.unwrap_or_default(),
+        })
+    }
+
+    fn parse(self, is_change: bool) -> Result<XmlState, std::io::Error> {
+        let mut reader = quick_xml::Reader::from_reader(self.reader);
+        reader.config_mut().trim_text(true);
+        let mut state = XmlState {
+            osm_file: OsmFile::default(),
+            current: None,
+            with_metadata: self.with_metadata,
+            changes: is_change.then(Vec::new),
+            action: None,
+        };
+        let mut buf = Vec::new();
+        loop {
+            let res = match reader.read_event_into(&mut buf) {
+                Ok(Event::Start(e)) => state.start(&e, false),
+                Ok(Event::Empty(e)) => state.start(&e, true),
+                Ok(Event::End(e)) => {
+                    match e.name().as_ref() {
+                        b"node" | b"way" | b"relation" => state.finish(),
+                        b"create" | b"modify" | b"delete" => state.action = None,
+                        _ => (),
+                    }
+                    Ok(())
+                }
+                Ok(Event::Eof) => break,
+                Ok(_) => Ok(()),
+                Err(e) => Err(invalid_data(e)),
+            };
+            if let Err(e) = res {
+                return Err(invalid_data(format!(
+                    "Invalid OSM XML at byte {}: {}",
+                    reader.buffer_position(),
+                    e
+                )));
+            }
+            buf.clear();
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::OsmFileElement;
+    use super::*;
+    use std::collections::HashSet;
+
+    const SAMPLE: &str = include_str!("../../tests/data/sample.osm");
+
+    fn read(filter: Option<LoadFilter>) -> OsmFile {
+        let mut reader = XmlReader::new(SAMPLE.as_bytes()).with_metadata(true);
+        if let Some(filter) = filter {
+            reader = reader.with_filter(filter);
+        }
+        reader.read().unwrap()
+    }
+
+    fn ids<'a, T: 'a>(elements: impl Iterator<Item = OsmFileElement<'a, T>>) -> Vec<i64> {
+        let mut ids = elements.map(|el| el.id()).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn reads_elements() {
+        let osm_file = read(None);
+        assert_eq!(ids(osm_file.nodes()), [1, 2, 3, 4, 5, 6, 9]);
+        assert_eq!(ids(osm_file.ways()), [10, 11, 12, 13]);
+        assert_eq!(ids(osm_file.relations()), [20, 21]);
+
+        let node = osm_file.get_node(1).unwrap();
+        assert_eq!(node.data().lat, 47.379);
+        assert_eq!(node.data().lon, 8.538);
+        assert_eq!(node.get_tag_value("name").unwrap(), "Zürich HB");
+        let info = node.info().unwrap();
+        assert_eq!(info.version, 3);
+        assert_eq!(info.timestamp, parse_timestamp("2024-01-31T12:00:00Z"));
+        assert_eq!(info.changeset, Some(100));
+        assert_eq!(info.uid, Some(42));
+        assert_eq!(node.user().unwrap(), "alice");
+
+        assert_eq!(osm_file.get_way(12).unwrap().data().refs, [3, 4, 5]);
+        let rel = osm_file.get_relation(20).unwrap();
+        let members = &rel.data().members;
+        assert_eq!(members.len(), 2);
+        assert!(members[1].0 == OsmRelationMemberType::Way);
+        assert_eq!(members[1].1.ref_id, 11);
+        assert_eq!(osm_file.get_string(members[1].1.role_sid).unwrap(), "inner");
+    }
+
+    #[test]
+    fn reads_header() {
+        let osm_file = read(None);
+        let header = osm_file.header();
+        assert_eq!(header.writing_program.as_deref(), Some("hand-written"));
+        let bbox = header.bbox.unwrap();
+        assert_eq!((bbox.min_lat, bbox.max_lon), (47.37, 8.55));
+        assert_eq!(
+            header.replication_timestamp,
+            parse_timestamp("2024-01-31T12:00:00Z")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_xml() {
+        let xml = r#"<osm><node id="1" lat="x" lon="8"/></osm>"#;
+        assert!(XmlReader::new(xml.as_bytes()).read().is_err());
+        let xml = r#"<osm><node id="1" lat="47" lon="8"></way></osm>"#;
+        assert!(XmlReader::new(xml.as_bytes()).read().is_err());
+    }
+
+    #[test]
+    fn filters_by_bbox() {
+        let osm_file = read(Some(LoadFilter {
+            bbox: Some(BBox {
+                min_lat: 47.375,
+                min_lon: 8.535,
+                max_lat: 47.385,
+                max_lon: 8.545,
+            }),
+            tag_keys: None,
+        }));
+        // Way 12 is completed with node 5, multipolygon 20 with way 11
+        assert_eq!(ids(osm_file.nodes()), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(ids(osm_file.ways()), [10, 11, 12]);
+        assert_eq!(ids(osm_file.relations()), [20, 21]);
+    }
+
+    #[test]
+    fn filters_by_tag_keys() {
+        let osm_file = read(Some(LoadFilter {
+            bbox: None,
+            tag_keys: Some(HashSet::from(["natural".to_string()])),
+        }));
+        assert_eq!(ids(osm_file.nodes()), [3, 4, 5, 6]);
+        assert_eq!(ids(osm_file.ways()), [11, 12]);
+        assert_eq!(ids(osm_file.relations()), [20]);
+    }
+}
@@ -2,13 +2,17 @@ use crate::{
     osmpbf,
     style_file::{
         self,
-        ast::{self, Command, Filter, FilterExpr, FilterType},
+        ast::{
+            self, Command, DedupKey, Filter, FilterExpr, FilterType, InfoField, Measure, SortKey,
+            SortOrder,
+        },
     },
 };
 use rand::Rng;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
 enum OSMElementType {
     Node,
     Way,
@@ -58,24 +62,290 @@ impl OSMPaintObj {
     }
 }
 
-fn wildcard_match(str: &str, match_str: &str) -> bool {
-    if match_str == "*" {
-        return true;
+/// Returns whether `str` matches `pattern`, in which `*` stands for any
+/// sequence of characters.
+fn wildcard_match(str: &str, pattern: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = str.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let Some(mut last) = parts.next() else {
+        return rest.is_empty();
+    };
+    // Every part but the last is matched as early as possible, leaving the
+    // most room for the parts after it
+    for part in parts {
+        match rest.find(last) {
+            Some(idx) => rest = &rest[idx + last.len()..],
+            None => return false,
+        }
+        last = part;
+    }
+    rest.ends_with(last)
+}
+
+/// Factors that convert the units used in OSM tag values to km/h, meters
+/// and tonnes.
+const UNITS: &[(&str, f64)] = &[
+    ("km/h", 1.0),
+    ("kmh", 1.0),
+    ("kph", 1.0),
+    ("mph", 1.609344),
+    ("knots", 1.852),
+    ("mm", 0.001),
+    ("cm", 0.01),
+    ("m", 1.0),
+    ("km", 1000.0),
+    ("mi", 1609.344),
+    ("nmi", 1852.0),
+    ("ft", 0.3048),
+    ("'", 0.3048),
+    ("in", 0.0254),
+    ("\"", 0.0254),
+    ("kg", 0.001),
+    ("t", 1.0),
+    ("lbs", 0.00045359237),
+    ("st", 0.90718474),
+];
+
+/// Splits a value into its leading number and the rest.
+fn split_number(value: &str) -> Option<(f64, &str)> {
+    let end = value
+        .char_indices()
+        .find(|(i, c)| !(c.is_ascii_digit() || *c == '.' || (*i == 0 && *c == '-')))
+        .map_or(value.len(), |(i, _)| i);
+    let number = value[..end].parse().ok()?;
+    Some((number, value[end..].trim_start()))
+}
+
+/// Parses a numeric tag value like `50`, `30 mph`, `3.5 t` or `6'6"`,
+/// converting it to km/h, meters or tonnes. Of multiple values separated
+/// by `;`, the first one is used.
+fn osm_number(value: &str) -> Option<f64> {
+    let (number, unit) = split_number(value.split(';').next()?.trim())?;
+    if unit.is_empty() {
+        return Some(number);
+    }
+    if let Some((_, factor)) = UNITS.iter().find(|(name, _)| *name == unit) {
+        return Some(number * factor);
+    }
+    // Feet followed by inches
+    let (inches, unit) = split_number(unit.strip_prefix('\'')?.trim_start())?;
+    (unit == "\"").then_some(number * 0.3048 + inches * 0.0254)
+}
+
+fn info_value<T>(el: &osmpbf::OsmFileElement<T>, field: &InfoField) -> Option<String> {
+    let info = el.info()?;
+    match field {
+        InfoField::Version => Some(info.version.to_string()),
+        InfoField::Timestamp => info.timestamp.map(osmpbf::format_timestamp),
+        InfoField::Changeset => info.changeset.map(|c| c.to_string()),
+        InfoField::Uid => info.uid.map(|u| u.to_string()),
+        InfoField::User => el.user().cloned(),
+    }
+}
+
+fn tag_value(osm_file: &osmpbf::OsmFile, paint_obj: &OSMPaintObj, key: &str) -> Option<String> {
+    match paint_obj.ty {
+        OSMElementType::Node => osm_file
+            .get_node(paint_obj.id)
+            .and_then(|node| node.get_tag_value(key).cloned()),
+        OSMElementType::Way => osm_file
+            .get_way(paint_obj.id)
+            .and_then(|way| way.get_tag_value(key).cloned()),
+        OSMElementType::Relation => osm_file
+            .get_relation(paint_obj.id)
+            .and_then(|rel| rel.get_tag_value(key).cloned()),
+    }
+}
+
+/// Colors named in `colour` tags, besides `#RRGGBB` and `#RGB`.
+const NAMED_COLORS: &[(&str, &str)] = &[
+    ("black", "#000000"),
+    ("white", "#ffffff"),
+    ("grey", "#808080"),
+    ("gray", "#808080"),
+    ("silver", "#c0c0c0"),
+    ("red", "#ff0000"),
+    ("maroon", "#800000"),
+    ("orange", "#ffa500"),
+    ("yellow", "#ffff00"),
+    ("gold", "#ffd700"),
+    ("olive", "#808000"),
+    ("lime", "#00ff00"),
+    ("green", "#008000"),
+    ("teal", "#008080"),
+    ("cyan", "#00ffff"),
+    ("aqua", "#00ffff"),
+    ("blue", "#0000ff"),
+    ("navy", "#000080"),
+    ("purple", "#800080"),
+    ("violet", "#ee82ee"),
+    ("magenta", "#ff00ff"),
+    ("fuchsia", "#ff00ff"),
+    ("pink", "#ffc0cb"),
+    ("brown", "#a52a2a"),
+];
+
+/// Parses a color tag value like `#E2001A`, `#f00` or `red`.
+fn osm_color(value: &str) -> Option<ast::Color> {
+    let value = value.trim().to_lowercase();
+    if let Some((_, hex)) = NAMED_COLORS.iter().find(|(name, _)| *name == value) {
+        return ast::Color::new(hex).ok();
+    }
+    let digits = value.strip_prefix('#')?;
+    if digits.len() == 3 {
+        let doubled: String = digits.chars().flat_map(|c| [c, c]).collect();
+        return ast::Color::new(&doubled).ok();
+    }
+    ast::Color::new(digits).ok()
+}
+
+/// Color of a draw function.
+enum Paint {
+    Color(ast::Color),
+    Random,
+}
+
+fn paint_arg(arg: &ast::FuncArg) -> Option<Paint> {
+    match arg {
+        ast::FuncArg::Color(color) => Some(Paint::Color(color.clone())),
+        ast::FuncArg::RandomColor => Some(Paint::Random),
+        ast::FuncArg::String(value) => osm_color(value).map(Paint::Color),
+        _ => None,
+    }
+}
+
+fn number_arg(arg: &ast::FuncArg) -> Option<f64> {
+    match arg {
+        ast::FuncArg::Float(n) => Some(*n),
+        ast::FuncArg::String(value) => osm_number(value),
+        _ => None,
+    }
+}
+
+/// Evaluates an argument of a draw function for an element. `convert`
+/// converts the resulting value, which for tag values is a string, or a
+/// number if a factor is given.
+fn eval_arg<T>(
+    osm_file: &osmpbf::OsmFile,
+    paint_obj: &OSMPaintObj,
+    arg: &ast::FuncArg,
+    convert: fn(&ast::FuncArg) -> Option<T>,
+) -> Option<T> {
+    match arg {
+        ast::FuncArg::Tag {
+            key,
+            factor,
+            fallback,
+        } => tag_value(osm_file, paint_obj, key)
+            .and_then(|value| match factor {
+                Some(factor) => osm_number(&value).map(|n| ast::FuncArg::Float(n * factor)),
+                None => Some(ast::FuncArg::String(value)),
+            })
+            .and_then(|value| convert(&value))
+            .or_else(|| {
+                let fallback = fallback.as_deref()?;
+                eval_arg(osm_file, paint_obj, fallback, convert)
+            }),
+        ast::FuncArg::Match {
+            key,
+            cases,
+            fallback,
+        } => {
+            let value = tag_value(osm_file, paint_obj, key);
+            cases
+                .iter()
+                .find(|(_, case, _)| value.as_ref() == Some(case))
+                .map(|(_, _, arg)| arg)
+                .or(fallback.as_ref().map(|(_, arg)| arg.as_ref()))
+                .and_then(|arg| eval_arg(osm_file, paint_obj, arg, convert))
+        }
+        arg => convert(arg),
+    }
+}
+
+/// Returns the text of a label. Tags the element lacks are left out along
+/// with the whitespace after them, or before them if nothing follows, and
+/// there is no text if it lacks all of them.
+fn label_text(
+    osm_file: &osmpbf::OsmFile,
+    paint_obj: &OSMPaintObj,
+    parts: &[ast::LabelPart],
+) -> Option<String> {
+    let mut text = String::new();
+    let mut has_tag = false;
+    let mut after_missing = false;
+    for part in parts {
+        match part {
+            ast::LabelPart::Text(part) if after_missing => {
+                text += part.trim_start();
+                after_missing = part.trim_start().is_empty();
+            }
+            ast::LabelPart::Text(part) => text += part,
+            ast::LabelPart::Tag(key) => match tag_value(osm_file, paint_obj, key) {
+                Some(value) => {
+                    text += &value;
+                    has_tag = true;
+                    after_missing = false;
+                }
+                None => after_missing = true,
+            },
+        }
+    }
+    if !has_tag {
+        return None;
     }
-    // Performance optimization: only use Regex if needed
-    if !match_str
-        .chars()
-        .any(|c| matches!(c, '*' | '?' | '|' | '[' | ']' | '{' | '}' | '\\'))
-    {
-        return str == match_str;
+    if after_missing {
+        text.truncate(text.trim_end().len());
+    }
+    Some(text)
+}
+
+/// Returns whether the element has the tag `key` and its value satisfies `f`.
+fn match_tag(
+    osm_file: &osmpbf::OsmFile,
+    paint_obj: &OSMPaintObj,
+    key: &str,
+    f: impl Fn(&str) -> bool,
+) -> bool {
+    match paint_obj.ty {
+        OSMElementType::Node => osm_file
+            .get_node(paint_obj.id)
+            .is_some_and(|node| node.get_tag_value(key).is_some_and(|val| f(val))),
+        OSMElementType::Way => osm_file
+            .get_way(paint_obj.id)
+            .is_some_and(|way| way.get_tag_value(key).is_some_and(|val| f(val))),
+        OSMElementType::Relation => osm_file
+            .get_relation(paint_obj.id)
+            .is_some_and(|rel| rel.get_tag_value(key).is_some_and(|val| f(val))),
+    }
+}
+
+fn measure_element(osm_file: &osmpbf::OsmFile, paint_obj: &OSMPaintObj, measure: &Measure) -> f64 {
+    let ty = member_type(paint_obj.ty);
+    match measure {
+        Measure::Area => osm_file.area(ty, paint_obj.id),
+        Measure::Length => osm_file.length(ty, paint_obj.id),
+        Measure::Nodes => match paint_obj.ty {
+            OSMElementType::Way => osm_file
+                .get_way(paint_obj.id)
+                .map_or(0, |way| way.data().refs.len()) as f64,
+            _ => 0.0,
+        },
+        Measure::Members => match paint_obj.ty {
+            OSMElementType::Relation => osm_file
+                .get_relation(paint_obj.id)
+                .map_or(0, |rel| rel.data().members.len())
+                as f64,
+            _ => 0.0,
+        },
     }
-    let regex = match_str.replace("*", ".*");
-    let re = regex::Regex::new(&format!("^{}$", regex)).unwrap();
-    re.is_match(str)
 }
 
 fn match_predicate(
     osm_file: &osmpbf::OsmFile,
+    view: &osmpbf::BBox,
     paint_obj: &OSMPaintObj,
     filter: &style_file::ast::FilterExpr,
 ) -> bool {
@@ -95,49 +365,62 @@ fn match_predicate(
             }
             return false;
         }
-        FilterExpr::Filter(Filter::Match(key, value)) => match paint_obj.ty {
-            OSMElementType::Node => {
-                if let Some(node) = osm_file.get_node(paint_obj.id) {
-                    if let Some(val) = node.get_tag_value(&key) {
-                        return wildcard_match(val, value);
-                    }
-                }
-                return false;
-            }
-            OSMElementType::Way => {
-                if let Some(way) = osm_file.get_way(paint_obj.id) {
-                    if let Some(val) = way.get_tag_value(&key) {
-                        return wildcard_match(val, value);
-                    }
-                }
-                return false;
-            }
-            OSMElementType::Relation => {
-                if let Some(rel) = osm_file.get_relation(paint_obj.id) {
-                    if let Some(val) = rel.get_tag_value(&key) {
-                        return wildcard_match(val, value);
-                    }
-                }
-                return false;
-            }
-        },
+        FilterExpr::Filter(Filter::Match(key, value)) => {
+            match_tag(osm_file, paint_obj, key, |val| wildcard_match(val, value))
+        }
+        FilterExpr::Filter(Filter::Compare(key, op, value)) => {
+            match_tag(osm_file, paint_obj, key, |val| {
+                osm_number(val).is_some_and(|number| op.compare(number, *value))
+            })
+        }
+        FilterExpr::Filter(Filter::Regex(key, re, _)) => {
+            match_tag(osm_file, paint_obj, key, |val| re.is_match(val))
+        }
+        FilterExpr::Filter(Filter::Has(key)) => match_tag(osm_file, paint_obj, key, |_| true),
+        FilterExpr::Filter(Filter::Lacks(key)) => !match_tag(osm_file, paint_obj, key, |_| true),
+        FilterExpr::Filter(Filter::IsClosed) => {
+            osm_file.is_closed(member_type(paint_obj.ty), paint_obj.id)
+        }
+        FilterExpr::Filter(Filter::WithinView) => osm_file
+            .bbox(member_type(paint_obj.ty), paint_obj.id)
+            .is_some_and(|bbox| {
+                view.contains(bbox.min_lat, bbox.min_lon)
+                    && view.contains(bbox.max_lat, bbox.max_lon)
+            }),
+        FilterExpr::Filter(Filter::Measure(measure, op, value)) => {
+            op.compare(measure_element(osm_file, paint_obj, measure), *value)
+        }
+        FilterExpr::Filter(Filter::MatchInfo(field, value)) => {
+            let info = match paint_obj.ty {
+                OSMElementType::Node => osm_file
+                    .get_node(paint_obj.id)
+                    .and_then(|node| info_value(&node, field)),
+                OSMElementType::Way => osm_file
+                    .get_way(paint_obj.id)
+                    .and_then(|way| info_value(&way, field)),
+                OSMElementType::Relation => osm_file
+                    .get_relation(paint_obj.id)
+                    .and_then(|rel| info_value(&rel, field)),
+            };
+            info.is_some_and(|info| wildcard_match(&info, value))
+        }
         FilterExpr::And(left, right) => {
-            return match_predicate(osm_file, paint_obj, left)
-                && match_predicate(osm_file, paint_obj, right);
+            return match_predicate(osm_file, view, paint_obj, left)
+                && match_predicate(osm_file, view, paint_obj, right);
         }
         FilterExpr::Or(left, right) => {
-            return match_predicate(osm_file, paint_obj, left)
-                || match_predicate(osm_file, paint_obj, right);
+            return match_predicate(osm_file, view, paint_obj, left)
+                || match_predicate(osm_file, view, paint_obj, right);
         }
         FilterExpr::Not(expr) => {
-            return !match_predicate(osm_file, paint_obj, expr);
+            return !match_predicate(osm_file, view, paint_obj, expr);
         }
     }
 }
 
 fn mercator_projection(lat: f64, lon: f64) -> (f64, f64) {
-    let x = (lon / 1e7).to_radians();
-    let sin_lat = (lat / 1e7).to_radians().sin();
+    let x = lon.to_radians();
+    let sin_lat = lat.to_radians().sin();
     let y = ((1.0 + sin_lat) / (1.0 - sin_lat)).ln() / 2.0;
     (
         x / (2.0 * std::f64::consts::PI),
@@ -145,6 +428,387 @@ fn mercator_projection(lat: f64, lon: f64) -> (f64, f64) {
     )
 }
 
+fn inverse_mercator_projection(x: f64, y: f64) -> (f64, f64) {
+    let lon = (x * 2.0 * std::f64::consts::PI).to_degrees();
+    let lat = (y * 2.0 * std::f64::consts::PI).sinh().atan().to_degrees();
+    (lat, lon)
+}
+
+/// Pixels per unit of mercator space at the map center.
+fn ground_dist_scale(meta: &ast::Meta) -> f64 {
+    const EARTH_CIRCUMFERENCE: f64 = 40_075_000_000.0; // [mm]
+    let center_lat = meta.center.0;
+    let pixels_per_world_mm = meta.dpi / 25.4 / meta.scale;
+    EARTH_CIRCUMFERENCE * center_lat.to_radians().cos() * pixels_per_world_mm
+}
+
+/// Returns the area shown on the map, grown by `margin` times its size on
+/// every side.
+pub fn view_bbox(meta: &ast::Meta, margin: f64) -> osmpbf::BBox {
+    let (ox, oy) = mercator_projection(meta.center.0, meta.center.1);
+    let ground_dist_scale = ground_dist_scale(meta);
+    let half_w = meta.width_pixels() as f64 / 2.0 / ground_dist_scale;
+    let half_h = meta.height_pixels() as f64 / 2.0 / ground_dist_scale;
+    let (min_lat, min_lon) = inverse_mercator_projection(ox - half_w, oy - half_h);
+    let (max_lat, max_lon) = inverse_mercator_projection(ox + half_w, oy + half_h);
+    osmpbf::BBox {
+        min_lat,
+        min_lon,
+        max_lat,
+        max_lon,
+    }
+    .with_margin(margin)
+}
+
+fn member_type(ty: OSMElementType) -> osmpbf::OsmRelationMemberType {
+    match ty {
+        OSMElementType::Node => osmpbf::OsmRelationMemberType::Node,
+        OSMElementType::Way => osmpbf::OsmRelationMemberType::Way,
+        OSMElementType::Relation => osmpbf::OsmRelationMemberType::Relation,
+    }
+}
+
+fn all_elements(osm_file: &osmpbf::OsmFile) -> Vec<OSMPaintObj> {
+    let mut selection = vec![];
+    for node in osm_file.nodes() {
+        selection.push(OSMPaintObj::new_node(node.id()));
+    }
+    for way in osm_file.ways() {
+        selection.push(OSMPaintObj::new_way(way.id()));
+    }
+    for rel in osm_file.relations() {
+        selection.push(OSMPaintObj::new_relation(rel.id()));
+    }
+    selection.sort_unstable_by_key(|el| (el.ty, el.id));
+    selection
+}
+
+/// Margin around the map within which elements are still drawn, so that
+/// dots and labels of elements just outside of it are not cut off.
+const VIEW_MARGIN: f64 = 0.05;
+
+/// Returns the elements that may be visible on the map, sorted by type and
+/// id.
+fn view_elements(osm_file: &osmpbf::OsmFile, meta: &ast::Meta) -> Vec<OSMPaintObj> {
+    let mut selection: Vec<_> = osm_file
+        .query_bbox(&view_bbox(meta, VIEW_MARGIN))
+        .map(|(ty, id)| match ty {
+            osmpbf::OsmRelationMemberType::Node => OSMPaintObj::new_node(id),
+            osmpbf::OsmRelationMemberType::Way => OSMPaintObj::new_way(id),
+            osmpbf::OsmRelationMemberType::Relation => OSMPaintObj::new_relation(id),
+        })
+        .collect();
+    selection.sort_unstable_by_key(|el| (el.ty, el.id));
+    selection
+}
+
+/// Value by which an element is sorted. In ascending order numbers come
+/// before text, descending order reverses this. Elements without a value
+/// come last in either order.
+#[derive(PartialEq, PartialOrd)]
+enum SortValue {
+    Number(f64),
+    Text(String),
+    Missing,
+}
+
+fn sort_value(osm_file: &osmpbf::OsmFile, paint_obj: &OSMPaintObj, key: &SortKey) -> SortValue {
+    let text = match key {
+        SortKey::Tag(key) => tag_value(osm_file, paint_obj, key),
+        SortKey::Measure(measure) => {
+            return SortValue::Number(measure_element(osm_file, paint_obj, measure))
+        }
+        SortKey::Id => return SortValue::Number(paint_obj.id as f64),
+    };
+    match text {
+        Some(text) => match osm_number(&text) {
+            Some(number) => SortValue::Number(number),
+            None => SortValue::Text(text),
+        },
+        None => SortValue::Missing,
+    }
+}
+
+/// Sorts the selection stably by `key`.
+fn sort_selection(
+    osm_file: &osmpbf::OsmFile,
+    selection: &mut Vec<OSMPaintObj>,
+    key: &SortKey,
+    order: &SortOrder,
+) {
+    let mut keyed: Vec<_> = selection
+        .drain(..)
+        .map(|el| (sort_value(osm_file, &el, key), el))
+        .collect();
+    keyed.sort_by(|(a, _), (b, _)| {
+        let ordering = a.partial_cmp(b).unwrap_or(Ordering::Equal);
+        match (order, a, b) {
+            (_, SortValue::Missing, _) | (_, _, SortValue::Missing) => ordering,
+            (SortOrder::Ascending, ..) => ordering,
+            (SortOrder::Descending, ..) => ordering.reverse(),
+        }
+    });
+    selection.extend(keyed.into_iter().map(|(_, el)| el));
+}
+
+/// Returns the position of an element on the map in mm, relative to the
+/// origin of the projection.
+fn map_position(
+    osm_file: &osmpbf::OsmFile,
+    meta: &ast::Meta,
+    paint_obj: &OSMPaintObj,
+) -> Option<(f64, f64)> {
+    let (lat, lon) = match paint_obj.ty {
+        OSMElementType::Node => {
+            let node = osm_file.get_node(paint_obj.id)?;
+            (node.data().lat, node.data().lon)
+        }
+        OSMElementType::Way => {
+            // Center of mass, like the dot of a way
+            let way = osm_file.get_way(paint_obj.id)?;
+            let nodes: Vec<_> = way
+                .data()
+                .refs
+                .iter()
+                .filter_map(|id| Some(*osm_file.get_node(*id)?.data()))
+                .collect();
+            if nodes.is_empty() {
+                return None;
+            }
+            let n = nodes.len() as f64;
+            let lat = nodes.iter().map(|node| node.lat).sum::<f64>() / n;
+            let lon = nodes.iter().map(|node| node.lon).sum::<f64>() / n;
+            (lat, lon)
+        }
+        OSMElementType::Relation => {
+            let bbox = osm_file.bbox(osmpbf::OsmRelationMemberType::Relation, paint_obj.id)?;
+            (
+                (bbox.min_lat + bbox.max_lat) / 2.0,
+                (bbox.min_lon + bbox.max_lon) / 2.0,
+            )
+        }
+    };
+    let (x, y) = mercator_projection(lat, lon);
+    let mm = ground_dist_scale(meta) / (meta.dpi / 25.4);
+    Some((x * mm, y * mm))
+}
+
+/// Keys of the elements kept by a `@dedup` command so far.
+#[derive(Default)]
+struct Seen {
+    ids: HashSet<(OSMElementType, i64)>,
+    values: HashSet<String>,
+    /// Positions on the map, in cells of the dedup distance
+    positions: HashMap<(i64, i64), Vec<(f64, f64)>>,
+}
+
+impl Seen {
+    /// Records the key of `paint_obj` and returns whether it was new.
+    /// Elements without a value for the key are always kept.
+    fn insert(
+        &mut self,
+        osm_file: &osmpbf::OsmFile,
+        meta: &ast::Meta,
+        paint_obj: &OSMPaintObj,
+        key: &DedupKey,
+    ) -> bool {
+        match key {
+            DedupKey::Id => self.ids.insert((paint_obj.ty, paint_obj.id)),
+            DedupKey::Tag(key) => {
+                tag_value(osm_file, paint_obj, key).is_none_or(|value| self.values.insert(value))
+            }
+            DedupKey::Distance(distance) => {
+                let Some((x, y)) = map_position(osm_file, meta, paint_obj) else {
+                    return true;
+                };
+                let cell = ((x / distance).floor() as i64, (y / distance).floor() as i64);
+                for dx in -1..=1 {
+                    for dy in -1..=1 {
+                        let neighbor = (cell.0.saturating_add(dx), cell.1.saturating_add(dy));
+                        let close = self.positions.get(&neighbor).is_some_and(|positions| {
+                            positions
+                                .iter()
+                                .any(|(px, py)| (px - x).hypot(py - y) < *distance)
+                        });
+                        if close {
+                            return false;
+                        }
+                    }
+                }
+                self.positions.entry(cell).or_default().push((x, y));
+                true
+            }
+        }
+    }
+}
+
+/// State of the commands of a layer that is shared by all of their
+/// invocations, for example in every relation visited by `@sub`.
+struct LayerState<'a> {
+    osm_file: &'a osmpbf::OsmFile,
+    meta: &'a ast::Meta,
+    /// Map area without margin
+    view: osmpbf::BBox,
+    /// Keys seen by every `@dedup` command, by its number
+    dedup: HashMap<usize, Seen>,
+}
+
+impl<'a> LayerState<'a> {
+    fn new(osm_file: &'a osmpbf::OsmFile, meta: &'a ast::Meta) -> Self {
+        LayerState {
+            osm_file,
+            meta,
+            view: view_bbox(meta, 0.0),
+            dedup: HashMap::new(),
+        }
+    }
+}
+
+/// Applies the commands of a layer to `selection`, calling `draw` with the
+/// current selection for every draw function.
+fn walk_commands(
+    state: &mut LayerState,
+    selection: &mut Vec<OSMPaintObj>,
+    commands: &Vec<(ast::Location, Command)>,
+    draw: &mut impl FnMut(&Vec<OSMPaintObj>, &str, &ast::FuncArgs),
+) {
+    let osm_file = state.osm_file;
+    let view = state.view;
+    for (_, command) in commands {
+        match command {
+            Command::Filter(FilterType::Keep, expr) => {
+                log::debug!("Filter keep {:?}", expr);
+                selection.retain(|el| match_predicate(osm_file, &view, el, expr));
+            }
+            Command::Filter(FilterType::Remove, expr) => {
+                log::debug!("Filter remove {:?}", expr);
+                selection.retain(|el| !match_predicate(osm_file, &view, el, expr));
+            }
+            Command::Take(n) => {
+                selection.truncate(*n);
+            }
+            Command::Sort(key, order) => sort_selection(osm_file, selection, key, order),
+            Command::Dedup(key, id) => {
+                let seen = state.dedup.entry(*id).or_default();
+                selection.retain(|el| seen.insert(osm_file, state.meta, el, key));
+            }
+            Command::DrawFunc { ty, args } => draw(selection, ty, args),
+            Command::OffsetText { key, offsets } => {
+                for el in selection.iter_mut() {
+                    let val = match el.ty {
+                        OSMElementType::Node => osm_file
+                            .get_node(el.id)
+                            .unwrap()
+                            .get_tag_value(&key)
+                            .unwrap_or(&"".to_string())
+                            .clone(),
+                        OSMElementType::Way => osm_file
+                            .get_way(el.id)
+                            .unwrap()
+                            .get_tag_value(&key)
+                            .unwrap_or(&"".to_string())
+                            .clone(),
+                        OSMElementType::Relation => osm_file
+                            .get_relation(el.id)
+                            .unwrap()
+                            .get_tag_value(&key)
+                            .unwrap_or(&"".to_string())
+                            .clone(),
+                    };
+                    if let Some((_, patch)) = offsets.get(&val) {
+                        el.text_patch = patch.clone();
+                    }
+                }
+            }
+            Command::Sub(cmds) => {
+                for el in selection.iter() {
+                    if el.ty == OSMElementType::Relation {
+                        let rel = osm_file.get_relation(el.id).unwrap();
+                        let mut sub_selection = vec![];
+                        for (sub_ty, sub_id) in &rel.data().members {
+                            let role_str = osm_file.get_string(sub_id.role_sid).unwrap();
+                            let (ty, loaded) = match sub_ty {
+                                osmpbf::OsmRelationMemberType::Node => (
+                                    OSMElementType::Node,
+                                    osm_file.get_node(sub_id.ref_id).is_some(),
+                                ),
+                                osmpbf::OsmRelationMemberType::Way => (
+                                    OSMElementType::Way,
+                                    osm_file.get_way(sub_id.ref_id).is_some(),
+                                ),
+                                osmpbf::OsmRelationMemberType::Relation => (
+                                    OSMElementType::Relation,
+                                    osm_file.get_relation(sub_id.ref_id).is_some(),
+                                ),
+                            };
+                            // Members outside of the loaded area are left out
+                            if !loaded {
+                                continue;
+                            }
+                            sub_selection.push(OSMPaintObj {
+                                ty,
+                                role: Some(role_str.clone()),
+                                id: sub_id.ref_id,
+                                text_patch: ast::TextPatch {
+                                    offset: None,
+                                    rename: None,
+                                    scale: None,
+                                },
+                            });
+                        }
+                        walk_commands(state, &mut sub_selection, cmds, draw);
+                    }
+                }
+            }
+            // Replaced by the defined block when the style is read
+            Command::Use(_) => {}
+            // Applied when the style is read
+            Command::Scale(..) => {}
+            Command::Parents(cmds) => {
+                for el in selection.iter() {
+                    let mut parent_selection = vec![];
+                    if el.ty == OSMElementType::Node {
+                        for way_id in osm_file.parent_ways(el.id) {
+                            parent_selection.push(OSMPaintObj::new_way(*way_id));
+                        }
+                    }
+                    for parent in osm_file.parent_relations(member_type(el.ty), el.id) {
+                        let role_str = osm_file.get_string(parent.role_sid).unwrap();
+                        parent_selection.push(OSMPaintObj {
+                            role: Some(role_str.clone()),
+                            ..OSMPaintObj::new_relation(parent.id)
+                        });
+                    }
+                    walk_commands(state, &mut parent_selection, cmds, draw);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the elements that are drawn by at least one of the layers.
+pub fn select_elements(osm_file: &osmpbf::OsmFile, style: &ast::Style) -> osmpbf::ElementIds {
+    let mut ids = osmpbf::ElementIds::default();
+    for layer in &style.layers {
+        let mut selection = all_elements(osm_file);
+        walk_commands(
+            &mut LayerState::new(osm_file, &style.meta),
+            &mut selection,
+            &layer.commands,
+            &mut |selection, _, _| {
+                for el in selection {
+                    match el.ty {
+                        OSMElementType::Node => ids.nodes.insert(el.id),
+                        OSMElementType::Way => ids.ways.insert(el.id),
+                        OSMElementType::Relation => ids.relations.insert(el.id),
+                    };
+                }
+            },
+        );
+    }
+    ids
+}
+
 impl<'a> Renderer<'a> {
     pub fn new(meta: &'a ast::Meta, cr: &'a cairo::Context, osm_file: &'a osmpbf::OsmFile) -> Self {
         Renderer {
@@ -162,15 +826,11 @@ impl<'a> Renderer<'a> {
         self.cr.set_line_join(cairo::LineJoin::Round);
     }
 
-    fn world_to_screen(&self, lat: i64, lon: i64) -> (f64, f64) {
-        let (x, y) = mercator_projection(lat as f64, lon as f64);
+    fn world_to_screen(&self, lat: f64, lon: f64) -> (f64, f64) {
+        let (x, y) = mercator_projection(lat, lon);
         let (ox, oy) = mercator_projection(self.meta.center.0, self.meta.center.1);
         let mercator_space_coords = (x - ox, -(y - oy));
-        const EARTH_CIRCUMFERENCE: f64 = 40_075_000_000.0; // [mm]
-        let center_lat = self.meta.center.0 as f64 / 1e7;
-        let pixels_per_world_mm = self.meta.dpi / 25.4 / self.meta.scale;
-        let ground_dist_scale =
-            EARTH_CIRCUMFERENCE * center_lat.to_radians().cos() * pixels_per_world_mm;
+        let ground_dist_scale = ground_dist_scale(self.meta);
         let (w, h) = (
             self.meta.width_pixels() as f64,
             self.meta.height_pixels() as f64,
@@ -180,115 +840,33 @@ impl<'a> Renderer<'a> {
         (x, y)
     }
 
-    pub fn paint(&mut self, commands: &Vec<Command>) {
-        let mut selection = vec![];
-        for node in self.osm_file.nodes() {
-            selection.push(OSMPaintObj::new_node(node.id()));
-        }
-        for way in self.osm_file.ways() {
-            selection.push(OSMPaintObj::new_way(way.id()));
-        }
-        for rel in self.osm_file.relations() {
-            selection.push(OSMPaintObj::new_relation(rel.id()));
-        }
-        self.paint_list(&mut selection, commands);
-    }
-
-    fn paint_list(&mut self, selection: &mut Vec<OSMPaintObj>, commands: &Vec<Command>) {
-        for command in commands {
-            match command {
-                Command::Filter(FilterType::Keep, expr) => {
-                    println!("Filter keep {:?}", expr);
-                    selection.retain(|el| match_predicate(self.osm_file, el, expr));
-                }
-                Command::Filter(FilterType::Remove, expr) => {
-                    println!("Filter remove {:?}", expr);
-                    selection.retain(|el| !match_predicate(self.osm_file, el, expr));
-                }
-                Command::Take(n) => {
-                    selection.truncate(*n);
-                }
-                Command::DrawFunc { ty, args } => {
-                    println!("Draw {} elements", selection.len());
-                    match ty.as_str() {
-                        "Polyfill" => {
-                            self.polyfill(&selection, args);
-                        }
-                        "Outline" => {
-                            self.outline(&selection, args);
-                        }
-                        "Dot" => {
-                            self.dot(&selection, args);
-                        }
-                        "Text" => {
-                            self.text(&selection, args);
-                        }
-                        _ => {
-                            println!("Unknown draw function: {}", ty);
-                        }
+    pub fn paint(&mut self, commands: &Vec<(ast::Location, Command)>) {
+        let osm_file = self.osm_file;
+        let mut selection = view_elements(osm_file, self.meta);
+        walk_commands(
+            &mut LayerState::new(osm_file, self.meta),
+            &mut selection,
+            commands,
+            &mut |selection, ty, args| {
+                log::debug!("Draw {} elements with {}", selection.len(), ty);
+                match ty {
+                    "Polyfill" => {
+                        self.polyfill(selection, args);
                     }
-                }
-                Command::OffsetText { key, offsets } => {
-                    for el in selection.iter_mut() {
-                        let val = match el.ty {
-                            OSMElementType::Node => self
-                                .osm_file
-                                .get_node(el.id)
-                                .unwrap()
-                                .get_tag_value(&key)
-                                .unwrap_or(&"".to_string())
-                                .clone(),
-                            OSMElementType::Way => self
-                                .osm_file
-                                .get_way(el.id)
-                                .unwrap()
-                                .get_tag_value(&key)
-                                .unwrap_or(&"".to_string())
-                                .clone(),
-                            OSMElementType::Relation => self
-                                .osm_file
-                                .get_relation(el.id)
-                                .unwrap()
-                                .get_tag_value(&key)
-                                .unwrap_or(&"".to_string())
-                                .clone(),
-                        };
-                        if let Some(patch) = offsets.get(&val) {
-                            el.text_patch = patch.clone();
-                        }
+                    "Outline" => {
+                        self.outline(selection, args);
                     }
-                }
-                Command::Sub(cmds) => {
-                    for el in selection.iter() {
-                        if el.ty == OSMElementType::Relation {
-                            let rel = self.osm_file.get_relation(el.id).unwrap();
-                            let mut sub_selection = vec![];
-                            for (sub_ty, sub_id) in &rel.data().members {
-                                let role_str = self.osm_file.get_string(sub_id.role_sid).unwrap();
-                                let ty = match sub_ty {
-                                    osmpbf::OsmRelationMemberType::Node => OSMElementType::Node,
-                                    osmpbf::OsmRelationMemberType::Way => OSMElementType::Way,
-                                    osmpbf::OsmRelationMemberType::Relation => {
-                                        OSMElementType::Relation
-                                    }
-                                };
-                                sub_selection.push(OSMPaintObj {
-                                    ty,
-                                    role: Some(role_str.clone()),
-                                    id: sub_id.ref_id,
-                                    text_patch: ast::TextPatch {
-                                        offset: None,
-                                        rename: None,
-                                        scale: None,
-                                    },
-                                });
-                            }
-                            self.paint_list(&mut sub_selection, &cmds);
-                        }
+                    "Dot" => {
+                        self.dot(selection, args);
                     }
+                    "Text" => {
+                        self.text(selection, args);
+                    }
+                    // Rejected when the style is read
+                    _ => log::warn!("Unknown draw function {}, skipped", ty),
                 }
-            }
-        }
+            },
+        );
     }
 
     fn draw_way(&self, way: &osmpbf::OsmWayData, move_first: bool) {
@@ -306,58 +884,12 @@ impl<'a> Renderer<'a> {
         }
     }
 
-    fn relation_to_multipolygon(&self, rel: &osmpbf::OsmRelationData) -> Vec<Vec<i64>> {
-        let mut ways = vec![];
-        for point in &rel.members {
-            if point.0 != osmpbf::OsmRelationMemberType::Way {
-                continue;
-            }
-            if let Some(way) = self.osm_file.get_way(point.1.ref_id) {
-                ways.push(way);
-            }
-        }
-        let mut res_polygons = vec![];
-        while ways.len() > 0 {
-            let mut curr_ring = vec![];
-            let way0 = ways.remove(0);
-            curr_ring.extend(way0.data().refs.iter());
-            loop {
-                let last_node = curr_ring[curr_ring.len() - 1];
-                if curr_ring[0] == last_node {
-                    break;
-                }
-                let mut found = false;
-                for i in 0..ways.len() {
-                    if ways[i].data().refs[0] == last_node {
-                        curr_ring.extend(ways[i].data().refs.iter());
-                        ways.remove(i);
-                        found = true;
-                        break;
-                    }
-                    if ways[i].data().refs[ways[i].data().refs.len() - 1] == last_node {
-                        let mut refs = ways[i].data().refs.clone();
-                        refs.reverse();
-                        curr_ring.extend(refs);
-                        ways.remove(i);
-                        found = true;
-                        break;
-                    }
-                }
-                if !found {
-                    break;
-                }
-            }
-            res_polygons.push(curr_ring);
-        }
-        res_polygons
-    }
-
     fn draw_relation_ways(
         &self,
         rel: &osmpbf::OsmFileElement<osmpbf::OsmRelationData>,
         close_path: bool,
     ) -> bool {
-        let multipoly = self.relation_to_multipolygon(rel.data());
+        let multipoly = self.osm_file.relation_rings(rel.data());
         for poly in multipoly {
             let mut is_first = true;
             for point in &poly {
@@ -378,32 +910,45 @@ impl<'a> Renderer<'a> {
         true
     }
 
-    fn polyfill(&mut self, els: &Vec<OSMPaintObj>, args: &HashMap<String, ast::FuncArg>) {
-        let alpha = if let Some(ast::FuncArg::Float(alpha)) = args.get("alpha") {
-            *alpha
-        } else {
-            1.0
-        };
-        if let Some(ast::FuncArg::Color(ast::Color { r, g, b })) = args.get("color") {
-            self.cr.set_source_rgba(
-                *r as f64 / 255.0,
-                *g as f64 / 255.0,
-                *b as f64 / 255.0,
-                alpha,
-            );
+    fn arg<T>(
+        &self,
+        args: &ast::FuncArgs,
+        key: &str,
+        el: &OSMPaintObj,
+        convert: fn(&ast::FuncArg) -> Option<T>,
+    ) -> Option<T> {
+        args.get(key)
+            .and_then(|arg| eval_arg(self.osm_file, el, arg, convert))
+    }
+
+    /// Returns the color of a paint as red, green and blue from 0 to 1.
+    /// Random colors are light, to be usable as background.
+    fn rgb(&mut self, paint: Paint) -> (f64, f64, f64) {
+        match paint {
+            Paint::Color(ast::Color { r, g, b }) => {
+                (r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0)
+            }
+            Paint::Random => (
+                self.rng.random::<f64>() * 0.2 + 0.8,
+                self.rng.random::<f64>() * 0.2 + 0.8,
+                self.rng.random::<f64>() * 0.2 + 0.8,
+            ),
         }
-        let rand_color = if let Some(ast::FuncArg::RandomColor) = args.get("color") {
-            true
-        } else {
-            false
-        };
+    }
+
+    /// Returns the color of an element as red, green and blue, which is
+    /// black if the rule gives none for the element.
+    fn color(&mut self, args: &ast::FuncArgs, el: &OSMPaintObj) -> (f64, f64, f64) {
+        let black = Paint::Color(ast::Color { r: 0, g: 0, b: 0 });
+        let paint = self.arg(args, "color", el, paint_arg).unwrap_or(black);
+        self.rgb(paint)
+    }
+
+    fn polyfill(&mut self, els: &Vec<OSMPaintObj>, args: &ast::FuncArgs) {
         for el in els {
-            if rand_color {
-                let r = self.rng.random::<f64>() * 0.2 + 0.8;
-                let g = self.rng.random::<f64>() * 0.2 + 0.8;
-                let b = self.rng.random::<f64>() * 0.2 + 0.8;
-                self.cr.set_source_rgba(r, g, b, alpha);
-            }
+            let alpha = self.arg(args, "alpha", el, number_arg).unwrap_or(1.0);
+            let (r, g, b) = self.color(args, el);
+            self.cr.set_source_rgba(r, g, b, alpha);
             match el.ty {
                 OSMElementType::Node => {}
                 OSMElementType::Way => {
@@ -427,43 +972,23 @@ impl<'a> Renderer<'a> {
         }
     }
 
-    fn outline(&mut self, els: &Vec<OSMPaintObj>, args: &HashMap<String, ast::FuncArg>) {
-        let alpha = if let Some(ast::FuncArg::Float(alpha)) = args.get("alpha") {
-            *alpha
-        } else {
-            1.0
-        };
-        if let Some(ast::FuncArg::Color(ast::Color { r, g, b })) = args.get("color") {
-            self.cr.set_source_rgba(
-                *r as f64 / 255.0,
-                *g as f64 / 255.0,
-                *b as f64 / 255.0,
-                alpha,
-            );
-        }
-        let rand_color = if let Some(ast::FuncArg::RandomColor) = args.get("color") {
-            true
-        } else {
-            false
-        };
-        let mut width = self.unit_scale;
-        if let Some(ast::FuncArg::Float(w)) = args.get("width") {
-            width *= *w;
-        }
+    fn outline(&mut self, els: &Vec<OSMPaintObj>, args: &ast::FuncArgs) {
         if let Some(ast::FuncArg::String(dash)) = args.get("dash") {
-            let dashes = dash
-                .split(",")
-                .map(|x| x.parse::<f64>().unwrap())
-                .collect::<Vec<f64>>();
+            let dashes = ast::parse_dash(dash).unwrap_or_default();
             self.cr.set_dash(&dashes, 0.0);
         }
-        self.cr.set_line_width(width);
+        // Elements are stroked together until the color or width changes
+        let mut stroke = None;
         for el in els {
-            if rand_color {
-                let r = self.rng.random::<f64>() * 0.2 + 0.8;
-                let g = self.rng.random::<f64>() * 0.2 + 0.8;
-                let b = self.rng.random::<f64>() * 0.2 + 0.8;
-                self.cr.set_source_rgb(r, g, b);
+            let alpha = self.arg(args, "alpha", el, number_arg).unwrap_or(1.0);
+            let color = self.color(args, el);
+            let width = self.arg(args, "width", el, number_arg).unwrap_or(1.0) * self.unit_scale;
+            if stroke != Some((color, alpha, width)) {
+                let _ = self.cr.stroke();
+                let (r, g, b) = color;
+                self.cr.set_source_rgba(r, g, b, alpha);
+                self.cr.set_line_width(width);
+                stroke = Some((color, alpha, width));
             }
             match el.ty {
                 OSMElementType::Node => {}
@@ -481,27 +1006,11 @@ impl<'a> Renderer<'a> {
         self.cr.set_dash(&[], 0.0);
     }
 
-    fn dot(&mut self, els: &Vec<OSMPaintObj>, args: &HashMap<String, ast::FuncArg>) {
-        if let Some(ast::FuncArg::Color(ast::Color { r, g, b })) = args.get("color") {
-            self.cr
-                .set_source_rgb(*r as f64 / 255.0, *g as f64 / 255.0, *b as f64 / 255.0);
-        }
-        let rand_color = if let Some(ast::FuncArg::RandomColor) = args.get("color") {
-            true
-        } else {
-            false
-        };
-        let mut radius = self.unit_scale;
-        if let Some(ast::FuncArg::Float(w)) = args.get("radius") {
-            radius *= *w;
-        }
+    fn dot(&mut self, els: &Vec<OSMPaintObj>, args: &ast::FuncArgs) {
         for el in els {
-            if rand_color {
-                let r = self.rng.random::<f64>() * 0.2 + 0.8;
-                let g = self.rng.random::<f64>() * 0.2 + 0.8;
-                let b = self.rng.random::<f64>() * 0.2 + 0.8;
-                self.cr.set_source_rgb(r, g, b);
-            }
+            let (r, g, b) = self.color(args, el);
+            self.cr.set_source_rgb(r, g, b);
+            let radius = self.arg(args, "radius", el, number_arg).unwrap_or(1.0) * self.unit_scale;
             match el.ty {
                 OSMElementType::Node => {
                     let node = self.osm_file.get_node(el.id).unwrap();
@@ -513,16 +1022,16 @@ impl<'a> Renderer<'a> {
                 OSMElementType::Way => {
                     // Center of mass
                     let way = self.osm_file.get_way(el.id).unwrap();
-                    let mut x = 0;
-                    let mut y = 0;
+                    let mut x = 0.0;
+                    let mut y = 0.0;
                     for point in &way.data().refs {
                         if let Some(node) = self.osm_file.get_node(*point) {
                             x += node.data().lon;
                             y += node.data().lat;
                         }
                     }
-                    x /= way.data().refs.len() as i64;
-                    y /= way.data().refs.len() as i64;
+                    x /= way.data().refs.len() as f64;
+                    y /= way.data().refs.len() as f64;
                     let (map_x, map_y) = self.world_to_screen(y, x);
                     self.cr
                         .arc(map_x, map_y, radius, 0.0, 2.0 * std::f64::consts::PI);
@@ -554,22 +1063,20 @@ impl<'a> Renderer<'a> {
         }
     }
 
-    fn text(&mut self, els: &Vec<OSMPaintObj>, args: &HashMap<String, ast::FuncArg>) {
+    fn text(&mut self, els: &Vec<OSMPaintObj>, args: &ast::FuncArgs) {
+        let label = if let Some(ast::FuncArg::String(field)) = args.get("field") {
+            ast::parse_label(field).unwrap_or_default()
+        } else {
+            vec![ast::LabelPart::Tag("name".to_string())]
+        };
         for el in els {
-            let field = if let Some(ast::FuncArg::String(field)) = args.get("field") {
-                field.clone()
-            } else {
-                "name".to_string()
-            };
-            let mut lat = 0;
-            let mut lon = 0;
-            let text;
+            let mut lat = 0.0;
+            let mut lon = 0.0;
             match el.ty {
                 OSMElementType::Node => {
                     let node = self.osm_file.get_node(el.id).unwrap();
                     lat = node.data().lat;
                     lon = node.data().lon;
-                    text = node.get_tag_value(&field).map(|v| v.clone());
                 }
                 OSMElementType::Way => {
                     let way = self.osm_file.get_way(el.id).unwrap();
@@ -579,9 +1086,8 @@ impl<'a> Renderer<'a> {
                             lon += node.data().lon;
                         }
                     }
-                    lat /= way.data().refs.len() as i64;
-                    lon /= way.data().refs.len() as i64;
-                    text = way.get_tag_value(&field).map(|v| v.clone());
+                    lat /= way.data().refs.len() as f64;
+                    lon /= way.data().refs.len() as f64;
                 }
                 OSMElementType::Relation => {
                     let rel = self.osm_file.get_relation(el.id).unwrap();
@@ -591,19 +1097,15 @@ impl<'a> Renderer<'a> {
                             lon += node.data().lon;
                         }
                     }
-                    lat /= rel.data().members.len() as i64;
-                    lon /= rel.data().members.len() as i64;
-                    text = rel.get_tag_value(&field).map(|v| v.clone());
+                    lat /= rel.data().members.len() as f64;
+                    lon /= rel.data().members.len() as f64;
                 }
             }
-            if let Some(val) = text {
+            if let Some(val) = label_text(self.osm_file, el, &label) {
                 let mut pango_font = pangocairo::pango::FontDescription::new();
                 pango_font.set_weight(pangocairo::pango::Weight::Bold);
-                let font_size = if let Some(ast::FuncArg::Float(size)) = args.get("size") {
-                    *size * self.unit_scale
-                } else {
-                    12.0 * self.unit_scale
-                };
+                let font_size =
+                    self.arg(args, "size", el, number_arg).unwrap_or(12.0) * self.unit_scale;
                 let size_delta = el.text_patch.scale.unwrap_or(0.0) * self.unit_scale;
                 if let Some(ast::FuncArg::String(family)) = args.get("font_family") {
                     pango_font.set_family(family.as_str());
@@ -628,12 +1130,284 @@ impl<'a> Renderer<'a> {
                 self.cr.move_to(map_x, map_y);
                 self.cr
                     .rel_move_to(-rect.width() as f64 / 2.0, -rect.height() as f64 / 2.0);
-                if let Some(ast::FuncArg::Color(ast::Color { r, g, b })) = args.get("color") {
-                    self.cr
-                        .set_source_rgb(*r as f64 / 255.0, *g as f64 / 255.0, *b as f64 / 255.0);
-                }
+                let (r, g, b) = self.color(args, el);
+                self.cr.set_source_rgb(r, g, b);
                 pangocairo::functions::show_layout(&self.cr, &lyt);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XML: &str = r#"<osm version="0.6">
+        <node id="1" lat="47.0" lon="8.0"><tag k="name" v="A"/></node>
+        <node id="2" lat="47.00001" lon="8.0"><tag k="name" v="B"/></node>
+        <node id="3" lat="47.01" lon="8.0"><tag k="name" v="A"/></node>
+        <node id="4" lat="47.02" lon="8.0"><tag k="ref" v="A"/></node>
+        <relation id="10"><member type="node" ref="1" role=""/><member type="node" ref="2" role=""/></relation>
+        <relation id="11"><member type="node" ref="1" role=""/><member type="node" ref="3" role=""/><member type="node" ref="4" role=""/></relation>
+        <relation id="12"><member type="node" ref="4" role=""/><member type="node" ref="2" role=""/></relation>
+    </osm>"#;
+
+    /// Returns the ids of the elements passed to each draw function of a
+    /// style with a single layer.
+    fn drawn(osm_file: &osmpbf::OsmFile, layer: &str) -> Vec<Vec<i64>> {
+        let source = format!(
+            "FORMAT 100 100\nDPI 100\nSCALE 1:50000\nCENTER 47.0 8.0\n{}\n[A]\n{}",
+            "@define dot {\n    Dot { radius: 1 }\n}\n@define dedup {\n    @dedup .id\n}", layer
+        );
+        let style = style_file::read_source(&source).unwrap();
+        let mut drawn = vec![];
+        walk_commands(
+            &mut LayerState::new(osm_file, &style.meta),
+            &mut all_elements(osm_file),
+            &style.layers[0].commands,
+            &mut |selection, _, _| drawn.push(selection.iter().map(|el| el.id).collect()),
+        );
+        drawn
+    }
+
+    /// Returns the ids of the elements selected by a filter expression.
+    fn kept(osm_file: &osmpbf::OsmFile, expr: &str) -> Vec<i64> {
+        drawn(osm_file, &format!("    @keep {}\n    $dot", expr)).remove(0)
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(split_number("30 mph"), Some((30.0, "mph")));
+        assert_eq!(split_number("-1.5t"), Some((-1.5, "t")));
+        assert_eq!(split_number("1-2"), Some((1.0, "-2")));
+        assert_eq!(split_number("mph"), None);
+
+        assert_eq!(osm_number("50"), Some(50.0));
+        assert_eq!(osm_number("30 mph"), Some(30.0 * 1.609344));
+        assert_eq!(osm_number("3.5 t"), Some(3.5));
+        assert_eq!(osm_number("7500 kg"), Some(7.5));
+        assert!((osm_number("6'6\"").unwrap() - 1.9812).abs() < 1e-9);
+        assert!((osm_number("6' 6\"").unwrap() - 1.9812).abs() < 1e-9);
+        assert!((osm_number("6'").unwrap() - 1.8288).abs() < 1e-9);
+        assert_eq!(osm_number("5;7"), Some(5.0));
+        assert_eq!(osm_number("-5"), Some(-5.0));
+        assert_eq!(osm_number("none"), None);
+        assert_eq!(osm_number("50 furlongs"), None);
+        assert_eq!(osm_number("6'6"), None);
+        assert_eq!(osm_number(""), None);
+    }
+
+    #[test]
+    fn compare_filter() {
+        let xml = r#"<osm version="0.6">
+            <node id="1" lat="47.0" lon="8.0"><tag k="admin_level" v="2"/></node>
+            <node id="2" lat="47.0" lon="8.0"><tag k="admin_level" v="6"/></node>
+            <node id="3" lat="47.0" lon="8.0"><tag k="admin_level" v="8"/></node>
+            <node id="4" lat="47.0" lon="8.0"><tag k="admin_level" v="4;8"/></node>
+            <node id="5" lat="47.0" lon="8.0"><tag k="admin_level" v="none"/></node>
+            <node id="6" lat="47.0" lon="8.0"><tag k="name" v="6"/></node>
+        </osm>"#;
+        let osm_file = osmpbf::XmlReader::new(xml.as_bytes()).read().unwrap();
+        assert_eq!(kept(&osm_file, "admin_level<=6"), [1, 2, 4]);
+        assert_eq!(kept(&osm_file, "admin_level > 6"), [3]);
+        // Values that are not numbers compare false either way
+        assert_eq!(kept(&osm_file, "admin_level != 6"), [1, 3, 4]);
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("primary", "primary"));
+        assert!(!wildcard_match("primary_link", "primary"));
+        assert!(wildcard_match("primary_link", "primary*"));
+        assert!(wildcard_match("primary", "primary*"));
+        assert!(wildcard_match("primary_link", "*link"));
+        assert!(!wildcard_match("link_road", "*link"));
+        assert!(wildcard_match("Zürich HB", "Z*HB"));
+        assert!(wildcard_match("ab", "a*b"));
+        assert!(!wildcard_match("ab", "ab*b"));
+        assert!(wildcard_match("abcbc", "a*bc*c"));
+        assert!(wildcard_match("", "*"));
+        assert!(wildcard_match("a.b", "a.b"));
+        assert!(!wildcard_match("axb", "a.b"));
+        assert!(wildcard_match("(a)", "(*)"));
+        assert!(!wildcard_match("a", "(*)"));
+    }
+
+    #[test]
+    fn tag_filters() {
+        let xml = r#"<osm version="0.6">
+            <node id="1" lat="47.0" lon="8.0"><tag k="name" v="Zürich HB"/></node>
+            <node id="2" lat="47.0" lon="8.0"><tag k="name" v="Bahnhof Zürich"/></node>
+            <node id="3" lat="47.0" lon="8.0"><tag k="name" v="zürich"/><tag k="ref" v="S12"/></node>
+            <node id="4" lat="47.0" lon="8.0"><tag k="ref" v="S1a"/></node>
+        </osm>"#;
+        let osm_file = osmpbf::XmlReader::new(xml.as_bytes()).read().unwrap();
+        assert_eq!(kept(&osm_file, "name=\"Zürich*\""), [1]);
+        assert_eq!(kept(&osm_file, "name=\"*Zürich*\""), [1, 2]);
+        assert_eq!(kept(&osm_file, "name~\"Zürich\""), [1, 2]);
+        assert_eq!(kept(&osm_file, "name~\"^Zürich\""), [1]);
+        assert_eq!(kept(&osm_file, "name~*\"^zÜrich\""), [1, 3]);
+        assert_eq!(kept(&osm_file, "ref~\"^S[0-9]+$\""), [3]);
+        assert_eq!(kept(&osm_file, "has ref"), [3, 4]);
+        assert_eq!(kept(&osm_file, "lacks ref"), [1, 2]);
+        assert_eq!(kept(&osm_file, "has name and lacks ref"), [1, 2]);
+    }
+
+    #[test]
+    fn within_view() {
+        // The view spans about 0.02° of latitude on either side of the center
+        let xml = r#"<osm version="0.6">
+            <node id="1" lat="47.0" lon="8.0"/>
+            <node id="2" lat="47.01" lon="8.0"/>
+            <node id="3" lat="47.1" lon="8.0"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/></way>
+            <way id="11"><nd ref="2"/><nd ref="3"/></way>
+            <relation id="20"><member type="way" ref="10" role=""/></relation>
+            <relation id="21"><member type="way" ref="10" role=""/><member type="way" ref="11" role=""/></relation>
+        </osm>"#;
+        let osm_file = osmpbf::XmlReader::new(xml.as_bytes()).read().unwrap();
+        assert_eq!(kept(&osm_file, ".within_view"), [1, 2, 10, 20]);
+        assert_eq!(kept(&osm_file, "not .within_view"), [3, 11, 21]);
+    }
+
+    #[test]
+    fn sort() {
+        let xml = r#"<osm version="0.6">
+            <node id="1" lat="47.0" lon="8.0"><tag k="population" v="10"/></node>
+            <node id="2" lat="47.0" lon="8.0"><tag k="population" v="many"/></node>
+            <node id="3" lat="47.0" lon="8.0"/>
+            <node id="4" lat="47.0" lon="8.0"><tag k="population" v="2"/></node>
+            <node id="5" lat="47.0" lon="8.0"><tag k="population" v="abc"/></node>
+            <node id="6" lat="47.0" lon="8.0"><tag k="population" v="10"/></node>
+            <node id="7" lat="47.0" lon="8.0"/>
+        </osm>"#;
+        let osm_file = osmpbf::XmlReader::new(xml.as_bytes()).read().unwrap();
+        let sorted = |sort: &str| drawn(&osm_file, &format!("{}\n    $dot", sort)).remove(0);
+        assert_eq!(sorted("    @sort population"), [4, 1, 6, 5, 2, 3, 7]);
+        assert_eq!(sorted("    @sort population asc"), [4, 1, 6, 5, 2, 3, 7]);
+        assert_eq!(sorted("    @sort population desc"), [2, 5, 1, 6, 4, 3, 7]);
+        // Equal values keep the order of the previous sort
+        assert_eq!(
+            sorted("    @sort .id desc\n    @sort population"),
+            [4, 6, 1, 5, 2, 7, 3]
+        );
+        assert_eq!(
+            sorted("    @sort .id desc\n    @sort population desc"),
+            [2, 5, 6, 1, 4, 7, 3]
+        );
+    }
+
+    #[test]
+    fn label_text_of_missing_tags() {
+        let xml = r#"<osm version="0.6">
+            <node id="1" lat="47.0" lon="8.0"><tag k="name" v="Zürich  HB"/><tag k="ref" v="ZH"/></node>
+            <node id="2" lat="47.0" lon="8.0"><tag k="name" v=" Oerlikon"/></node>
+            <node id="3" lat="47.0" lon="8.0"><tag k="ref" v="ZO"/></node>
+        </osm>"#;
+        let osm_file = osmpbf::XmlReader::new(xml.as_bytes()).read().unwrap();
+        let label = |id: i64, field: &str| {
+            let el = all_elements(&osm_file)
+                .into_iter()
+                .find(|el| el.id == id)
+                .unwrap();
+            label_text(&osm_file, &el, &ast::parse_label(field).unwrap())
+        };
+        assert_eq!(label(1, "{ref} {name}").unwrap(), "ZH Zürich  HB");
+        assert_eq!(label(2, "{ref} {name}").unwrap(), " Oerlikon");
+        assert_eq!(label(3, "{ref} {name}").unwrap(), "ZO");
+        assert_eq!(label(1, "{name}\n{ele}\n{ref}").unwrap(), "Zürich  HB\nZH");
+        assert_eq!(label(2, "{name} {ref}\n{ele}").unwrap(), " Oerlikon");
+        assert_eq!(label(3, "{name}"), None);
+    }
+
+    #[test]
+    fn sub_of_partly_loaded_relation() {
+        let xml = r#"<osm version="0.6">
+            <node id="1" lat="47.0" lon="8.0"/>
+            <node id="2" lat="47.1" lon="8.0"/>
+            <node id="3" lat="47.2" lon="8.0"/>
+            <node id="4" lat="47.3" lon="8.0"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/></way>
+            <way id="11"><nd ref="3"/><nd ref="4"/></way>
+            <relation id="20">
+                <member type="node" ref="1" role="stop"/>
+                <member type="way" ref="10" role=""/>
+                <member type="way" ref="11" role=""/>
+                <member type="node" ref="4" role="stop"/>
+                <member type="relation" ref="21" role=""/>
+            </relation>
+            <relation id="21"><member type="node" ref="4" role=""/></relation>
+        </osm>"#;
+        let filter = osmpbf::LoadFilter {
+            bbox: Some(osmpbf::BBox {
+                min_lat: 46.95,
+                min_lon: 7.95,
+                max_lat: 47.05,
+                max_lon: 8.05,
+            }),
+            tag_keys: None,
+        };
+        let osm_file = osmpbf::XmlReader::new(xml.as_bytes())
+            .with_filter(filter)
+            .read()
+            .unwrap();
+        assert!(osm_file.get_relation(20).is_some());
+        assert!(osm_file.get_way(11).is_none());
+        let layer = "    @keep .relation\n    @sub {\n        $dot\n        @sub {\n            $dot\n        }\n    }";
+        assert_eq!(drawn(&osm_file, layer), [vec![1, 10]]);
+    }
+
+    #[test]
+    fn dedup_in_sub() {
+        let osm_file = osmpbf::XmlReader::new(XML.as_bytes()).read().unwrap();
+        let sub = |dedup: &str| {
+            let layer = format!(
+                "    @keep .relation\n    @sub {{\n        {}\n        $dot\n    }}",
+                dedup
+            );
+            drawn(&osm_file, &layer)
+        };
+        assert_eq!(sub("@keep .node"), [vec![1, 2], vec![1, 3, 4], vec![4, 2]]);
+        assert_eq!(sub("@dedup .id"), [vec![1, 2], vec![3, 4], vec![]]);
+        // Node 4 has no name
+        assert_eq!(sub("@dedup name"), [vec![1, 2], vec![4], vec![4]]);
+        // 1.1 m apart is 0.02 mm at 1:50000, 1.1 km is 22 mm
+        assert_eq!(sub("@dedup 1mm"), [vec![1], vec![3, 4], vec![]]);
+        assert_eq!(sub("@dedup 30mm"), [vec![1], vec![4], vec![]]);
+    }
+
+    #[test]
+    fn dedup_in_parents() {
+        let osm_file = osmpbf::XmlReader::new(XML.as_bytes()).read().unwrap();
+        let layer = "    @keep .node\n    @parents {\n        @dedup .id\n        $dot\n    }";
+        assert_eq!(
+            drawn(&osm_file, layer),
+            [vec![10, 11], vec![12], vec![], vec![]]
+        );
+    }
+
+    #[test]
+    fn dedup_commands_are_separate() {
+        let osm_file = osmpbf::XmlReader::new(XML.as_bytes()).read().unwrap();
+        let layer = "    @keep .node\n    @dedup .id\n    $dot\n    @dedup .id\n    $dot";
+        assert_eq!(
+            drawn(&osm_file, layer),
+            [vec![1, 2, 3, 4], vec![1, 2, 3, 4]]
+        );
+        // A separate command in every use of a block
+        let layer = r#"    @keep .relation
+    @sub {
+        @keep name="A"
+        $dedup
+        $dot
+    }
+    @sub {
+        $dedup
+        $dot
+    }"#;
+        assert_eq!(
+            drawn(&osm_file, layer),
+            [vec![1], vec![3], vec![], vec![1, 2], vec![3, 4], vec![]]
+        );
+    }
+}
@@ -1,21 +1,88 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-#[derive(Debug)]
+/// Position in a style file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Location {
+    /// Index of the file in the order the files were read
+    pub file: usize,
+    /// Byte offset in the file
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum InfoField {
+    Version,
+    Timestamp,
+    Changeset,
+    Uid,
+    User,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CompareOp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Ne,
+}
+
+impl CompareOp {
+    pub fn compare(self, a: f64, b: f64) -> bool {
+        match self {
+            CompareOp::Eq => a == b,
+            CompareOp::Lt => a < b,
+            CompareOp::Le => a <= b,
+            CompareOp::Gt => a > b,
+            CompareOp::Ge => a >= b,
+            CompareOp::Ne => a != b,
+        }
+    }
+}
+
+/// Property of the geometry of an element.
+#[derive(Debug, Clone)]
+pub enum Measure {
+    /// Area of a closed way or multipolygon in m²
+    Area,
+    /// Length of a way or the member ways of a relation in m
+    Length,
+    /// Number of nodes of a way
+    Nodes,
+    /// Number of members of a relation
+    Members,
+}
+
+#[derive(Debug, Clone)]
 pub enum Filter {
     IsNode,
     IsWay,
     IsRelation,
     Match(String, String),
+    /// Numeric comparison of a tag value, see [`crate::render`] for the
+    /// accepted units
+    Compare(String, CompareOp, f64),
+    /// Match of a tag value against a regular expression, anywhere in the
+    /// value unless anchored. The flag is set if the expression ignores case.
+    Regex(String, regex::Regex, bool),
+    Has(String),
+    Lacks(String),
     MatchRole(String),
+    IsClosed,
+    /// Whether the element lies completely within the map area
+    WithinView,
+    Measure(Measure, CompareOp, f64),
+    MatchInfo(InfoField, String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum FilterType {
     Keep,
     Remove,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum FilterExpr {
     And(Box<FilterExpr>, Box<FilterExpr>),
     Or(Box<FilterExpr>, Box<FilterExpr>),
@@ -23,7 +90,38 @@ pub enum FilterExpr {
     Filter(Filter),
 }
 
-#[derive(Debug)]
+impl FilterExpr {
+    pub(super) fn visit_filters(&self, f: &mut impl FnMut(&Filter)) {
+        match self {
+            FilterExpr::And(a, b) | FilterExpr::Or(a, b) => {
+                a.visit_filters(f);
+                b.visit_filters(f);
+            }
+            FilterExpr::Not(a) => a.visit_filters(f),
+            FilterExpr::Filter(filter) => f(filter),
+        }
+    }
+
+    /// Returns whether only elements that have a tag tested by the
+    /// expression can match it, or its negation if `negated` is set.
+    fn requires_tag(&self, negated: bool) -> bool {
+        match self {
+            FilterExpr::And(a, b) if !negated => a.requires_tag(false) || b.requires_tag(false),
+            FilterExpr::Or(a, b) if negated => a.requires_tag(true) || b.requires_tag(true),
+            FilterExpr::And(a, b) | FilterExpr::Or(a, b) => {
+                a.requires_tag(negated) && b.requires_tag(negated)
+            }
+            FilterExpr::Not(a) => a.requires_tag(!negated),
+            FilterExpr::Filter(
+                Filter::Match(..) | Filter::Compare(..) | Filter::Regex(..) | Filter::Has(_),
+            ) => !negated,
+            FilterExpr::Filter(Filter::Lacks(_)) => negated,
+            FilterExpr::Filter(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
@@ -31,23 +129,189 @@ pub struct Color {
 }
 
 impl Color {
-    pub fn new(hex: &str) -> Color {
-        let hex = hex.trim_start_matches('#');
-        let r = u8::from_str_radix(&hex[0..2], 16).unwrap();
-        let g = u8::from_str_radix(&hex[2..4], 16).unwrap();
-        let b = u8::from_str_radix(&hex[4..6], 16).unwrap();
-        Color { r, g, b }
+    /// Parses a color written as `#RRGGBB`.
+    pub fn new(hex: &str) -> Result<Color, String> {
+        let digits = hex.trim_start_matches('#');
+        if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid color `{}`, expected #RRGGBB", hex));
+        }
+        let channel = |idx| u8::from_str_radix(&digits[idx..idx + 2], 16).unwrap();
+        Ok(Color {
+            r: channel(0),
+            g: channel(2),
+            b: channel(4),
+        })
     }
 }
 
-#[derive(Debug)]
+/// Parses the dash pattern of an outline, a comma-separated list of
+/// lengths.
+pub fn parse_dash(dash: &str) -> Result<Vec<f64>, String> {
+    let dashes = dash
+        .split(',')
+        .map(|part| match part.trim().parse::<f64>() {
+            Ok(len) if len >= 0.0 => Ok(len),
+            _ => Err(format!(
+                "invalid dash pattern \"{}\", `{}` is not a length",
+                dash,
+                part.trim()
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if dashes.iter().all(|len| *len == 0.0) {
+        return Err(format!(
+            "invalid dash pattern \"{}\", all lengths are zero",
+            dash
+        ));
+    }
+    Ok(dashes)
+}
+
+/// Part of the text of a label.
+#[derive(Debug, Clone)]
+pub enum LabelPart {
+    Text(String),
+    /// Value of a tag of the element
+    Tag(String),
+}
+
+/// Parses the field of a text, either a tag key or a format string in
+/// which `{key}` stands for the value of a tag.
+pub fn parse_label(field: &str) -> Result<Vec<LabelPart>, String> {
+    if !field.contains(['{', '}']) {
+        return Ok(vec![LabelPart::Tag(field.to_string())]);
+    }
+    let mut parts = vec![];
+    let mut rest = field;
+    while let Some(start) = rest.find(['{', '}']) {
+        let key = rest[start..]
+            .strip_prefix('{')
+            .and_then(|after| after.split_once('}'))
+            .map(|(key, _)| key)
+            .filter(|key| !key.contains('{'))
+            .ok_or_else(|| format!("invalid label \"{}\", unmatched brace", field))?;
+        if key.trim().is_empty() {
+            return Err(format!("invalid label \"{}\", `{{}}` names no tag", field));
+        }
+        if start > 0 {
+            parts.push(LabelPart::Text(rest[..start].to_string()));
+        }
+        parts.push(LabelPart::Tag(key.trim().to_string()));
+        rest = &rest[start + key.len() + 2..];
+    }
+    if !rest.is_empty() {
+        parts.push(LabelPart::Text(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+/// Cases of a `@match` by tag value, with the location of the value
+pub type MatchCases = Vec<(Location, String, FuncArg)>;
+
+#[derive(Debug, Clone)]
 pub enum FuncArg {
     String(String),
     Color(Color),
     Float(f64),
     RandomColor,
+    /// Reference to a value defined with `@define`
+    Constant(Location, String),
+    /// Number interpolated between values given for increasing scale
+    /// denominators
+    Interpolate(Vec<(f64, f64)>),
+    /// Value of a tag of the element that is drawn, multiplied by the
+    /// factor if there is one. The fallback is used if the element lacks
+    /// the tag or its value is unusable.
+    Tag {
+        key: String,
+        factor: Option<f64>,
+        fallback: Option<Box<FuncArg>>,
+    },
+    /// Value chosen by the value of a tag of the element that is drawn
+    Match {
+        key: String,
+        cases: MatchCases,
+        fallback: Option<(Location, Box<FuncArg>)>,
+    },
+}
+
+impl FuncArg {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            FuncArg::String(_) => "a string",
+            FuncArg::Color(_) => "a color",
+            FuncArg::Float(_) => "a number",
+            FuncArg::RandomColor => "@random_color",
+            FuncArg::Constant(..) => "a reference",
+            FuncArg::Interpolate(_) => "an interpolation",
+            FuncArg::Tag { .. } => "a tag value",
+            FuncArg::Match { .. } => "a match",
+        }
+    }
+
+    /// Returns the values this value chooses from, the fallback of a tag
+    /// value and the cases of a match.
+    pub fn parts(&self) -> Vec<&FuncArg> {
+        match self {
+            FuncArg::Tag { fallback, .. } => fallback.iter().map(|arg| arg.as_ref()).collect(),
+            FuncArg::Match {
+                cases, fallback, ..
+            } => cases
+                .iter()
+                .map(|(_, _, arg)| arg)
+                .chain(fallback.iter().map(|(_, arg)| arg.as_ref()))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn parts_mut(&mut self) -> Vec<&mut FuncArg> {
+        match self {
+            FuncArg::Tag { fallback, .. } => fallback.iter_mut().map(|arg| arg.as_mut()).collect(),
+            FuncArg::Match {
+                cases, fallback, ..
+            } => cases
+                .iter_mut()
+                .map(|(_, _, arg)| arg)
+                .chain(fallback.iter_mut().map(|(_, arg)| arg.as_mut()))
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// Arguments of a draw function, with the location of their names.
+#[derive(Debug, Clone, Default)]
+pub struct FuncArgs(HashMap<String, (Location, FuncArg)>);
+
+impl FuncArgs {
+    pub fn get(&self, key: &str) -> Option<&FuncArg> {
+        self.0.get(key).map(|(_, arg)| arg)
+    }
+
+    /// Adds an argument, unless there already is one with the same name.
+    pub fn insert(&mut self, key: String, location: Location, arg: FuncArg) -> bool {
+        if self.0.contains_key(&key) {
+            return false;
+        }
+        self.0.insert(key, (location, arg));
+        true
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, Location, &FuncArg)> {
+        self.0
+            .iter()
+            .map(|(key, (location, arg))| (key, *location, arg))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Location, &mut FuncArg)> {
+        self.0.values_mut().map(|(location, arg)| (*location, arg))
+    }
 }
 
+/// Patches by tag value, with the location of the value
+pub type TextPatches = HashMap<String, (Location, TextPatch)>;
+
 #[derive(Debug, Clone)]
 pub struct TextPatch {
     pub offset: Option<(f64, f64)>,
@@ -73,28 +337,61 @@ impl TextPatch {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub enum SortKey {
+    /// Value of a tag, compared as a number if possible
+    Tag(String),
+    Measure(Measure),
+    Id,
+}
+
+#[derive(Debug, Clone)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+#[derive(Debug, Clone)]
+pub enum DedupKey {
+    Id,
+    Tag(String),
+    /// Distance on the map in mm
+    Distance(f64),
+}
+
+#[derive(Debug, Clone)]
 pub enum Command {
     Take(usize),
+    Sort(SortKey, SortOrder),
+    /// The number identifies the command within the style, every use of a
+    /// block defined with `@define` gets its own
+    Dedup(DedupKey, usize),
+    /// Skips the rest of the block unless the scale denominator of the map
+    /// compares true with the number
+    Scale(CompareOp, f64),
     Filter(FilterType, FilterExpr),
     DrawFunc {
         ty: String,
-        args: HashMap<String, FuncArg>,
+        args: FuncArgs,
     },
-    Sub(Vec<Command>),
+    Sub(Vec<(Location, Command)>),
+    /// Reference to a block defined with `@define`
+    Use(String),
+    Parents(Vec<(Location, Command)>),
     OffsetText {
         key: String,
-        offsets: HashMap<String, TextPatch>,
+        offsets: TextPatches,
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Layer {
     pub name: String,
-    pub commands: Vec<Command>,
+    pub commands: Vec<(Location, Command)>,
+    pub location: Location,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Meta {
     pub format: (f64, f64),
     pub dpi: f64,
@@ -112,8 +409,235 @@ impl Meta {
     }
 }
 
-#[derive(Debug)]
+/// Line of the header of a style file.
+#[derive(Debug, Clone)]
+pub enum HeaderLine {
+    Format(f64, f64),
+    Dpi(f64),
+    Scale(f64),
+    Center(f64, f64),
+}
+
+#[derive(Debug, Clone)]
+pub enum Definition {
+    Value(FuncArg),
+    Block(Vec<(Location, Command)>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Item {
+    Layer(Layer),
+    Define(String, Definition),
+    /// Path of another style file, relative to this one
+    Include(String),
+}
+
+/// A style file as it is written, before includes and definitions are
+/// resolved.
+#[derive(Debug, Clone)]
+pub struct StyleFile {
+    /// Header lines, which may be left out if an included file has them
+    pub header: Vec<(Location, HeaderLine)>,
+    pub items: Vec<(Location, Item)>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Style {
     pub meta: Meta,
     pub layers: Vec<Layer>,
 }
+
+/// Which elements of a selection go missing when only the elements that
+/// have one of the tag keys of the style are loaded.
+#[derive(Clone, Copy, PartialEq)]
+enum Pruned {
+    Nothing,
+    /// Elements without any of the keys
+    Untagged,
+    /// Any element, for example after `@take` picked others
+    Anything,
+}
+
+/// Returns whether every draw function of `commands` gets the same elements
+/// with only the tagged elements loaded, if `pruned` is missing from the
+/// selection they start with.
+fn draws_all(commands: &[(Location, Command)], mut pruned: Pruned) -> bool {
+    for (_, command) in commands {
+        match command {
+            Command::Filter(ty, expr)
+                if pruned == Pruned::Untagged
+                    && expr.requires_tag(matches!(ty, FilterType::Remove)) =>
+            {
+                pruned = Pruned::Nothing;
+            }
+            // Which elements these keep depends on the others
+            Command::Take(_) | Command::Dedup(..) if pruned == Pruned::Untagged => {
+                pruned = Pruned::Anything;
+            }
+            Command::DrawFunc { .. } if pruned != Pruned::Nothing => return false,
+            Command::Sub(cmds) | Command::Parents(cmds) => {
+                let related = match (command, pruned) {
+                    // The members of loaded relations are always loaded
+                    (Command::Sub(_), Pruned::Nothing) => Pruned::Nothing,
+                    // Parents only if they have one of the keys themselves
+                    (_, Pruned::Nothing) => Pruned::Untagged,
+                    _ => Pruned::Anything,
+                };
+                if !draws_all(cmds, related) {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+    }
+    true
+}
+
+impl Style {
+    /// Calls `f` for every filter of every layer.
+    fn visit_filters(&self, mut f: impl FnMut(&Filter)) {
+        fn visit(commands: &[(Location, Command)], f: &mut impl FnMut(&Filter)) {
+            for (_, command) in commands {
+                match command {
+                    Command::Filter(_, expr) => expr.visit_filters(f),
+                    Command::Sub(cmds) | Command::Parents(cmds) => visit(cmds, f),
+                    _ => {}
+                }
+            }
+        }
+        for layer in &self.layers {
+            visit(&layer.commands, &mut f);
+        }
+    }
+
+    /// Returns all tag keys that are tested by a filter of the style, or
+    /// `None` if the style also draws elements that may have none of them,
+    /// like with `@keep lacks name` or `@keep .way`. Only loading the elements
+    /// with one of the keys would leave those out of the map.
+    pub fn tag_keys(&self) -> Option<HashSet<String>> {
+        let draws_tagged = self
+            .layers
+            .iter()
+            .all(|layer| draws_all(&layer.commands, Pruned::Untagged));
+        if !draws_tagged {
+            return None;
+        }
+        let mut keys = HashSet::new();
+        self.visit_filters(|filter| match filter {
+            Filter::Match(key, _)
+            | Filter::Compare(key, ..)
+            | Filter::Regex(key, ..)
+            | Filter::Has(key)
+            | Filter::Lacks(key) => {
+                keys.insert(key.clone());
+            }
+            _ => {}
+        });
+        Some(keys)
+    }
+
+    /// Returns whether any filter of the style tests element metadata.
+    pub fn uses_element_info(&self) -> bool {
+        let mut uses_info = false;
+        self.visit_filters(|filter| uses_info |= matches!(filter, Filter::MatchInfo(..)));
+        uses_info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn tag_keys(layers: &str) -> Option<Vec<String>> {
+        let source = format!(
+            "FORMAT 297 420\nDPI 300\nSCALE 1:10000\nCENTER 47.4 8.5\n{}",
+            layers
+        );
+        let style = super::super::read_source(&source).unwrap();
+        let mut keys = style.tag_keys()?.into_iter().collect::<Vec<_>>();
+        keys.sort();
+        Some(keys)
+    }
+
+    #[test]
+    fn tag_keys_of_tag_tests() {
+        let keys = tag_keys(
+            r#"[A]
+    @keep highway="primary" or has railway
+    @remove lacks name
+    Outline { color: #000000 }
+[B]
+    @keep not (lacks building or .node)
+    @remove not height > 10
+    Polyfill { color: #000000 }
+[C]
+    @keep .way and (natural="water" or waterway~"river")
+    @take 10
+    Polyfill { color: #000000 }
+"#,
+        );
+        let expected = [
+            "building", "height", "highway", "name", "natural", "railway", "waterway",
+        ];
+        assert_eq!(keys.unwrap(), expected);
+    }
+
+    #[test]
+    fn tag_keys_of_untagged_selections() {
+        let draw = "    Outline { color: #000000 }\n";
+        for selection in [
+            "@keep lacks name",
+            "@keep not name=\"x\"",
+            "@remove has highway",
+            "@keep .way",
+            "@keep .closed",
+            "@keep .area > 100",
+            "@keep has highway or .node",
+            "@keep not (has highway and has name)",
+            // @take picks other elements if untagged ones are missing
+            "@take 5\n    @keep has highway",
+        ] {
+            let keys = tag_keys(&format!("[A]\n    {}\n{}", selection, draw));
+            assert!(keys.is_none(), "{}", selection);
+        }
+        assert!(tag_keys(&format!(
+            "[A]\n    @keep has highway\n    @take 5\n{}",
+            draw
+        ))
+        .is_some());
+    }
+
+    #[test]
+    fn tag_keys_of_related_elements() {
+        let sub = r#"[A]
+    @keep type="route"
+    @sub {
+        @keep .way
+        Outline { color: #000000 }
+    }
+"#;
+        assert!(tag_keys(sub).is_some());
+        let sub = r#"[A]
+    @keep .relation
+    @sub {
+        @keep has highway
+        Outline { color: #000000 }
+    }
+"#;
+        assert!(tag_keys(sub).is_none());
+        let parents = r#"[A]
+    @keep railway="station"
+    @parents {
+        @keep public_transport="stop_area"
+        Outline { color: #000000 }
+    }
+"#;
+        assert!(tag_keys(parents).is_some());
+        let parents = r#"[A]
+    @keep railway="station"
+    @parents {
+        @keep .relation
+        Outline { color: #000000 }
+    }
+"#;
+        assert!(tag_keys(parents).is_none());
+    }
+}
@@ -0,0 +1,217 @@
+use super::ast::Location;
+use lalrpop_util::ParseError;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Problem found in a style file.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Part of a file the problem is about, if any
+    pub location: Option<Location>,
+    pub message: String,
+    /// Lines printed below the source snippet
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(location: Location, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            location: Some(location),
+            message: message.into(),
+            notes: vec![],
+        }
+    }
+
+    pub fn warning(location: Location, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(location, message)
+        }
+    }
+}
+
+/// Converts an error of the parser of the file with index `file`.
+pub fn parse_error(file: usize, err: ParseError<usize, impl Display, Diagnostic>) -> Diagnostic {
+    let at = |offset| Location { file, offset };
+    match err {
+        ParseError::InvalidToken { location } => Diagnostic::error(at(location), "invalid token"),
+        ParseError::UnrecognizedEof { location, expected } => Diagnostic::error(
+            at(location),
+            format!(
+                "unexpected end of file, expected one of {}",
+                expected.join(", ")
+            ),
+        ),
+        ParseError::UnrecognizedToken {
+            token: (start, token, _),
+            expected,
+        } => Diagnostic::error(
+            at(start),
+            format!(
+                "unexpected `{}`, expected one of {}",
+                token,
+                expected.join(", ")
+            ),
+        ),
+        ParseError::ExtraToken {
+            token: (start, token, _),
+        } => Diagnostic::error(at(start), format!("unexpected `{}`", token)),
+        ParseError::User { error } => error,
+    }
+}
+
+/// Returns the line and column of a byte offset, both starting at 1.
+fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// The style files that were read, to show diagnostics in.
+#[derive(Default)]
+pub struct Sources {
+    files: Vec<(PathBuf, String)>,
+}
+
+impl Sources {
+    /// Adds a file and returns its index.
+    pub fn add(&mut self, path: &Path, source: String) -> usize {
+        self.files.push((path.to_path_buf(), source));
+        self.files.len() - 1
+    }
+
+    pub fn source(&self, file: usize) -> &str {
+        &self.files[file].1
+    }
+
+    /// Returns the location as `file:line:column`.
+    pub fn position(&self, location: Location) -> String {
+        let (path, source) = &self.files[location.file];
+        let (line, col) = line_col(source, location.offset);
+        format!("{}:{}:{}", path.display(), line, col)
+    }
+
+    /// Formats a diagnostic with the line of the file it points to, with
+    /// the token at the location underlined.
+    pub fn format(&self, diagnostic: &Diagnostic) -> String {
+        let severity = match diagnostic.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let mut out = format!("{}: {}", severity, diagnostic.message);
+        if let Some(location) = diagnostic.location {
+            let source = self.source(location.file);
+            let offset = location.offset.min(source.len());
+            let (line, _) = line_col(source, offset);
+            let line_start = source[..offset].rfind('\n').map_or(0, |idx| idx + 1);
+            let line_end = source[offset..]
+                .find('\n')
+                .map_or(source.len(), |idx| offset + idx);
+            let rest = &source[offset..line_end];
+            let token_end = rest
+                .find(|c: char| c.is_whitespace() || "{}(),:=".contains(c))
+                .unwrap_or(rest.len());
+            let token_len = rest[..token_end].chars().count().max(1);
+            // Keep tabs, so that the marker lines up with the text above
+            let indent: String = source[line_start..offset]
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let gutter = " ".repeat(line.to_string().len());
+            out += &format!(
+                "\n{}--> {}\n{} |\n{} | {}\n{} | {}{}",
+                gutter,
+                self.position(location),
+                gutter,
+                line,
+                &source[line_start..line_end],
+                gutter,
+                indent,
+                "^".repeat(token_len)
+            );
+        }
+        for note in &diagnostic.notes {
+            out += &format!("\n  = {}", note);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::read_source;
+    use super::*;
+
+    fn sources(source: &str) -> Sources {
+        let mut sources = Sources::default();
+        sources.add(Path::new("style.chz"), source.to_string());
+        sources
+    }
+
+    #[test]
+    fn position() {
+        let source = "[A]\n\tOutline { wdth: 1 }\n    @keep name=\"Zürich\" oder\n";
+        let sources = sources(source);
+        let at = |offset| Location { file: 0, offset };
+        assert_eq!(sources.position(at(0)), "style.chz:1:1");
+        assert_eq!(sources.position(at(15)), "style.chz:2:12");
+        assert_eq!(
+            sources.position(at(source.find("oder").unwrap())),
+            "style.chz:3:25"
+        );
+        assert_eq!(sources.position(at(source.len())), "style.chz:4:1");
+    }
+
+    #[test]
+    fn snippet() {
+        let source = "[A]\n\tOutline { wdth: 1 }\n    @keep name=\"Zürich\" oder\n";
+        let sources = sources(source);
+        let at = |offset| Location { file: 0, offset };
+        assert_eq!(
+            sources.format(&Diagnostic::error(at(15), "unknown argument")),
+            "error: unknown argument\n --> style.chz:2:12\n  |\n2 | \tOutline { wdth: 1 }\n  | \t          ^^^^"
+        );
+        let mut warning = Diagnostic::warning(at(source.find('"').unwrap()), "unused");
+        warning.notes.push("included from main.chz:1:1".to_string());
+        assert_eq!(
+            sources.format(&warning),
+            "warning: unused\n --> style.chz:3:16\n  |\n3 |     @keep name=\"Zürich\" oder\n  |                ^^^^^^^^\n  = included from main.chz:1:1"
+        );
+        assert_eq!(
+            sources.format(&Diagnostic::error(
+                at(source.find("oder").unwrap()),
+                "unexpected"
+            )),
+            "error: unexpected\n --> style.chz:3:25\n  |\n3 |     @keep name=\"Zürich\" oder\n  |                         ^^^^"
+        );
+    }
+
+    #[test]
+    fn invalid_number() {
+        let header = |center: &str| {
+            format!(
+                "FORMAT 297 420\nDPI 300\nSCALE 1:10000\nCENTER {}\n[A]\n    Dot {{ radius: 1 }}\n",
+                center
+            )
+        };
+        let error = read_source(&header("47.4.1 8.5")).unwrap_err();
+        assert!(error.starts_with("error: invalid number `47.4.1`"));
+        assert!(error.contains(":4:8\n"));
+        for number in [".", "-.", "-"] {
+            let error = read_source(&header(&format!("{} 8.5", number))).unwrap_err();
+            assert!(error.contains("invalid"), "{}", error);
+        }
+        assert!(read_source(&header("-47.4 .5")).is_ok());
+    }
+}
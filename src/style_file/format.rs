@@ -0,0 +1,518 @@
+use super::ast::{
+    Color, Command, CompareOp, DedupKey, Definition, Filter, FilterExpr, FilterType, FuncArg,
+    HeaderLine, InfoField, Item, Location, Measure, SortKey, SortOrder, TextPatch,
+};
+use super::diagnostic::{parse_error, Sources};
+use super::style::StyleFileParser;
+use std::fs;
+use std::path::Path;
+
+const INDENT: &str = "    ";
+
+#[derive(Clone, Copy)]
+struct Comment<'a> {
+    offset: usize,
+    text: &'a str,
+}
+
+/// Finds the comments of a style file, and the offsets of matching curly
+/// braces. Strings and layer names are skipped, as they may contain `//`,
+/// `/*` or braces.
+fn scan(source: &str) -> (Vec<Comment<'_>>, Vec<(usize, usize)>) {
+    let find = |from: usize, pat: &str| source[from..].find(pat).map(|idx| from + idx);
+    let mut comments = vec![];
+    let mut braces = vec![];
+    let mut open = vec![];
+    let mut idx = 0;
+    while idx < source.len() {
+        let rest = &source[idx..];
+        if rest.starts_with('{') {
+            open.push(idx);
+        } else if rest.starts_with('}') {
+            if let Some(start) = open.pop() {
+                braces.push((start, idx));
+            }
+        }
+        idx = if rest.starts_with('"') {
+            find(idx + 1, "\"").map_or(source.len(), |end| end + 1)
+        } else if rest.starts_with('[') {
+            find(idx + 1, "]").map_or(source.len(), |end| end + 1)
+        } else if rest.starts_with("//") {
+            let end = find(idx, "\n").unwrap_or(source.len());
+            comments.push(Comment {
+                offset: idx,
+                text: source[idx..end].trim_end(),
+            });
+            end
+        } else if rest.starts_with("/*") {
+            let end = find(idx + 2, "*/").map_or(source.len(), |end| end + 2);
+            comments.push(Comment {
+                offset: idx,
+                text: &source[idx..end],
+            });
+            end
+        } else {
+            idx + rest.chars().next().map_or(1, char::len_utf8)
+        };
+    }
+    braces.sort();
+    (comments, braces)
+}
+
+fn string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\n', "\\n"))
+}
+
+fn color(color: &Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+fn compare_op(op: CompareOp) -> &'static str {
+    match op {
+        CompareOp::Eq => "=",
+        CompareOp::Lt => "<",
+        CompareOp::Le => "<=",
+        CompareOp::Gt => ">",
+        CompareOp::Ge => ">=",
+        CompareOp::Ne => "!=",
+    }
+}
+
+fn measure(measure: &Measure) -> &'static str {
+    match measure {
+        Measure::Area => ".area",
+        Measure::Length => ".length",
+        Measure::Nodes => ".nodes",
+        Measure::Members => ".members",
+    }
+}
+
+fn filter(filter: &Filter) -> String {
+    match filter {
+        Filter::IsNode => ".node".to_string(),
+        Filter::IsWay => ".way".to_string(),
+        Filter::IsRelation => ".relation".to_string(),
+        Filter::Match(key, value) => format!("{}={}", key, string(value)),
+        Filter::Compare(key, op, n) => format!("{}{}{}", key, compare_op(*op), n),
+        Filter::Regex(key, re, case_insensitive) => {
+            let op = if *case_insensitive { "~*" } else { "~" };
+            format!("{}{}{}", key, op, string(re.as_str()))
+        }
+        Filter::Has(key) => format!("has {}", key),
+        Filter::Lacks(key) => format!("lacks {}", key),
+        Filter::MatchRole(role) => format!(".role={}", string(role)),
+        Filter::IsClosed => ".closed".to_string(),
+        Filter::WithinView => ".within_view".to_string(),
+        Filter::Measure(m, op, n) => format!("{} {} {}", measure(m), compare_op(*op), n),
+        Filter::MatchInfo(field, value) => {
+            let field = match field {
+                InfoField::Version => ".version",
+                InfoField::Timestamp => ".timestamp",
+                InfoField::Changeset => ".changeset",
+                InfoField::Uid => ".uid",
+                InfoField::User => ".user",
+            };
+            format!("{}={}", field, string(value))
+        }
+    }
+}
+
+/// Precedence level of an expression in the grammar, lower binds tighter.
+fn level(expr: &FilterExpr) -> u8 {
+    match expr {
+        FilterExpr::Filter(_) => 0,
+        FilterExpr::Not(_) => 1,
+        FilterExpr::And(..) => 2,
+        FilterExpr::Or(..) => 3,
+    }
+}
+
+/// Formats an expression, in parentheses if it binds less tightly than
+/// `max_level` allows.
+fn filter_expr(expr: &FilterExpr, max_level: u8) -> String {
+    let formatted = match expr {
+        FilterExpr::Filter(f) => filter(f),
+        FilterExpr::Not(a) => format!("not {}", filter_expr(a, 0)),
+        FilterExpr::And(a, b) => format!("{} and {}", filter_expr(a, 2), filter_expr(b, 1)),
+        FilterExpr::Or(a, b) => format!("{} or {}", filter_expr(a, 3), filter_expr(b, 2)),
+    };
+    if level(expr) > max_level {
+        format!("({})", formatted)
+    } else {
+        formatted
+    }
+}
+
+fn text_patch(patch: &TextPatch) -> String {
+    let mut parts = vec![];
+    if let Some(rename) = &patch.rename {
+        parts.push(format!("rename {}", string(rename)));
+    }
+    if let Some(scale) = patch.scale {
+        parts.push(format!("scale {}", scale));
+    }
+    if let Some((x, y)) = patch.offset {
+        parts.push(format!("offset {} / {}", x, y));
+    }
+    format!("{{ {} }}", parts.join(" "))
+}
+
+/// Writes the canonical form of a style file. Comments at the end of a
+/// line stay there, other comments are placed before the part of the file
+/// that follows them.
+struct Printer<'a> {
+    source: &'a str,
+    comments: Vec<Comment<'a>>,
+    /// Offsets of the opening and closing curly braces, by opening offset
+    braces: Vec<(usize, usize)>,
+    /// Index of the first comment that was not written yet
+    next_comment: usize,
+    out: String,
+}
+
+impl Printer<'_> {
+    /// Starts a line, keeping an empty line before it if the source has
+    /// one at `offset`.
+    fn start_line(&mut self, offset: usize, depth: usize) {
+        let before = &self.source[..offset];
+        let gap = &before[before.trim_end().len()..];
+        if gap.matches('\n').count() > 1 && !self.out.is_empty() && !self.out.ends_with("{\n") {
+            self.out.push('\n');
+        }
+        self.out += &INDENT.repeat(depth);
+    }
+
+    /// Writes the comments before `location` and starts the line for it.
+    fn line(&mut self, location: Location, depth: usize) {
+        self.comments_before(location.offset, depth);
+        self.start_line(location.offset, depth);
+    }
+
+    fn comments_before(&mut self, offset: usize, depth: usize) {
+        while let Some(&comment) = self.comments.get(self.next_comment) {
+            if comment.offset >= offset {
+                break;
+            }
+            self.next_comment += 1;
+            let before = self.source[..comment.offset].trim_end_matches([' ', '\t']);
+            if !before.is_empty() && !before.ends_with('\n') && self.out.ends_with('\n') {
+                // Comment at the end of a line stays there
+                self.out.pop();
+                self.out.push(' ');
+            } else {
+                self.start_line(comment.offset, depth);
+            }
+            self.out += comment.text;
+            self.out.push('\n');
+        }
+    }
+
+    /// Writes the comments before the closing brace of the block that
+    /// starts after `location`, and the brace itself.
+    fn close_block(&mut self, location: Location, depth: usize) {
+        let end = self
+            .braces
+            .iter()
+            .find(|(start, _)| *start >= location.offset)
+            .map_or(location.offset, |(_, end)| *end);
+        self.comments_before(end, depth + 1);
+        self.out += &INDENT.repeat(depth);
+        self.out += "}";
+    }
+
+    /// Writes an argument value, with the cases of matches on their own
+    /// lines. `location` is where the value starts, or the name before it.
+    fn arg(&mut self, location: Location, arg: &FuncArg, depth: usize) {
+        match arg {
+            FuncArg::Match {
+                key,
+                cases,
+                fallback,
+            } => {
+                self.out += &format!("@match {} {{\n", key);
+                let fallback = fallback
+                    .iter()
+                    .map(|(location, arg)| (*location, None, arg.as_ref()));
+                let cases = cases
+                    .iter()
+                    .map(|(location, value, arg)| (*location, Some(value), arg))
+                    .chain(fallback)
+                    .collect::<Vec<_>>();
+                for (idx, (location, value, arg)) in cases.iter().enumerate() {
+                    self.line(*location, depth + 1);
+                    self.out += &value.map_or("else".to_string(), |value| string(value));
+                    self.out += ": ";
+                    self.arg(*location, arg, depth + 1);
+                    if idx + 1 < cases.len() {
+                        self.out.push(',');
+                    }
+                    self.out.push('\n');
+                }
+                self.close_block(location, depth);
+            }
+            FuncArg::Tag {
+                key,
+                factor,
+                fallback,
+            } => {
+                self.out += &format!("@tag {}", key);
+                if let Some(factor) = factor {
+                    self.out += &format!(" * {}", factor);
+                }
+                if let Some(fallback) = fallback {
+                    self.out += " else ";
+                    self.arg(location, fallback, depth);
+                }
+            }
+            FuncArg::String(s) => self.out += &string(s),
+            FuncArg::Color(c) => self.out += &color(c),
+            FuncArg::Float(n) => self.out += &n.to_string(),
+            FuncArg::RandomColor => self.out += "@random_color",
+            FuncArg::Constant(_, name) => self.out += &format!("${}", name),
+            FuncArg::Interpolate(stops) => {
+                let stops = stops
+                    .iter()
+                    .map(|(scale, value)| format!("{}: {}", scale, value))
+                    .collect::<Vec<_>>();
+                self.out += &format!("@scale {{ {} }}", stops.join(", "));
+            }
+        }
+    }
+
+    fn header_line(&mut self, location: Location, line: &HeaderLine) {
+        self.line(location, 0);
+        self.out += &match line {
+            HeaderLine::Format(w, h) => format!("FORMAT {} {}\n", w, h),
+            HeaderLine::Dpi(dpi) => format!("DPI {}\n", dpi),
+            HeaderLine::Scale(scale) => format!("SCALE 1:{}\n", scale),
+            HeaderLine::Center(x, y) => format!("CENTER {} {}\n", x, y),
+        };
+    }
+
+    fn block(&mut self, location: Location, commands: &[(Location, Command)], depth: usize) {
+        self.out += "{\n";
+        self.commands(commands, depth + 1);
+        self.close_block(location, depth);
+    }
+
+    fn commands(&mut self, commands: &[(Location, Command)], depth: usize) {
+        for (location, command) in commands {
+            self.line(*location, depth);
+            match command {
+                Command::Take(n) => self.out += &format!("@take {}", n),
+                Command::Scale(op, n) => self.out += &format!("@scale {} {}", compare_op(*op), n),
+                Command::Sort(key, order) => {
+                    self.out += "@sort ";
+                    self.out += &match key {
+                        SortKey::Tag(key) => key.clone(),
+                        SortKey::Measure(m) => measure(m).to_string(),
+                        SortKey::Id => ".id".to_string(),
+                    };
+                    if let SortOrder::Descending = order {
+                        self.out += " desc";
+                    }
+                }
+                Command::Dedup(key, _) => {
+                    self.out += &match key {
+                        DedupKey::Id => "@dedup .id".to_string(),
+                        DedupKey::Tag(key) => format!("@dedup {}", key),
+                        DedupKey::Distance(mm) => format!("@dedup {}mm", mm),
+                    }
+                }
+                Command::Filter(ty, expr) => {
+                    let ty = match ty {
+                        FilterType::Keep => "keep",
+                        FilterType::Remove => "remove",
+                    };
+                    self.out += &format!("@{} {}", ty, filter_expr(expr, 3));
+                }
+                Command::DrawFunc { ty, args } => {
+                    self.out += &format!("{} {{\n", ty);
+                    let mut args = args.iter().collect::<Vec<_>>();
+                    args.sort_by_key(|(_, location, _)| *location);
+                    for (idx, (key, location, arg)) in args.iter().enumerate() {
+                        self.line(*location, depth + 1);
+                        self.out += &format!("{}: ", key);
+                        self.arg(*location, arg, depth + 1);
+                        if idx + 1 < args.len() {
+                            self.out.push(',');
+                        }
+                        self.out.push('\n');
+                    }
+                    self.close_block(*location, depth);
+                }
+                Command::Sub(cmds) => {
+                    self.out += "@sub ";
+                    self.block(*location, cmds, depth);
+                }
+                Command::Parents(cmds) => {
+                    self.out += "@parents ";
+                    self.block(*location, cmds, depth);
+                }
+                Command::Use(name) => self.out += &format!("${}", name),
+                Command::OffsetText { key, offsets } => {
+                    self.out += &format!("@patch_text {} {{\n", key);
+                    let mut offsets = offsets.iter().collect::<Vec<_>>();
+                    offsets.sort_by_key(|(_, (location, _))| *location);
+                    for (idx, (value, (location, patch))) in offsets.iter().enumerate() {
+                        self.line(*location, depth + 1);
+                        self.out += &format!("{}: {}", string(value), text_patch(patch));
+                        if idx + 1 < offsets.len() {
+                            self.out.push(',');
+                        }
+                        self.out.push('\n');
+                    }
+                    self.close_block(*location, depth);
+                }
+            }
+            self.out.push('\n');
+        }
+    }
+
+    fn item(&mut self, location: Location, item: &Item) {
+        self.line(location, 0);
+        match item {
+            Item::Layer(layer) => {
+                self.out += &format!("[{}]\n", layer.name);
+                self.commands(&layer.commands, 1);
+            }
+            Item::Define(name, Definition::Value(value)) => {
+                self.out += &format!("@define {} = ", name);
+                self.arg(location, value, 0);
+                self.out.push('\n');
+            }
+            Item::Define(name, Definition::Block(commands)) => {
+                self.out += &format!("@define {} ", name);
+                self.block(location, commands, 0);
+                self.out.push('\n');
+            }
+            Item::Include(path) => self.out += &format!("@include {}\n", string(path)),
+        }
+    }
+}
+
+/// Formats a style file in the canonical way, keeping its comments. Only
+/// the file itself is formatted, includes and definitions are kept as they
+/// are.
+pub fn format_file(path: &Path) -> Result<String, String> {
+    let source = fs::read_to_string(path)
+        .map_err(|e| format!("error: unable to read {}: {}", path.display(), e))?;
+    let mut sources = Sources::default();
+    let file = sources.add(path, source);
+    let source = sources.source(file);
+    let style_file = StyleFileParser::new()
+        .parse(file, source)
+        .map_err(|e| sources.format(&parse_error(file, e)))?;
+
+    let (comments, braces) = scan(source);
+    let mut printer = Printer {
+        source,
+        comments,
+        braces,
+        next_comment: 0,
+        out: String::new(),
+    };
+    for (location, line) in &style_file.header {
+        printer.header_line(*location, line);
+    }
+    for (location, item) in &style_file.items {
+        printer.item(*location, item);
+    }
+    printer.comments_before(source.len(), 0);
+    Ok(printer.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{read_source, temp_file};
+    use super::format_file;
+
+    fn format_source(source: &str) -> String {
+        let path = temp_file(source);
+        let formatted = format_file(&path);
+        let _ = std::fs::remove_file(path);
+        formatted.unwrap()
+    }
+
+    /// Adds a comment before and at the end of every line.
+    fn with_comments(source: &str) -> (String, Vec<String>) {
+        let mut out = String::new();
+        let mut comments = vec![];
+        for (idx, line) in source.lines().enumerate() {
+            if line.trim().is_empty() {
+                out += "\n";
+                continue;
+            }
+            let before = format!("// before <{}>", idx);
+            let after = format!("/* after <{}> */", idx);
+            out += &format!("{}\n{} {}\n", before, line, after);
+            comments.extend([before, after]);
+        }
+        (out, comments)
+    }
+
+    #[test]
+    fn examples_round_trip() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "chz") {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let commented = with_comments(&source);
+            for (source, comments) in [(source, vec![]), commented] {
+                let once = format_source(&source);
+                let twice = format_source(&once);
+                assert_eq!(once, twice, "{}", path.display());
+
+                let layers = |source: &str| {
+                    let style = read_source(source).unwrap();
+                    style.layers.into_iter().map(|l| l.name).collect::<Vec<_>>()
+                };
+                assert_eq!(layers(&once), layers(&source), "{}", path.display());
+                for comment in comments {
+                    assert_eq!(once.matches(&comment).count(), 1, "{}", comment);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let source = r#"// Header
+FORMAT 297 420 /* A3 */
+DPI 300
+SCALE 1:10000
+CENTER 47.4 8.5
+/* Colours
+   used below */
+@define water = #97b0f6 // blue
+[Water] // "[quoted]"
+    @keep natural="water" // or "//"
+    /* before the rule */ Polyfill {
+        color: $water // trailing
+        // last argument
+    }
+    // end of layer
+// end of file
+"#;
+        let once = format_source(source);
+        assert_eq!(once, format_source(&once));
+        read_source(&once).unwrap();
+        for comment in [
+            "// Header",
+            "/* A3 */",
+            "/* Colours\n   used below */",
+            "// blue",
+            "// \"[quoted]\"",
+            "// or \"//\"",
+            "/* before the rule */",
+            "// trailing",
+            "// last argument",
+            "// end of layer",
+            "// end of file",
+        ] {
+            assert_eq!(once.matches(comment).count(), 1, "{}\n{}", comment, once);
+        }
+    }
+}
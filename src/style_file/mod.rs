@@ -1,5 +1,46 @@
 use lalrpop_util::lalrpop_mod;
 
 pub mod ast;
+mod diagnostic;
+mod format;
+mod resolve;
+mod scale;
+mod validate;
+
+pub use format::format_file;
+pub use resolve::read;
 
 lalrpop_mod!(pub style, "/style_file/style.rs");
+
+/// Writes a style file with the given contents to a new temporary file.
+#[cfg(test)]
+fn temp_file(source: &str) -> std::path::PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "chaze-style-{}-{}.chz",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+/// Reads a style file with the given contents.
+#[cfg(test)]
+pub fn read_source(source: &str) -> Result<ast::Style, String> {
+    let path = temp_file(source);
+    let style = read(&path, None);
+    let _ = std::fs::remove_file(path);
+    style
+}
+
+/// Reads a style file with the given contents and returns its formatted
+/// warnings, or its errors.
+#[cfg(test)]
+fn warnings(source: &str) -> Result<String, String> {
+    let path = temp_file(source);
+    let warnings = resolve::read_validated(&path).map(|(_, warnings)| warnings);
+    let _ = std::fs::remove_file(path);
+    warnings
+}
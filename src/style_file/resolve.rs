@@ -0,0 +1,463 @@
+use super::ast::{Command, Definition, FuncArg, HeaderLine, Item, Layer, Location, Meta, Style};
+use super::diagnostic::{parse_error, Diagnostic, Severity, Sources};
+use super::scale::apply_scale;
+use super::style::StyleFileParser;
+use super::validate::validate;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Replaces references to definitions with the defined values and blocks.
+#[derive(Default)]
+struct Resolver {
+    definitions: HashMap<String, Definition>,
+    /// Number of `@dedup` commands resolved so far
+    dedups: usize,
+}
+
+impl Resolver {
+    fn arg(&self, arg: &FuncArg) -> Result<FuncArg, Diagnostic> {
+        let FuncArg::Constant(location, name) = arg else {
+            let mut arg = arg.clone();
+            for part in arg.parts_mut() {
+                *part = self.arg(part)?;
+            }
+            return Ok(arg);
+        };
+        match self.definitions.get(name) {
+            Some(Definition::Value(value)) => Ok(value.clone()),
+            Some(Definition::Block(_)) => Err(Diagnostic::error(
+                *location,
+                format!("${} is a block, not a value", name),
+            )),
+            None => Err(Diagnostic::error(
+                *location,
+                format!("${} is not defined", name),
+            )),
+        }
+    }
+
+    fn commands(
+        &mut self,
+        commands: Vec<(Location, Command)>,
+    ) -> Result<Vec<(Location, Command)>, Diagnostic> {
+        let mut resolved = Vec::with_capacity(commands.len());
+        for (location, command) in commands {
+            match command {
+                Command::Use(name) => match self.definitions.get(&name) {
+                    Some(Definition::Block(block)) => {
+                        // Resolving the block again numbers its `@dedup`
+                        // commands anew, as if it was written out here
+                        let block = block.clone();
+                        resolved.extend(self.commands(block)?)
+                    }
+                    Some(Definition::Value(_)) => {
+                        return Err(Diagnostic::error(
+                            location,
+                            format!("${} is a value, not a block", name),
+                        ))
+                    }
+                    None => {
+                        return Err(Diagnostic::error(
+                            location,
+                            format!("${} is not defined", name),
+                        ))
+                    }
+                },
+                Command::DrawFunc { ty, mut args } => {
+                    for (_, arg) in args.iter_mut() {
+                        *arg = self.arg(arg)?;
+                    }
+                    resolved.push((location, Command::DrawFunc { ty, args }));
+                }
+                Command::Sub(cmds) => resolved.push((location, Command::Sub(self.commands(cmds)?))),
+                Command::Parents(cmds) => {
+                    resolved.push((location, Command::Parents(self.commands(cmds)?)))
+                }
+                Command::Dedup(key, _) => {
+                    self.dedups += 1;
+                    resolved.push((location, Command::Dedup(key, self.dedups)))
+                }
+                command => resolved.push((location, command)),
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn define(
+        &mut self,
+        location: Location,
+        name: String,
+        definition: Definition,
+    ) -> Result<(), Diagnostic> {
+        let definition = match definition {
+            Definition::Value(value) => Definition::Value(self.arg(&value)?),
+            Definition::Block(commands) => Definition::Block(self.commands(commands)?),
+        };
+        if self.definitions.contains_key(&name) {
+            return Err(Diagnostic::error(
+                location,
+                format!("${} is defined twice", name),
+            ));
+        }
+        self.definitions.insert(name, definition);
+        Ok(())
+    }
+}
+
+/// Header lines collected from a style file and the files it includes.
+#[derive(Default)]
+struct Header {
+    format: Option<(f64, f64)>,
+    dpi: Option<f64>,
+    scale: Option<f64>,
+    center: Option<(f64, f64)>,
+}
+
+impl Header {
+    fn new(lines: Vec<(Location, HeaderLine)>) -> Result<Header, Diagnostic> {
+        fn set<T>(
+            field: &mut Option<T>,
+            value: T,
+            location: Location,
+            name: &str,
+        ) -> Result<(), Diagnostic> {
+            if field.replace(value).is_some() {
+                return Err(Diagnostic::error(
+                    location,
+                    format!("{} is given twice", name),
+                ));
+            }
+            Ok(())
+        }
+
+        let mut header = Header::default();
+        for (location, line) in lines {
+            match line {
+                HeaderLine::Format(w, h) => set(&mut header.format, (w, h), location, "FORMAT")?,
+                HeaderLine::Dpi(dpi) => set(&mut header.dpi, dpi, location, "DPI")?,
+                HeaderLine::Scale(scale) => set(&mut header.scale, scale, location, "SCALE")?,
+                HeaderLine::Center(x, y) => set(&mut header.center, (x, y), location, "CENTER")?,
+            }
+        }
+        Ok(header)
+    }
+
+    /// Fills the lines that are missing with the ones of `other`.
+    fn or(self, other: Header) -> Header {
+        Header {
+            format: self.format.or(other.format),
+            dpi: self.dpi.or(other.dpi),
+            scale: self.scale.or(other.scale),
+            center: self.center.or(other.center),
+        }
+    }
+
+    fn meta(self) -> Result<Meta, Diagnostic> {
+        let Header {
+            format: Some(format),
+            dpi: Some(dpi),
+            scale: Some(scale),
+            center: Some(center),
+        } = self
+        else {
+            let missing = [
+                ("FORMAT", self.format.is_none()),
+                ("DPI", self.dpi.is_none()),
+                ("SCALE", self.scale.is_none()),
+                ("CENTER", self.center.is_none()),
+            ]
+            .into_iter()
+            .filter(|(_, missing)| *missing)
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+            let start = Location { file: 0, offset: 0 };
+            return Err(Diagnostic::error(
+                start,
+                format!("missing header line {}", missing.join(", ")),
+            ));
+        };
+        Ok(Meta {
+            format,
+            dpi,
+            scale,
+            center,
+        })
+    }
+}
+
+/// Reads style files, following their includes.
+#[derive(Default)]
+struct Reader {
+    sources: Sources,
+    resolver: Resolver,
+    layers: Vec<Layer>,
+    /// Files that are currently being read, to detect cyclic includes
+    stack: Vec<PathBuf>,
+    /// Files that have been read, which are skipped when included again
+    included: HashSet<PathBuf>,
+}
+
+/// Error without a location, which is given the location of the
+/// `@include` by the including file.
+fn file_error(message: String) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        location: None,
+        message,
+        notes: vec![],
+    }
+}
+
+impl Reader {
+    /// Reads a file and returns its header lines, completed by the lines of
+    /// the files it includes. Lines that come first take precedence, and
+    /// files that have been read before add nothing.
+    fn read(&mut self, path: &Path) -> Result<Header, Diagnostic> {
+        let read = || io::Result::Ok((fs::read_to_string(path)?, path.canonicalize()?));
+        let (source, canonical) =
+            read().map_err(|e| file_error(format!("unable to read {}: {}", path.display(), e)))?;
+        if self.stack.contains(&canonical) {
+            return Err(file_error(format!("{} includes itself", path.display())));
+        }
+        if !self.included.insert(canonical.clone()) {
+            return Ok(Header::default());
+        }
+        let file_idx = self.sources.add(path, source);
+        let file = StyleFileParser::new()
+            .parse(file_idx, self.sources.source(file_idx))
+            .map_err(|e| parse_error(file_idx, e))?;
+
+        self.stack.push(canonical);
+        let mut header = Header::new(file.header)?;
+        for (location, item) in file.items {
+            match item {
+                Item::Define(name, definition) => {
+                    self.resolver.define(location, name, definition)?
+                }
+                Item::Layer(layer) => {
+                    let commands = self.resolver.commands(layer.commands)?;
+                    self.layers.push(Layer { commands, ..layer });
+                }
+                Item::Include(include) => {
+                    let included = path.parent().unwrap_or(Path::new("")).join(include);
+                    let included_header = self.read(&included).map_err(|mut e| {
+                        if e.location.is_none() {
+                            e.location = Some(location);
+                        } else {
+                            let position = self.sources.position(location);
+                            e.notes.push(format!("included from {}", position));
+                        }
+                        e
+                    })?;
+                    header = header.or(included_header);
+                }
+            }
+        }
+        self.stack.pop();
+        Ok(header)
+    }
+}
+
+/// Reads a style file along with the files it includes, resolves its
+/// definitions and validates the result. The header lines of the file take
+/// precedence over the ones of included files, and `scale` over the scale
+/// of the header. The commands that depend on the scale are applied.
+///
+/// Warnings are printed to stderr. If there are errors, all diagnostics are
+/// returned formatted with the lines of the files they point to.
+pub fn read(path: &Path, scale: Option<f64>) -> Result<Style, String> {
+    let (style, warnings) = read_validated(path)?;
+    if !warnings.is_empty() {
+        eprintln!("{}\n", warnings);
+    }
+
+    let mut meta = style.meta;
+    meta.scale = scale.unwrap_or(meta.scale);
+    Ok(Style {
+        layers: apply_scale(style.layers, meta.scale),
+        meta,
+    })
+}
+
+/// Reads and validates a style file, returning it along with its formatted
+/// warnings.
+pub(super) fn read_validated(path: &Path) -> Result<(Style, String), String> {
+    let mut reader = Reader::default();
+    let meta = match reader.read(path).and_then(Header::meta) {
+        Ok(meta) => meta,
+        Err(e) => return Err(reader.sources.format(&e)),
+    };
+    let style = Style {
+        meta,
+        layers: reader.layers,
+    };
+
+    let mut diagnostics = validate(&style);
+    diagnostics.sort_by_key(|d| d.location);
+    let formatted = diagnostics
+        .iter()
+        .map(|d| reader.sources.format(d))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        return Err(formatted);
+    }
+    Ok((style, formatted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Writes the files to a new directory and returns its path.
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chaze-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        for (file, source) in files {
+            fs::write(dir.join(file), source).unwrap();
+        }
+        dir
+    }
+
+    const BASE: &str = r#"FORMAT 297 420
+DPI 300
+SCALE 1:10000
+CENTER 47.4 8.5
+@define water = #97b0f6
+[Water]
+    @keep natural="water"
+    Polyfill { color: $water }
+"#;
+
+    #[test]
+    fn diamond_include() {
+        let dir = write_files(
+            "diamond",
+            &[
+                ("base.chz", BASE),
+                (
+                    "a.chz",
+                    "@include \"base.chz\"\n[A]\n    Polyfill { color: $water }\n",
+                ),
+                (
+                    "b.chz",
+                    "@include \"base.chz\"\n[B]\n    Outline { color: $water }\n",
+                ),
+                ("main.chz", "@include \"a.chz\"\n@include \"b.chz\"\n"),
+            ],
+        );
+        let style = read(&dir.join("main.chz"), None);
+        fs::remove_dir_all(dir).unwrap();
+        let names = style
+            .unwrap()
+            .layers
+            .into_iter()
+            .map(|layer| layer.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["Water", "A", "B"]);
+    }
+
+    #[test]
+    fn header_line_override() {
+        let dir = write_files(
+            "override",
+            &[
+                ("base.chz", BASE),
+                ("a.chz", "SCALE 1:5000\n@include \"base.chz\"\n"),
+                ("b.chz", "DPI 150\nCENTER 1 2\n@include \"a.chz\"\n"),
+            ],
+        );
+        let a = read(&dir.join("a.chz"), None).map(|style| style.meta);
+        let b = read(&dir.join("b.chz"), None).map(|style| style.meta);
+        fs::remove_dir_all(dir).unwrap();
+        let a = a.unwrap();
+        assert_eq!(
+            (a.format, a.dpi, a.scale, a.center),
+            ((297., 420.), 300., 5000., (47.4, 8.5))
+        );
+        let b = b.unwrap();
+        assert_eq!(
+            (b.format, b.dpi, b.scale, b.center),
+            ((297., 420.), 150., 5000., (1., 2.))
+        );
+    }
+
+    #[test]
+    fn header_errors() {
+        let dir = write_files(
+            "header",
+            &[
+                (
+                    "missing.chz",
+                    "DPI 300\nSCALE 1:10000\n[A]\n    Outline { color: #000000 }\n",
+                ),
+                ("twice.chz", "DPI 300\nDPI 150\n@include \"base.chz\"\n"),
+                ("base.chz", BASE),
+            ],
+        );
+        let missing = read(&dir.join("missing.chz"), None).err();
+        let twice = read(&dir.join("twice.chz"), None).err();
+        fs::remove_dir_all(dir).unwrap();
+        assert!(missing
+            .unwrap()
+            .contains("missing header line FORMAT, CENTER"));
+        assert!(twice.unwrap().contains("DPI is given twice"));
+    }
+
+    #[test]
+    fn included_from() {
+        let dir = write_files(
+            "included",
+            &[
+                (
+                    "main.chz",
+                    "@include \"base.chz\"\n@include \"layers.chz\"\n",
+                ),
+                ("layers.chz", "\n@include \"bad.chz\"\n"),
+                ("bad.chz", "[A]\n    Dot { radius: $size }\n"),
+                (
+                    "missing.chz",
+                    "@include \"base.chz\"\n\n@include \"none.chz\"\n",
+                ),
+                ("base.chz", BASE),
+            ],
+        );
+        let error = read(&dir.join("main.chz"), None).err();
+        let missing = read(&dir.join("missing.chz"), None).err();
+        fs::remove_dir_all(&dir).unwrap();
+        let error = error.unwrap();
+        assert!(error.starts_with("error: $size is not defined"));
+        assert!(error.contains("bad.chz:2:19\n"));
+        let notes: Vec<_> = error
+            .lines()
+            .filter(|line| line.starts_with("  = "))
+            .collect();
+        assert_eq!(notes.len(), 2);
+        assert!(notes[0].ends_with("layers.chz:2:1"));
+        assert!(notes[1].ends_with("main.chz:2:1"));
+        // Errors without a location point to the `@include`
+        let missing = missing.unwrap();
+        assert!(missing.starts_with("error: unable to read"));
+        assert!(missing.contains("missing.chz:3:1\n"));
+    }
+
+    #[test]
+    fn cyclic_include() {
+        let dir = write_files(
+            "cycle",
+            &[
+                ("a.chz", "@include \"b.chz\"\n"),
+                ("b.chz", "@include \"base.chz\"\n@include \"a.chz\"\n"),
+                ("base.chz", BASE),
+            ],
+        );
+        let error = read(&dir.join("a.chz"), None).err();
+        fs::remove_dir_all(dir).unwrap();
+        assert!(error.unwrap().contains("a.chz includes itself"));
+    }
+}
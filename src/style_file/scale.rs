@@ -0,0 +1,184 @@
+use super::ast::{Command, FuncArg, FuncArgs, Layer, Location};
+
+/// Interpolates between the stops linearly in the logarithm of the scale,
+/// so that doubling the scale always changes the value by the same amount.
+/// Outside of the stops, the value of the nearest stop is used.
+fn interpolate(stops: &[(f64, f64)], scale: f64) -> f64 {
+    let Some(idx) = stops.iter().position(|(stop, _)| *stop >= scale) else {
+        return stops.last().map_or(0.0, |(_, value)| *value);
+    };
+    if idx == 0 {
+        return stops[0].1;
+    }
+    let ((s0, v0), (s1, v1)) = (stops[idx - 1], stops[idx]);
+    let t = (scale.ln() - s0.ln()) / (s1.ln() - s0.ln());
+    v0 + t * (v1 - v0)
+}
+
+/// Replaces the interpolations in an argument by their value at the scale.
+fn evaluate(arg: &mut FuncArg, scale: f64) {
+    if let FuncArg::Interpolate(stops) = arg {
+        *arg = FuncArg::Float(interpolate(stops, scale));
+    }
+    for part in arg.parts_mut() {
+        evaluate(part, scale);
+    }
+}
+
+/// Returns whether a draw function is drawn at the scale, according to its
+/// `min_scale` and `max_scale` arguments.
+fn in_range(args: &FuncArgs, scale: f64) -> bool {
+    let limit = |key| match args.get(key) {
+        Some(FuncArg::Float(limit)) => Some(*limit),
+        _ => None,
+    };
+    limit("min_scale").is_none_or(|min| scale >= min)
+        && limit("max_scale").is_none_or(|max| scale <= max)
+}
+
+fn commands(commands: Vec<(Location, Command)>, scale: f64) -> Vec<(Location, Command)> {
+    let mut applied = Vec::with_capacity(commands.len());
+    for (location, command) in commands {
+        match command {
+            Command::Scale(op, n) => {
+                if !op.compare(scale, n) {
+                    break;
+                }
+            }
+            Command::DrawFunc { ty, mut args } => {
+                if !in_range(&args, scale) {
+                    continue;
+                }
+                for (_, arg) in args.iter_mut() {
+                    evaluate(arg, scale);
+                }
+                applied.push((location, Command::DrawFunc { ty, args }));
+            }
+            Command::Sub(cmds) => {
+                applied.push((location, Command::Sub(self::commands(cmds, scale))))
+            }
+            Command::Parents(cmds) => {
+                applied.push((location, Command::Parents(self::commands(cmds, scale))))
+            }
+            command => applied.push((location, command)),
+        }
+    }
+    applied
+}
+
+/// Removes the commands of the layers that do not apply at the scale, and
+/// evaluates the interpolated arguments.
+pub fn apply_scale(layers: Vec<Layer>, scale: f64) -> Vec<Layer> {
+    layers
+        .into_iter()
+        .map(|layer| Layer {
+            commands: commands(layer.commands, scale),
+            ..layer
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{read, temp_file};
+    use super::*;
+
+    #[test]
+    fn interpolation() {
+        let stops = [(1000.0, 1.0), (4000.0, 3.0), (8000.0, 0.0)];
+        assert_eq!(interpolate(&stops, 1000.0), 1.0);
+        assert!((interpolate(&stops, 2000.0) - 2.0).abs() < 1e-9);
+        assert_eq!(interpolate(&stops, 4000.0), 3.0);
+        assert!((interpolate(&stops, 4000.0 * 2f64.sqrt()) - 1.5).abs() < 1e-9);
+        // The nearest stop outside of the stops
+        assert_eq!(interpolate(&stops, 10.0), 1.0);
+        assert_eq!(interpolate(&stops, 1e6), 0.0);
+        assert_eq!(interpolate(&[(5000.0, 2.0)], 1000.0), 2.0);
+        assert_eq!(interpolate(&[(5000.0, 2.0)], 9000.0), 2.0);
+    }
+
+    const STYLE: &str = "FORMAT 297 420
+DPI 300
+SCALE 1:10000
+CENTER 47.4 8.5
+[A]
+    Dot { radius: 1 }
+    @sub {
+        Dot { radius: 2 }
+        @scale > 20000
+        Dot { radius: 3 }
+    }
+    @scale < 20000
+    Outline { width: @scale { 1000: 1, 4000: 3 } }
+    Dot { radius: 4, min_scale: 5000, max_scale: 15000 }
+";
+
+    /// Describes the draw functions of a layer and their arguments, apart
+    /// from the scale limits.
+    fn describe(commands: &[(Location, Command)]) -> Vec<String> {
+        commands
+            .iter()
+            .map(|(_, command)| match command {
+                Command::DrawFunc { ty, args } => {
+                    let args = args
+                        .iter()
+                        .filter(|(key, _, _)| !key.ends_with("_scale"))
+                        .map(|(key, _, arg)| match arg {
+                            FuncArg::Float(value) => format!(" {}={}", key, value),
+                            _ => format!(" {}={}", key, arg.describe()),
+                        })
+                        .collect::<String>();
+                    format!("{}{}", ty, args)
+                }
+                Command::Sub(cmds) => format!("@sub [{}]", describe(cmds).join(", ")),
+                _ => "other".to_string(),
+            })
+            .collect()
+    }
+
+    /// Reads the style, optionally at another scale, and describes its
+    /// layer.
+    fn drawn(scale: Option<f64>) -> (f64, Vec<String>) {
+        let path = temp_file(STYLE);
+        let style = read(&path, scale);
+        let _ = std::fs::remove_file(path);
+        let style = style.unwrap();
+        (style.meta.scale, describe(&style.layers[0].commands))
+    }
+
+    #[test]
+    fn apply_at_scale() {
+        assert_eq!(
+            drawn(None),
+            (
+                10000.0,
+                vec![
+                    "Dot radius=1".to_string(),
+                    "@sub [Dot radius=2]".to_string(),
+                    "Outline width=3".to_string(),
+                    "Dot radius=4".to_string(),
+                ]
+            )
+        );
+        // `@scale` skips the rest of the block it is in
+        assert_eq!(
+            drawn(Some(30000.0)).1,
+            ["Dot radius=1", "@sub [Dot radius=2, Dot radius=3]"]
+        );
+        assert_eq!(
+            drawn(Some(1000.0)),
+            (
+                1000.0,
+                vec![
+                    "Dot radius=1".to_string(),
+                    "@sub [Dot radius=2]".to_string(),
+                    "Outline width=1".to_string(),
+                ]
+            )
+        );
+        // The limits are inclusive
+        assert_eq!(drawn(Some(5000.0)).1.last().unwrap(), "Dot radius=4");
+        assert_eq!(drawn(Some(15000.0)).1.last().unwrap(), "Dot radius=4");
+        assert_eq!(drawn(Some(4999.0)).1.last().unwrap(), "Outline width=3");
+    }
+}
@@ -0,0 +1,295 @@
+use super::ast::{self, Command, Filter, FuncArg, FuncArgs, Location, Style};
+use super::diagnostic::Diagnostic;
+
+/// Type of the value of a draw function argument. Colors and numbers can
+/// also be taken from the tags of the element that is drawn.
+#[derive(Clone, Copy)]
+enum ArgType {
+    /// `#RRGGBB` or `@random_color`
+    Paint,
+    Color,
+    /// Number, possibly interpolated across scales
+    Number,
+    /// Scale denominator
+    Scale,
+    String,
+    /// String with a dash pattern, see [`ast::parse_dash`]
+    Dash,
+    /// Tag key or format string, see [`ast::parse_label`]
+    Label,
+}
+
+impl ArgType {
+    fn describe(self) -> &'static str {
+        match self {
+            ArgType::Paint => "a color or @random_color",
+            ArgType::Color => "a color",
+            ArgType::Number | ArgType::Scale => "a number",
+            ArgType::String | ArgType::Label => "a string",
+            ArgType::Dash => "a dash pattern",
+        }
+    }
+
+    fn check(self, arg: &FuncArg) -> Result<(), String> {
+        match (self, arg) {
+            (ArgType::Paint, FuncArg::Color(_) | FuncArg::RandomColor)
+            | (ArgType::Color, FuncArg::Color(_))
+            | (ArgType::Number | ArgType::Scale, FuncArg::Float(_))
+            | (ArgType::String, FuncArg::String(_)) => Ok(()),
+            (ArgType::Number, FuncArg::Interpolate(stops)) => {
+                let increasing = stops.windows(2).all(|pair| pair[0].0 < pair[1].0);
+                if increasing && stops.iter().all(|(scale, _)| *scale > 0.0) {
+                    Ok(())
+                } else {
+                    Err(
+                        "the scales of an interpolation must be positive and increasing"
+                            .to_string(),
+                    )
+                }
+            }
+            (ArgType::Dash, FuncArg::String(dash)) => ast::parse_dash(dash).map(|_| ()),
+            (ArgType::Label, FuncArg::String(field)) => ast::parse_label(field).map(|_| ()),
+            (
+                ArgType::Paint | ArgType::Color,
+                FuncArg::Tag {
+                    factor: Some(_), ..
+                },
+            ) => Err(format!("{} cannot be multiplied", self.describe())),
+            (
+                ArgType::Paint | ArgType::Color | ArgType::Number,
+                FuncArg::Tag { .. } | FuncArg::Match { .. },
+            ) => arg
+                .parts()
+                .into_iter()
+                .try_for_each(|part| self.check(part)),
+            _ => Err(format!(
+                "expected {}, found {}",
+                self.describe(),
+                arg.describe()
+            )),
+        }
+    }
+}
+
+/// Arguments that every draw function accepts, see [`super::scale`].
+const COMMON_ARGS: &[(&str, ArgType)] =
+    &[("min_scale", ArgType::Scale), ("max_scale", ArgType::Scale)];
+
+/// The draw functions known to [`crate::render::Renderer::paint`] with
+/// their arguments.
+const DRAW_FUNCS: &[(&str, &[(&str, ArgType)])] = &[
+    (
+        "Polyfill",
+        &[("color", ArgType::Paint), ("alpha", ArgType::Number)],
+    ),
+    (
+        "Outline",
+        &[
+            ("color", ArgType::Paint),
+            ("width", ArgType::Number),
+            ("alpha", ArgType::Number),
+            ("dash", ArgType::Dash),
+        ],
+    ),
+    (
+        "Dot",
+        &[("color", ArgType::Paint), ("radius", ArgType::Number)],
+    ),
+    (
+        "Text",
+        &[
+            ("field", ArgType::Label),
+            ("size", ArgType::Number),
+            ("color", ArgType::Color),
+            ("font_family", ArgType::String),
+        ],
+    ),
+];
+
+fn names<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    names.collect::<Vec<_>>().join(", ")
+}
+
+fn draw_func(ty: &str, args: &FuncArgs, location: Location, diagnostics: &mut Vec<Diagnostic>) {
+    let Some((_, params)) = DRAW_FUNCS.iter().find(|(name, _)| *name == ty) else {
+        diagnostics.push(Diagnostic::error(
+            location,
+            format!(
+                "unknown draw function `{}`, expected one of {}",
+                ty,
+                names(DRAW_FUNCS.iter().map(|(name, _)| *name))
+            ),
+        ));
+        return;
+    };
+    let params = params.iter().chain(COMMON_ARGS);
+    for (key, location, arg) in args.iter() {
+        let Some((_, arg_type)) = params.clone().find(|(name, _)| name == key) else {
+            diagnostics.push(Diagnostic::error(
+                location,
+                format!(
+                    "unknown argument `{}` of {}, expected one of {}",
+                    key,
+                    ty,
+                    names(params.clone().map(|(name, _)| *name))
+                ),
+            ));
+            continue;
+        };
+        if let Err(e) = arg_type.check(arg) {
+            diagnostics.push(Diagnostic::error(
+                location,
+                format!("invalid `{}` of {}: {}", key, ty, e),
+            ));
+        }
+    }
+}
+
+/// Checks a block of commands and returns whether it draws anything.
+fn commands(commands: &[(Location, Command)], diagnostics: &mut Vec<Diagnostic>) -> bool {
+    let mut draws = false;
+    for (idx, (location, command)) in commands.iter().enumerate() {
+        match command {
+            Command::DrawFunc { ty, args } => {
+                draw_func(ty, args, *location, diagnostics);
+                draws = true;
+            }
+            Command::Sub(cmds) | Command::Parents(cmds) => {
+                draws |= self::commands(cmds, diagnostics)
+            }
+            Command::OffsetText { key, .. } => {
+                // Patches only apply to the elements of this block
+                let has_text = commands[idx + 1..]
+                    .iter()
+                    .any(|(_, cmd)| matches!(cmd, Command::DrawFunc { ty, .. } if ty == "Text"));
+                if !has_text {
+                    diagnostics.push(Diagnostic::warning(
+                        *location,
+                        format!("`@patch_text {}` is unused, no Text follows it", key),
+                    ));
+                }
+            }
+            Command::Filter(_, expr) => expr.visit_filters(&mut |filter| {
+                // `=` used to take regular expressions, which now only match
+                // values that contain them literally
+                if let Filter::Match(key, value) = filter {
+                    if value.contains(['|', '^', '$', '[', '(', '\\', '+', '?']) {
+                        diagnostics.push(Diagnostic::warning(
+                            *location,
+                            format!(
+                                "`{}=\"{}\"` is compared literally apart from `*`, use `~` for a regular expression",
+                                key, value
+                            ),
+                        ));
+                    }
+                }
+            }),
+            _ => {}
+        }
+    }
+    draws
+}
+
+/// Checks the draw functions and their arguments, and looks for commands
+/// that have no effect.
+pub fn validate(style: &Style) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    for layer in &style.layers {
+        if !commands(&layer.commands, &mut diagnostics) {
+            diagnostics.push(Diagnostic::warning(
+                layer.location,
+                format!("layer `{}` draws nothing", layer.name),
+            ));
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::warnings;
+
+    const HEADER: &str = "FORMAT 297 420\nDPI 300\nSCALE 1:10000\nCENTER 47.4 8.5\n";
+
+    /// Returns the warnings of a style with a single layer.
+    fn layer_warnings(layer: &str) -> Result<String, String> {
+        warnings(&format!("{}[A]\n{}", HEADER, layer))
+    }
+
+    #[test]
+    fn draw_funcs() {
+        let errors = layer_warnings("    Dott { radius: 1 }").unwrap_err();
+        assert!(errors.starts_with(
+            "error: unknown draw function `Dott`, expected one of Polyfill, Outline, Dot, Text"
+        ));
+        assert!(errors.contains(":6:5\n"));
+
+        let errors = layer_warnings(
+            "    Dot { colour: #000000, radius: \"1\" }\n    Outline { dash: \"2, x\" }",
+        )
+        .unwrap_err();
+        let errors: Vec<_> = errors.split("\n\n").collect();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with(
+            "error: unknown argument `colour` of Dot, expected one of color, radius, min_scale, max_scale"
+        ));
+        assert!(errors[0].contains(":6:11\n"));
+        assert!(errors[1]
+            .starts_with("error: invalid `radius` of Dot: expected a number, found a string"));
+        assert!(errors[2].starts_with(
+            "error: invalid `dash` of Outline: invalid dash pattern \"2, x\", `x` is not a length"
+        ));
+
+        assert!(layer_warnings("    Dot { color: #fff }")
+            .unwrap_err()
+            .starts_with("error: invalid color `#fff`, expected #RRGGBB"));
+        assert!(layer_warnings("    Dot { radius: 1, radius: 2 }")
+            .unwrap_err()
+            .starts_with("error: `radius` is given twice"));
+        assert_eq!(
+            layer_warnings("    Outline { color: #000000, width: 1, dash: \"2, 1\" }").unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn unused_commands() {
+        let warnings = layer_warnings(
+            "    @patch_text name { \"A\": { offset 1/1 } }\n    Text { field: \"name\" }\n    @patch_text ref { \"A\": { scale 2 } }",
+        )
+        .unwrap();
+        assert_eq!(warnings.matches("warning:").count(), 1);
+        assert!(warnings.starts_with("warning: `@patch_text ref` is unused, no Text follows it"));
+        assert!(warnings.contains(":8:5\n"));
+
+        let warnings =
+            layer_warnings("    @keep .node\n    @sub {\n        @keep .way\n    }").unwrap();
+        assert!(warnings.starts_with("warning: layer `A` draws nothing"));
+        assert!(
+            layer_warnings("    @sub {\n        Dot { radius: 1 }\n    }")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn match_with_regex() {
+        let warnings = layer_warnings(
+            "    @keep highway=\"primary|secondary\"\n    @keep name=\"*Bahnhof*\"\n    Dot { radius: 1 }",
+        )
+        .unwrap();
+        assert!(warnings.contains(
+            "`highway=\"primary|secondary\"` is compared literally apart from `*`, use `~` for a regular expression"
+        ));
+        assert!(!warnings.contains("Bahnhof"));
+        assert_eq!(
+            layer_warnings(
+                "    @keep highway~\"primary|secondary\" or not ref=\"^S\"\n    Dot { radius: 1 }"
+            )
+            .unwrap()
+            .matches("compared literally")
+            .count(),
+            1
+        );
+    }
+}